@@ -0,0 +1,294 @@
+use actix::Addr;
+use actix_web::dev::ServerHandle;
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::ToSocketAddrs;
+use std::sync::{Arc, RwLock};
+
+use crate::error_messages::TextEgressError;
+use crate::secrets::Secret;
+use crate::session_listener_actor::{AdminMessages, ProjectStatus, SessionListenerActor};
+
+pub const DEFAULT_ADMIN_URL: &str = "http://127.0.0.1:8089";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AdminConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_bind")]
+    pub bind: String,
+    /// Bearer token required by every request, when set. Required unless the API
+    /// only listens on loopback addresses.
+    #[serde(default)]
+    pub token: Option<Secret>,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        AdminConfig {
+            enabled: false,
+            bind: default_bind(),
+            token: None,
+        }
+    }
+}
+
+impl AdminConfig {
+    /// Refuses to serve the API on other interfaces without a token.
+    #[allow(clippy::result_large_err)]
+    pub fn validate(&self) -> Result<(), TextEgressError> {
+        if !self.enabled || self.token.is_some() {
+            return Ok(());
+        }
+        let loopback = self
+            .bind
+            .to_socket_addrs()
+            .map_err(|e| {
+                TextEgressError::ConfigFileError(format!("invalid admin bind address: {}", e))
+            })?
+            .all(|address| address.ip().is_loopback());
+        if !loopback {
+            return Err(TextEgressError::ConfigFileError(format!(
+                "the admin API on {} requires ADMIN__TOKEN",
+                self.bind
+            )));
+        }
+        Ok(())
+    }
+}
+
+fn default_bind() -> String {
+    "127.0.0.1:8089".to_string()
+}
+
+/// Session listeners of the running projects, by project id.
+pub type ProjectListeners = Arc<RwLock<HashMap<String, Addr<SessionListenerActor>>>>;
+
+/// A project that could not be started and is retried in the background.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DegradedProject {
+    pub project_id: String,
+    pub error: String,
+    pub attempts: u32,
+    pub retry_at: String,
+}
+
+/// Degraded projects, by project id.
+pub type DegradedProjects = Arc<RwLock<HashMap<String, DegradedProject>>>;
+
+/// A running project whose session listener could not report its status.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProjectError {
+    pub project_id: String,
+    pub error: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InstanceStatus {
+    pub version: String,
+    pub projects: Vec<ProjectStatus>,
+    #[serde(default)]
+    pub degraded: Vec<DegradedProject>,
+    #[serde(default)]
+    pub errors: Vec<ProjectError>,
+}
+
+#[derive(Clone)]
+struct AdminState {
+    listeners: ProjectListeners,
+    degraded: DegradedProjects,
+    token: Option<Secret>,
+}
+
+impl AdminState {
+    fn authorized(&self, request: &HttpRequest) -> bool {
+        let Some(token) = &self.token else {
+            return true;
+        };
+        request
+            .headers()
+            .get("Authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|value| value == token.expose())
+    }
+
+    fn listeners(&self) -> Vec<(String, Addr<SessionListenerActor>)> {
+        let listeners = self.listeners.read().unwrap();
+        listeners
+            .iter()
+            .map(|(project_id, addr)| (project_id.clone(), addr.clone()))
+            .collect()
+    }
+}
+
+/// Serves the admin API on a background task, returning its handle when enabled.
+#[allow(clippy::result_large_err)]
+pub fn start(
+    config: &AdminConfig,
+    listeners: ProjectListeners,
+    degraded: DegradedProjects,
+) -> Result<Option<ServerHandle>, TextEgressError> {
+    if !config.enabled {
+        return Ok(None);
+    }
+    config.validate()?;
+    let state = AdminState {
+        listeners,
+        degraded,
+        token: config.token.clone(),
+    };
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(web::Data::new(state.clone()))
+            .route("/status", web::get().to(status))
+            .route("/egresses/{egress_id}/stop", web::post().to(stop_egress))
+    })
+    .workers(1)
+    .bind(&config.bind)?
+    .run();
+    let handle = server.handle();
+    actix::spawn(async move {
+        if let Err(e) = server.await {
+            log::error!("Admin API stopped: {:?}", e);
+        }
+    });
+    log::info!("Admin API listening on {}", config.bind);
+    Ok(Some(handle))
+}
+
+async fn status(state: web::Data<AdminState>, request: HttpRequest) -> HttpResponse {
+    if !state.authorized(&request) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let mut projects = vec![];
+    let mut errors = vec![];
+    for (project_id, listener) in state.listeners() {
+        // One unresponsive project should not hide the status of the others
+        let error = match listener.send(AdminMessages::Status).await {
+            Ok(Ok(project)) => {
+                projects.push(project);
+                continue;
+            }
+            Ok(Err(e)) => e.to_string(),
+            Err(e) => e.to_string(),
+        };
+        errors.push(ProjectError { project_id, error });
+    }
+    projects.sort_by(|a, b| a.project_id.cmp(&b.project_id));
+    errors.sort_by(|a, b| a.project_id.cmp(&b.project_id));
+    let mut degraded: Vec<DegradedProject> =
+        state.degraded.read().unwrap().values().cloned().collect();
+    degraded.sort_by(|a, b| a.project_id.cmp(&b.project_id));
+    HttpResponse::Ok().json(InstanceStatus {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        projects,
+        degraded,
+        errors,
+    })
+}
+
+async fn stop_egress(
+    state: web::Data<AdminState>,
+    request: HttpRequest,
+    egress_id: web::Path<String>,
+) -> HttpResponse {
+    if !state.authorized(&request) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let egress_id = egress_id.into_inner();
+    for (_, listener) in state.listeners() {
+        let stop = AdminMessages::StopEgress {
+            egress_id: egress_id.clone(),
+        };
+        match listener.send(stop).await {
+            Ok(Ok(project)) => return HttpResponse::Ok().json(project),
+            Ok(Err(TextEgressError::EgressNotFound(_))) => continue,
+            Ok(Err(e)) => return HttpResponse::InternalServerError().body(e.to_string()),
+            Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+        }
+    }
+    HttpResponse::NotFound().body(format!("Egress {} is not running", egress_id))
+}
+
+/// Queries the admin API of a running instance.
+#[derive(Debug, Clone)]
+pub struct AdminClient {
+    client: reqwest::Client,
+    base_url: String,
+    token: Option<Secret>,
+}
+
+impl AdminClient {
+    pub fn new(base_url: &str, token: Option<Secret>) -> Self {
+        AdminClient {
+            client: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            token,
+        }
+    }
+
+    pub async fn status(&self) -> Result<InstanceStatus, TextEgressError> {
+        let request = self.client.get(format!("{}/status", self.base_url));
+        let response = self.send(request).await?;
+        Ok(response.error_for_status()?.json().await?)
+    }
+
+    /// Stops a running egress, returning the status of its project.
+    pub async fn stop_egress(&self, egress_id: &str) -> Result<ProjectStatus, TextEgressError> {
+        let request = self
+            .client
+            .post(format!("{}/egresses/{}/stop", self.base_url, egress_id));
+        let response = self.send(request).await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Err(TextEgressError::EgressNotFound(egress_id.to_string()));
+        }
+        Ok(response.error_for_status()?.json().await?)
+    }
+
+    async fn send(
+        &self,
+        mut request: reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, TextEgressError> {
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token.expose());
+        }
+        Ok(request.send().await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn admin(bind: &str, token: Option<&str>) -> AdminConfig {
+        AdminConfig {
+            enabled: true,
+            bind: bind.to_string(),
+            token: token.map(Secret::new),
+        }
+    }
+
+    #[test]
+    fn is_disabled_by_default() {
+        assert!(!AdminConfig::default().enabled);
+    }
+
+    #[test]
+    fn requires_a_token_beyond_loopback() {
+        assert!(admin("127.0.0.1:8089", None).validate().is_ok());
+        assert!(admin("[::1]:8089", None).validate().is_ok());
+        assert!(matches!(
+            admin("0.0.0.0:8089", None).validate(),
+            Err(TextEgressError::ConfigFileError(_))
+        ));
+        assert!(admin("0.0.0.0:8089", Some("token")).validate().is_ok());
+        assert!(admin("not an address", None).validate().is_err());
+        let disabled = AdminConfig {
+            enabled: false,
+            ..admin("0.0.0.0:8089", None)
+        };
+        assert!(disabled.validate().is_ok());
+    }
+}
@@ -0,0 +1,309 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::error_messages::TextEgressError;
+
+/// What to do with a new session when the concurrency limit is reached.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AdmissionPolicy {
+    /// Wait for a running egress to finish
+    #[default]
+    Queue,
+    Reject,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AdmissionConfig {
+    /// Limit on concurrent egresses across all projects
+    #[serde(default)]
+    pub max_concurrent_egresses: Option<usize>,
+    #[serde(default)]
+    pub policy: AdmissionPolicy,
+    /// How long a queued session waits before it is refused, forever when unset
+    #[serde(default)]
+    pub queue_timeout_secs: Option<u64>,
+    /// Sessions are refused when the work directory has less free space
+    #[serde(default = "default_min_free_disk_mb")]
+    pub min_free_disk_mb: u64,
+    /// Directory recordings are written to before upload, the system temp directory by default
+    #[serde(default)]
+    pub work_dir: Option<String>,
+}
+
+impl Default for AdmissionConfig {
+    fn default() -> Self {
+        AdmissionConfig {
+            max_concurrent_egresses: None,
+            policy: AdmissionPolicy::default(),
+            queue_timeout_secs: None,
+            min_free_disk_mb: default_min_free_disk_mb(),
+            work_dir: None,
+        }
+    }
+}
+
+fn default_min_free_disk_mb() -> u64 {
+    1024
+}
+
+/// Held for the lifetime of an egress, releases its concurrency slots on drop.
+#[derive(Debug)]
+pub struct AdmissionPermit {
+    _project: Option<OwnedSemaphorePermit>,
+    _global: Option<OwnedSemaphorePermit>,
+}
+
+/// Decides whether a project may start another egress.
+#[derive(Debug)]
+pub struct AdmissionController {
+    project_limit: Option<Arc<Semaphore>>,
+    global_limit: Option<Arc<Semaphore>>,
+    policy: AdmissionPolicy,
+    queue_timeout: Option<Duration>,
+    min_free_bytes: u64,
+    work_dir: PathBuf,
+    admitted: AtomicU64,
+    queued: AtomicU64,
+    refused: AtomicU64,
+}
+
+impl AdmissionController {
+    /// `global_limit` is shared by the controllers of every project.
+    pub fn new(
+        config: &AdmissionConfig,
+        project_limit: Option<usize>,
+        global_limit: Option<Arc<Semaphore>>,
+    ) -> Self {
+        AdmissionController {
+            project_limit: project_limit.map(|limit| Arc::new(Semaphore::new(limit))),
+            global_limit,
+            policy: config.policy,
+            queue_timeout: config.queue_timeout_secs.map(Duration::from_secs),
+            min_free_bytes: config.min_free_disk_mb * 1024 * 1024,
+            work_dir: config
+                .work_dir
+                .as_ref()
+                .map(PathBuf::from)
+                .unwrap_or_else(std::env::temp_dir),
+            admitted: AtomicU64::new(0),
+            queued: AtomicU64::new(0),
+            refused: AtomicU64::new(0),
+        }
+    }
+
+    pub fn work_dir(&self) -> &Path {
+        &self.work_dir
+    }
+
+    /// Checks free disk space and acquires the project and global slots for a session.
+    pub async fn admit(&self, session_id: &str) -> Result<AdmissionPermit, TextEgressError> {
+        let admission = async {
+            self.check_disk_space()?;
+            let project = self.acquire(self.project_limit.as_ref(), "project").await?;
+            let global = self.acquire(self.global_limit.as_ref(), "global").await?;
+            // Space may have run out while queued
+            self.check_disk_space()?;
+            Ok(AdmissionPermit {
+                _project: project,
+                _global: global,
+            })
+        };
+
+        match admission.await {
+            Ok(permit) => {
+                self.admitted.fetch_add(1, Ordering::Relaxed);
+                Ok(permit)
+            }
+            Err(e) => {
+                let refused = self.refused.fetch_add(1, Ordering::Relaxed) + 1;
+                log::warn!(
+                    "Refused session {}: {} ({} refused, {} queued, {} admitted so far)",
+                    session_id,
+                    e,
+                    refused,
+                    self.queued.load(Ordering::Relaxed),
+                    self.admitted.load(Ordering::Relaxed)
+                );
+                tracing::warn!(session_id = %session_id, refused, "session refused");
+                Err(e)
+            }
+        }
+    }
+
+    async fn acquire(
+        &self,
+        limit: Option<&Arc<Semaphore>>,
+        scope: &str,
+    ) -> Result<Option<OwnedSemaphorePermit>, TextEgressError> {
+        let Some(limit) = limit else {
+            return Ok(None);
+        };
+        if let Ok(permit) = limit.clone().try_acquire_owned() {
+            return Ok(Some(permit));
+        }
+
+        let refused = || {
+            TextEgressError::AdmissionRefused(format!("{} concurrent egress limit reached", scope))
+        };
+        if self.policy == AdmissionPolicy::Reject {
+            return Err(refused());
+        }
+
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        log::info!(
+            "Queueing session, {} concurrent egress limit reached",
+            scope
+        );
+        let acquire = limit.clone().acquire_owned();
+        let permit = match self.queue_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, acquire)
+                .await
+                .map_err(|_| refused())?,
+            None => acquire.await,
+        };
+        // The semaphores are never closed
+        Ok(Some(permit.map_err(|_| refused())?))
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn check_disk_space(&self) -> Result<(), TextEgressError> {
+        match available_space(&self.work_dir) {
+            Some(available) if available < self.min_free_bytes => {
+                Err(TextEgressError::AdmissionRefused(format!(
+                    "only {} MB free in {}",
+                    available / 1024 / 1024,
+                    self.work_dir.display()
+                )))
+            }
+            Some(_) => Ok(()),
+            None => {
+                log::warn!(
+                    "Could not determine free space in {}",
+                    self.work_dir.display()
+                );
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn available_space(path: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `path` is a valid NUL terminated string and `stat` is a valid out pointer
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn available_space(_path: &Path) -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(policy: AdmissionPolicy, queue_timeout_secs: Option<u64>) -> AdmissionConfig {
+        AdmissionConfig {
+            policy,
+            queue_timeout_secs,
+            min_free_disk_mb: 0,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn admits_without_limits() {
+        let controller =
+            AdmissionController::new(&config(AdmissionPolicy::Reject, None), None, None);
+        let _first = controller.admit("a").await.unwrap();
+        let _second = controller.admit("b").await.unwrap();
+        assert_eq!(controller.admitted.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn rejects_over_the_project_limit() {
+        let controller =
+            AdmissionController::new(&config(AdmissionPolicy::Reject, None), Some(1), None);
+        let permit = controller.admit("a").await.unwrap();
+        assert!(matches!(
+            controller.admit("b").await,
+            Err(TextEgressError::AdmissionRefused(_))
+        ));
+
+        drop(permit);
+        controller.admit("c").await.unwrap();
+        assert_eq!(controller.refused.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn shares_the_global_limit_across_projects() {
+        let global = Arc::new(Semaphore::new(1));
+        let config = config(AdmissionPolicy::Reject, None);
+        let first = AdmissionController::new(&config, None, Some(global.clone()));
+        let second = AdmissionController::new(&config, None, Some(global));
+
+        let _permit = first.admit("a").await.unwrap();
+        assert!(second.admit("b").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn queues_until_a_slot_is_released() {
+        let controller = Arc::new(AdmissionController::new(
+            &config(AdmissionPolicy::Queue, None),
+            Some(1),
+            None,
+        ));
+        let permit = controller.admit("a").await.unwrap();
+
+        let queued = tokio::spawn({
+            let controller = controller.clone();
+            async move { controller.admit("b").await.map(|_| ()) }
+        });
+        while controller.queued.load(Ordering::Relaxed) == 0 {
+            tokio::task::yield_now().await;
+        }
+        assert!(!queued.is_finished());
+
+        drop(permit);
+        queued.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn refuses_queued_sessions_after_the_timeout() {
+        let controller =
+            AdmissionController::new(&config(AdmissionPolicy::Queue, Some(1)), Some(1), None);
+        let _permit = controller.admit("a").await.unwrap();
+        assert!(matches!(
+            controller.admit("b").await,
+            Err(TextEgressError::AdmissionRefused(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn refuses_when_disk_space_is_low() {
+        let controller = AdmissionController::new(
+            &AdmissionConfig {
+                min_free_disk_mb: u64::MAX / 1024 / 1024,
+                ..Default::default()
+            },
+            None,
+            None,
+        );
+        assert!(matches!(
+            controller.admit("a").await,
+            Err(TextEgressError::AdmissionRefused(_))
+        ));
+    }
+}
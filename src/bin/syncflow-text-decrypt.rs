@@ -0,0 +1,40 @@
+use openssl::pkey::PKey;
+use std::error::Error;
+use std::path::Path;
+use std::{env, fs, process};
+use syncflow_text_egress_actor::encryption::{decrypt, ENCRYPTED_FILE_EXTENSION};
+
+/// Decrypts recordings uploaded with client side envelope encryption.
+///
+/// Usage: syncflow-text-decrypt <private-key.pem> <file.enc> [output]
+fn main() -> Result<(), Box<dyn Error>> {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 3 || args.len() > 4 {
+        eprintln!("Usage: {} <private-key.pem> <file.enc> [output]", args[0]);
+        process::exit(1);
+    }
+
+    let private_key = PKey::private_key_from_pem(&fs::read(&args[1])?)?;
+    let input = Path::new(&args[2]);
+    let output = match args.get(3) {
+        Some(output) => output.into(),
+        None if input.extension().and_then(|ext| ext.to_str())
+            == Some(ENCRYPTED_FILE_EXTENSION) =>
+        {
+            input.with_extension("")
+        }
+        None => {
+            eprintln!(
+                "Cannot derive output name for {}, pass it explicitly",
+                input.display()
+            );
+            process::exit(1);
+        }
+    };
+
+    let plaintext = decrypt(&private_key, &fs::read(input)?)?;
+    fs::write(&output, plaintext)?;
+    println!("Decrypted {} to {}", input.display(), output.display());
+
+    Ok(())
+}
@@ -0,0 +1,462 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+/// What to do with a message when a writer's queue is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Wait for the writer, slowing down room event processing
+    #[default]
+    Block,
+    /// Discard the oldest queued message
+    DropOldest,
+    /// Append the message to a `.spill.txt` sidecar file without fsync
+    Spill,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BufferingConfig {
+    /// Maximum number of messages queued per file
+    #[serde(default = "default_queue_capacity")]
+    pub queue_capacity: usize,
+    #[serde(default = "default_buffer_bytes")]
+    pub buffer_bytes: usize,
+    /// Interval between fsyncs, 0 only syncs when the file is closed
+    #[serde(default = "default_fsync_interval_ms")]
+    pub fsync_interval_ms: u64,
+    #[serde(default)]
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for BufferingConfig {
+    fn default() -> Self {
+        BufferingConfig {
+            queue_capacity: default_queue_capacity(),
+            buffer_bytes: default_buffer_bytes(),
+            fsync_interval_ms: default_fsync_interval_ms(),
+            overflow: OverflowPolicy::default(),
+        }
+    }
+}
+
+fn default_queue_capacity() -> usize {
+    1024
+}
+
+fn default_buffer_bytes() -> usize {
+    64 * 1024
+}
+
+fn default_fsync_interval_ms() -> u64 {
+    1000
+}
+
+/// Counters for a single writer, written to the egress metadata.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WriterMetrics {
+    pub enqueued: u64,
+    pub written: u64,
+    pub dropped: u64,
+    pub spilled: u64,
+    /// Number of writes that had to wait for queue space
+    pub blocked: u64,
+    pub fsyncs: u64,
+    pub max_queue_depth: u64,
+    /// Bytes written to the file, excluding dropped and spilled messages
+    #[serde(default)]
+    pub bytes_written: u64,
+    /// Receive times (ns) of the first and last messages written to the file
+    #[serde(default)]
+    pub first_written_at: Option<i64>,
+    #[serde(default)]
+    pub last_written_at: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WriterReport {
+    pub file_path: String,
+    /// Sidecar holding spilled messages, if any were spilled
+    pub spill_path: Option<String>,
+    pub metrics: WriterMetrics,
+}
+
+#[derive(Default)]
+struct Counters {
+    enqueued: AtomicU64,
+    written: AtomicU64,
+    dropped: AtomicU64,
+    spilled: AtomicU64,
+    blocked: AtomicU64,
+    fsyncs: AtomicU64,
+    max_queue_depth: AtomicU64,
+    bytes_written: AtomicU64,
+    /// Bytes queued for the file, neither written nor dropped yet
+    bytes_queued: AtomicU64,
+    written_range: Mutex<Option<(i64, i64)>>,
+}
+
+impl Counters {
+    fn snapshot(&self) -> WriterMetrics {
+        let written_range = *self.written_range.lock().unwrap();
+        WriterMetrics {
+            enqueued: self.enqueued.load(Ordering::Relaxed),
+            written: self.written.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            spilled: self.spilled.load(Ordering::Relaxed),
+            blocked: self.blocked.load(Ordering::Relaxed),
+            fsyncs: self.fsyncs.load(Ordering::Relaxed),
+            max_queue_depth: self.max_queue_depth.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            first_written_at: written_range.map(|(first, _)| first),
+            last_written_at: written_range.map(|(_, last)| last),
+        }
+    }
+}
+
+/// A line along with the time (ns) its message was received.
+struct QueuedLine {
+    line: Vec<u8>,
+    received_at: i64,
+}
+
+enum Enqueue {
+    Queued,
+    /// The queue is full and the line has to wait
+    Full(QueuedLine),
+    /// The queue is full and the line goes to the spill file
+    Spill(QueuedLine),
+}
+
+#[derive(Default)]
+struct Shared {
+    queue: Mutex<VecDeque<QueuedLine>>,
+    data_available: Notify,
+    space_available: Notify,
+    closed: AtomicBool,
+    counters: Counters,
+}
+
+/// Writes lines to a file from a background task through a bounded queue,
+/// so that a slow disk does not stall room event processing.
+pub struct BufferedWriter {
+    path: String,
+    capacity: usize,
+    overflow: OverflowPolicy,
+    shared: Arc<Shared>,
+    task: JoinHandle<std::io::Result<()>>,
+    spill: Option<(String, BufWriter<File>)>,
+}
+
+impl BufferedWriter {
+    pub fn spawn(file: File, path: &str, config: &BufferingConfig) -> Self {
+        let shared = Arc::new(Shared::default());
+        let task = tokio::spawn(run_writer(
+            BufWriter::with_capacity(config.buffer_bytes, file),
+            shared.clone(),
+            (config.fsync_interval_ms > 0).then(|| Duration::from_millis(config.fsync_interval_ms)),
+        ));
+
+        BufferedWriter {
+            path: path.to_string(),
+            capacity: config.queue_capacity.max(1),
+            overflow: config.overflow,
+            shared,
+            task,
+            spill: None,
+        }
+    }
+
+    /// Queues `line` for a message received at `received_at` (ns),
+    /// applying the overflow policy when the queue is full.
+    pub async fn write(&mut self, line: Vec<u8>, received_at: i64) -> std::io::Result<()> {
+        if self.task.is_finished() {
+            return Err(std::io::Error::other(format!(
+                "writer for {} stopped",
+                self.path
+            )));
+        }
+
+        let mut line = QueuedLine { line, received_at };
+        let mut waited = false;
+        loop {
+            line = match self.enqueue(line) {
+                Enqueue::Queued => break,
+                Enqueue::Full(line) => line,
+                Enqueue::Spill(line) => {
+                    self.shared.counters.spilled.fetch_add(1, Ordering::Relaxed);
+                    return self.spill(&line.line).await;
+                }
+            };
+            if !waited {
+                self.shared.counters.blocked.fetch_add(1, Ordering::Relaxed);
+                waited = true;
+            }
+            self.shared.space_available.notified().await;
+        }
+
+        self.shared.data_available.notify_one();
+        Ok(())
+    }
+
+    /// Queues `line` unless the queue is full, dropping the oldest line when configured to.
+    fn enqueue(&self, line: QueuedLine) -> Enqueue {
+        let counters = &self.shared.counters;
+        let mut queue = self.shared.queue.lock().unwrap();
+        if queue.len() >= self.capacity {
+            match self.overflow {
+                OverflowPolicy::Block => return Enqueue::Full(line),
+                OverflowPolicy::Spill => return Enqueue::Spill(line),
+                OverflowPolicy::DropOldest => {
+                    if let Some(dropped) = queue.pop_front() {
+                        counters
+                            .bytes_queued
+                            .fetch_sub(dropped.line.len() as u64, Ordering::Relaxed);
+                        counters.dropped.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+        }
+        counters
+            .bytes_queued
+            .fetch_add(line.line.len() as u64, Ordering::Relaxed);
+        queue.push_back(line);
+        counters.enqueued.fetch_add(1, Ordering::Relaxed);
+        counters
+            .max_queue_depth
+            .fetch_max(queue.len() as u64, Ordering::Relaxed);
+        Enqueue::Queued
+    }
+
+    async fn spill(&mut self, line: &[u8]) -> std::io::Result<()> {
+        if self.spill.is_none() {
+            let spill_path = match self.path.strip_suffix(".txt") {
+                Some(stem) => format!("{}.spill.txt", stem),
+                None => format!("{}.spill", self.path),
+            };
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&spill_path)
+                .await?;
+            self.spill = Some((spill_path, BufWriter::new(file)));
+        }
+        let (_, spill) = self.spill.as_mut().unwrap();
+        spill.write_all(line).await
+    }
+
+    /// Metrics so far, only counting the lines already written to the file.
+    pub fn metrics(&self) -> WriterMetrics {
+        self.shared.counters.snapshot()
+    }
+
+    /// Size the file will have once the queued lines are written.
+    pub fn pending_size(&self) -> u64 {
+        let counters = &self.shared.counters;
+        counters.bytes_written.load(Ordering::Relaxed)
+            + counters.bytes_queued.load(Ordering::Relaxed)
+    }
+
+    /// Drains the queue, syncs the file and reports the writer's metrics.
+    pub async fn close(mut self) -> std::io::Result<WriterReport> {
+        self.shared.closed.store(true, Ordering::Release);
+        self.shared.data_available.notify_one();
+        self.task.await.map_err(std::io::Error::other)??;
+
+        let spill_path = match self.spill.take() {
+            Some((spill_path, mut spill)) => {
+                spill.flush().await?;
+                spill.get_ref().sync_all().await?;
+                Some(spill_path)
+            }
+            None => None,
+        };
+
+        Ok(WriterReport {
+            file_path: self.path,
+            spill_path,
+            metrics: self.shared.counters.snapshot(),
+        })
+    }
+}
+
+impl std::fmt::Debug for BufferedWriter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BufferedWriter")
+            .field("path", &self.path)
+            .field("capacity", &self.capacity)
+            .field("overflow", &self.overflow)
+            .finish()
+    }
+}
+
+async fn run_writer(
+    mut writer: BufWriter<File>,
+    shared: Arc<Shared>,
+    fsync_interval: Option<Duration>,
+) -> std::io::Result<()> {
+    let counters = &shared.counters;
+    let mut next_sync = fsync_interval.map(|interval| Instant::now() + interval);
+
+    loop {
+        let batch: Vec<QueuedLine> = shared.queue.lock().unwrap().drain(..).collect();
+        if !batch.is_empty() {
+            shared.space_available.notify_one();
+            for QueuedLine { line, received_at } in batch {
+                writer.write_all(&line).await?;
+                let bytes = line.len() as u64;
+                counters.bytes_queued.fetch_sub(bytes, Ordering::Relaxed);
+                counters.bytes_written.fetch_add(bytes, Ordering::Relaxed);
+                counters.written.fetch_add(1, Ordering::Relaxed);
+                let mut written_range = counters.written_range.lock().unwrap();
+                let (first, _) = written_range.get_or_insert((received_at, received_at));
+                *written_range = Some((*first, received_at));
+            }
+        }
+
+        if let (Some(interval), Some(deadline)) = (fsync_interval, next_sync) {
+            if Instant::now() >= deadline {
+                writer.flush().await?;
+                writer.get_ref().sync_data().await?;
+                counters.fsyncs.fetch_add(1, Ordering::Relaxed);
+                next_sync = Some(Instant::now() + interval);
+            }
+        }
+
+        if !batch_pending(&shared) {
+            if shared.closed.load(Ordering::Acquire) {
+                break;
+            }
+            match next_sync {
+                Some(deadline) => {
+                    tokio::select! {
+                        _ = shared.data_available.notified() => {}
+                        _ = tokio::time::sleep_until(deadline) => {}
+                    }
+                }
+                None => shared.data_available.notified().await,
+            }
+        }
+    }
+
+    writer.flush().await?;
+    writer.get_ref().sync_all().await?;
+    counters.fsyncs.fetch_add(1, Ordering::Relaxed);
+    Ok(())
+}
+
+fn batch_pending(shared: &Shared) -> bool {
+    !shared.queue.lock().unwrap().is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    async fn writer(
+        dir: &TempDir,
+        capacity: usize,
+        overflow: OverflowPolicy,
+    ) -> (String, BufferedWriter) {
+        let path = dir.path().join("alice.txt").to_string_lossy().to_string();
+        let file = File::create(&path).await.unwrap();
+        let config = BufferingConfig {
+            queue_capacity: capacity,
+            overflow,
+            ..Default::default()
+        };
+        let writer = BufferedWriter::spawn(file, &path, &config);
+        (path, writer)
+    }
+
+    fn line(n: i64) -> Vec<u8> {
+        format!("line {}\n", n).into_bytes()
+    }
+
+    #[tokio::test]
+    async fn writes_every_line_when_blocking() {
+        let dir = TempDir::new("buffered-writer").unwrap();
+        let (path, mut writer) = writer(&dir, 1, OverflowPolicy::Block).await;
+        for n in 1..=5 {
+            writer.write(line(n), n).await.unwrap();
+        }
+        let report = writer.close().await.unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(content, "line 1\nline 2\nline 3\nline 4\nline 5\n");
+        assert_eq!(report.metrics.enqueued, 5);
+        assert_eq!(report.metrics.written, 5);
+        assert!(report.metrics.blocked > 0);
+        assert_eq!(report.metrics.bytes_written, content.len() as u64);
+        assert_eq!(report.metrics.first_written_at, Some(1));
+        assert_eq!(report.metrics.last_written_at, Some(5));
+        assert!(report.spill_path.is_none());
+    }
+
+    #[tokio::test]
+    async fn counts_only_lines_that_were_written_when_dropping() {
+        let dir = TempDir::new("buffered-writer").unwrap();
+        let (path, mut writer) = writer(&dir, 2, OverflowPolicy::DropOldest).await;
+        // The writer task does not run before the first await, so the queue overflows
+        for n in 1..=5 {
+            writer.write(line(n), n).await.unwrap();
+        }
+        assert_eq!(
+            writer.pending_size(),
+            (line(4).len() + line(5).len()) as u64
+        );
+        let report = writer.close().await.unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(content, "line 4\nline 5\n");
+        assert_eq!(report.metrics.dropped, 3);
+        assert_eq!(report.metrics.written, 2);
+        assert_eq!(report.metrics.bytes_written, content.len() as u64);
+        assert_eq!(report.metrics.first_written_at, Some(4));
+        assert_eq!(report.metrics.last_written_at, Some(5));
+    }
+
+    #[tokio::test]
+    async fn spills_lines_to_a_sidecar() {
+        let dir = TempDir::new("buffered-writer").unwrap();
+        let (path, mut writer) = writer(&dir, 2, OverflowPolicy::Spill).await;
+        for n in 1..=10 {
+            writer.write(line(n), n).await.unwrap();
+        }
+        let report = writer.close().await.unwrap();
+
+        let spill_path = report.spill_path.clone().unwrap();
+        assert!(spill_path.ends_with("alice.spill.txt"));
+        let content = std::fs::read_to_string(&path).unwrap();
+        let spilled = std::fs::read_to_string(&spill_path).unwrap();
+        assert!(report.metrics.spilled > 0);
+        assert_eq!(report.metrics.written + report.metrics.spilled, 10);
+        assert_eq!(content.lines().count() as u64, report.metrics.written);
+        assert_eq!(spilled.lines().count() as u64, report.metrics.spilled);
+        assert_eq!(report.metrics.bytes_written, content.len() as u64);
+        assert!(content.starts_with("line 1\nline 2\n"));
+    }
+
+    #[tokio::test]
+    async fn reports_progress_before_closing() {
+        let dir = TempDir::new("buffered-writer").unwrap();
+        let (_path, mut writer) = writer(&dir, 16, OverflowPolicy::Block).await;
+        writer.write(line(1), 1).await.unwrap();
+        assert_eq!(writer.metrics().written, 0);
+
+        while writer.metrics().written == 0 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(writer.metrics().first_written_at, Some(1));
+        assert_eq!(writer.pending_size(), line(1).len() as u64);
+        writer.close().await.unwrap();
+    }
+}
@@ -0,0 +1,335 @@
+use clap::{Args, Parser, Subcommand};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use syncflow_client::ProjectClient;
+use uuid::Uuid;
+
+use crate::admin::{AdminClient, InstanceStatus, DEFAULT_ADMIN_URL};
+use crate::config::{ConfigSources, TextEgressConfig};
+use crate::error_messages::TextEgressError;
+use crate::manifest::EgressManifest;
+use crate::recording::read_recording;
+use crate::room_listener_actor::{DataEgressResultFiles, TextEgressMetadata};
+use crate::s3_uploader_actor::upload_egress;
+use crate::secrets::Secret;
+use crate::session_listener_actor::{egress_prefix, TextEgressInfo, TextEgressStatus};
+
+#[derive(Debug, Parser)]
+#[command(version, about = "Records text streams of SyncFlow sessions to S3")]
+pub struct Cli {
+    #[command(flatten)]
+    pub sources: ConfigSources,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Register the configured projects and record their sessions (default)
+    Run,
+    /// Run the preflight checks and exit
+    Check,
+    /// Upload the files of a recording left on disk
+    Upload {
+        /// Directory holding the participant files and metadata.json
+        dir: PathBuf,
+        /// Project to upload for, required when several are configured
+        #[arg(long)]
+        project: Option<String>,
+        /// Egress id of the recording, a new `manual-` id by default
+        #[arg(long)]
+        egress_id: Option<String>,
+        #[arg(long)]
+        session_id: Option<String>,
+        /// Key prefix, by default the one the egress would have used
+        #[arg(long)]
+        prefix: Option<String>,
+    },
+    /// Validate a participant file, metadata.json or manifest.json
+    Inspect {
+        file: PathBuf,
+        /// Print the summary as JSON
+        #[arg(long)]
+        json: bool,
+        /// Print every record of a participant file
+        #[arg(long)]
+        records: bool,
+    },
+    /// Show the projects and egresses of a running instance
+    Status(AdminArgs),
+    /// List the running egresses of a running instance
+    List(AdminArgs),
+    /// Stop an egress of a running instance
+    Stop {
+        egress_id: String,
+        #[command(flatten)]
+        admin: AdminArgs,
+    },
+}
+
+#[derive(Debug, Args)]
+pub struct AdminArgs {
+    /// Admin API of the running instance
+    #[arg(long, default_value = DEFAULT_ADMIN_URL)]
+    pub admin_url: String,
+    /// Bearer token, if the admin API requires one
+    #[arg(long, env = "ADMIN_TOKEN", hide_env_values = true)]
+    pub admin_token: Option<String>,
+}
+
+impl AdminArgs {
+    fn client(&self) -> AdminClient {
+        AdminClient::new(&self.admin_url, self.admin_token.clone().map(Secret::new))
+    }
+}
+
+/// Uploads a recording directory with the S3 settings of a configured project.
+pub async fn upload(
+    config: &TextEgressConfig,
+    dir: &Path,
+    project_id: Option<&str>,
+    egress_id: Option<String>,
+    session_id: Option<String>,
+    prefix: Option<String>,
+) -> Result<(), TextEgressError> {
+    let project = match project_id {
+        Some(id) => config.projects.iter().find(|p| p.project_id == id),
+        None if config.projects.len() == 1 => config.projects.first(),
+        None => {
+            return Err(TextEgressError::ConfigFileError(
+                "--project is required when several projects are configured".to_string(),
+            ))
+        }
+    }
+    .ok_or_else(|| {
+        TextEgressError::ConfigFileError(format!(
+            "project {} is not configured",
+            project_id.unwrap_or_default()
+        ))
+    })?;
+
+    let metadata_path = dir.join("metadata.json");
+    let metadata: Option<TextEgressMetadata> = if metadata_path.exists() {
+        Some(serde_json::from_slice(&std::fs::read(&metadata_path)?)?)
+    } else {
+        None
+    };
+    let topic = metadata.as_ref().and_then(|m| m.topic.clone());
+
+    let mut files = vec![];
+    if metadata.is_some() {
+        files.push(DataEgressResultFiles {
+            participant: "metadata".to_string(),
+            file_path: metadata_path.display().to_string(),
+            topic: topic.clone(),
+            ..Default::default()
+        });
+    }
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "txt"))
+        .collect();
+    paths.sort();
+    for path in paths {
+        let (_, summary) = read_recording(&path)?;
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let (participant, segment) = parse_file_stem(&stem, topic.as_deref());
+        files.push(DataEgressResultFiles {
+            participant,
+            file_path: path.display().to_string(),
+            topic: topic.clone(),
+            message_count: summary.message_count,
+            first_message_at: summary.first_message_at,
+            last_message_at: summary.last_message_at,
+            segment,
+        });
+    }
+
+    let info = TextEgressInfo {
+        egress_id: egress_id.unwrap_or_else(|| format!("manual-{}", Uuid::new_v4())),
+        session_id: session_id.unwrap_or_default(),
+        room_name: metadata
+            .as_ref()
+            .map(|m| m.room_name.clone())
+            .unwrap_or_default(),
+        topic,
+        started_at: metadata.as_ref().map(|m| m.started_at as usize),
+        stopped_at: metadata
+            .as_ref()
+            .and_then(|m| m.ended_at)
+            .map(|t| t as usize),
+        files: files.clone(),
+        error: None,
+        status: TextEgressStatus::Stopped,
+        paths: vec![],
+        s3_bucket_name: Some(project.s3_config.bucket_name.clone()),
+        uploaded_segments: HashMap::new(),
+    };
+    let prefix = match prefix {
+        Some(prefix) => prefix,
+        None => {
+            let client = ProjectClient::new(
+                &config.syncflow_server_url,
+                &project.project_id,
+                &project.key,
+                project.secret.expose(),
+            );
+            let project_name = client.get_project_details().await?.name;
+            egress_prefix(&project_name, &project.project_id, &info)
+        }
+    };
+
+    let manifest = upload_egress(
+        &project.s3_config,
+        &prefix,
+        &files,
+        EgressManifest::from(&info),
+    )
+    .await?;
+    for object in &manifest.objects {
+        println!("{:>10}  {}", object.size, object.key);
+    }
+    println!(
+        "Uploaded {} objects to s3://{}/{}",
+        manifest.objects.len(),
+        project.s3_config.bucket_name,
+        prefix
+    );
+    Ok(())
+}
+
+/// Splits a participant file name, `<participant>-<topic>-<date>[-<segment>]`.
+fn parse_file_stem(stem: &str, topic: Option<&str>) -> (String, u32) {
+    let marker = format!("-{}-", topic.unwrap_or("all-topics"));
+    let Some((participant, date)) = stem.rsplit_once(&marker) else {
+        return (stem.to_string(), 0);
+    };
+    let segment = date
+        .rsplit_once('-')
+        .and_then(|(_, segment)| segment.parse().ok())
+        .unwrap_or(0);
+    (participant.to_string(), segment)
+}
+
+/// Prints a file's summary, returning whether it is valid.
+#[allow(clippy::result_large_err)]
+pub fn inspect(path: &Path, json: bool, records: bool) -> Result<bool, TextEgressError> {
+    if path.extension().is_some_and(|ext| ext == "json") {
+        let content = std::fs::read(path)?;
+        let value: serde_json::Value = serde_json::from_slice(&content)?;
+        let valid = serde_json::from_value::<TextEgressMetadata>(value.clone()).is_ok()
+            || serde_json::from_value::<EgressManifest>(value.clone()).is_ok();
+        println!("{}", serde_json::to_string_pretty(&value)?);
+        if !valid {
+            eprintln!(
+                "{} is neither egress metadata nor a manifest",
+                path.display()
+            );
+        }
+        return Ok(valid);
+    }
+
+    let (lines, summary) = read_recording(path)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&summary)?);
+    } else {
+        println!("{}", summary);
+    }
+    if records {
+        for record in &lines {
+            if json {
+                println!("{}", serde_json::to_string(record)?);
+            } else {
+                println!("{}  {}", record.received_at_iso, record.text);
+            }
+        }
+    }
+    Ok(summary.is_valid())
+}
+
+pub async fn status(args: &AdminArgs) -> Result<(), TextEgressError> {
+    let status = args.client().status().await?;
+    println!("syncflow-text-egress-actor {}", status.version);
+    for project in &status.projects {
+        let state = if project.draining {
+            "draining"
+        } else if project.registered {
+            "registered"
+        } else {
+            "not registered"
+        };
+        println!(
+            "{}  {}  {} egresses",
+            project.project_id,
+            state,
+            project.egresses.len()
+        );
+    }
+    for project in &status.degraded {
+        println!(
+            "{}  degraded after {} attempts, retrying at {}: {}",
+            project.project_id, project.attempts, project.retry_at, project.error
+        );
+    }
+    for project in &status.errors {
+        println!("{}  unavailable: {}", project.project_id, project.error);
+    }
+    Ok(())
+}
+
+pub async fn list(args: &AdminArgs) -> Result<(), TextEgressError> {
+    let status = args.client().status().await?;
+    print_egresses(&status);
+    Ok(())
+}
+
+pub async fn stop(args: &AdminArgs, egress_id: &str) -> Result<(), TextEgressError> {
+    args.client().stop_egress(egress_id).await?;
+    println!("Stopping egress {}", egress_id);
+    Ok(())
+}
+
+fn print_egresses(status: &InstanceStatus) {
+    println!(
+        "{:<38} {:<38} {:<24} {:<16} {:<12}",
+        "EGRESS", "PROJECT", "ROOM", "TOPIC", "STATUS"
+    );
+    for project in &status.projects {
+        for egress in &project.egresses {
+            let egress_status = format!("{:?}", egress.status);
+            println!(
+                "{:<38} {:<38} {:<24} {:<16} {:<12}",
+                egress.egress_id,
+                project.project_id,
+                egress.room_name,
+                egress.topic.as_deref().unwrap_or("all-topics"),
+                egress_status
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_participant_file_names() {
+        assert_eq!(
+            parse_file_stem("alice-all-topics-2024-05-01T10:00:00UTC", None),
+            ("alice".to_string(), 0)
+        );
+        assert_eq!(
+            parse_file_stem(
+                "bob-smith-transcript-2024-05-01T10:00:00UTC-2",
+                Some("transcript")
+            ),
+            ("bob-smith".to_string(), 2)
+        );
+        // Files of another topic keep their whole name
+        assert_eq!(
+            parse_file_stem("alice-chat-2024-05-01T10:00:00UTC", Some("transcript")),
+            ("alice-chat-2024-05-01T10:00:00UTC".to_string(), 0)
+        );
+    }
+}
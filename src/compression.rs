@@ -0,0 +1,119 @@
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+
+use crate::error_messages::TextEgressError;
+
+/// Compression applied to recordings before they are uploaded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// The `Content-Encoding` of the compressed object.
+    pub fn content_encoding(&self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            Compression::Gzip => Some("gzip"),
+            Compression::Zstd => Some("zstd"),
+        }
+    }
+
+    /// The suffix appended to object keys.
+    pub fn extension(&self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            Compression::Gzip => Some("gz"),
+            Compression::Zstd => Some("zst"),
+        }
+    }
+
+    pub fn compress(&self, content: Vec<u8>) -> std::io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(content),
+            Compression::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(&content)?;
+                encoder.finish()
+            }
+            Compression::Zstd => zstd::encode_all(content.as_slice(), 0),
+        }
+    }
+
+    /// The compression of an object with the given file extension.
+    pub fn from_extension(extension: &str) -> Self {
+        match extension {
+            "gz" => Compression::Gzip,
+            "zst" => Compression::Zstd,
+            _ => Compression::None,
+        }
+    }
+
+    pub fn decompress(&self, content: Vec<u8>) -> std::io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(content),
+            Compression::Gzip => {
+                let mut decoder = flate2::write::GzDecoder::new(Vec::new());
+                decoder.write_all(&content)?;
+                decoder.finish()
+            }
+            Compression::Zstd => zstd::decode_all(content.as_slice()),
+        }
+    }
+
+    /// Compresses on the blocking pool, recordings can be large.
+    pub async fn compress_blocking(&self, content: Vec<u8>) -> Result<Vec<u8>, TextEgressError> {
+        let compression = *self;
+        let compressed = tokio::task::spawn_blocking(move || compression.compress(content))
+            .await
+            .map_err(|e| TextEgressError::CompressionError(e.to_string()))??;
+        Ok(compressed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [Compression; 3] = [Compression::None, Compression::Gzip, Compression::Zstd];
+
+    #[test]
+    fn round_trips_content() {
+        let content = b"2024-01-01T00:00:00UTC|1|1||hello\n".repeat(100);
+        for compression in ALL {
+            let compressed = compression.compress(content.clone()).unwrap();
+            if compression != Compression::None {
+                assert!(compressed.len() < content.len(), "{:?}", compression);
+            }
+            assert_eq!(compression.decompress(compressed).unwrap(), content);
+        }
+    }
+
+    #[test]
+    fn detects_compression_from_the_extension() {
+        for compression in ALL {
+            let extension = compression.extension().unwrap_or("txt");
+            assert_eq!(Compression::from_extension(extension), compression);
+        }
+    }
+
+    #[test]
+    fn fails_on_corrupt_content() {
+        assert!(Compression::Gzip.decompress(b"not gzip".to_vec()).is_err());
+        assert!(Compression::Zstd.decompress(b"not zstd".to_vec()).is_err());
+    }
+
+    #[tokio::test]
+    async fn compresses_on_the_blocking_pool() {
+        let compressed = Compression::Zstd
+            .compress_blocking(b"hello".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(Compression::Zstd.decompress(compressed).unwrap(), b"hello");
+    }
+}
@@ -1,7 +1,21 @@
+use clap::Args;
 use dotenvy::dotenv;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
 
+use crate::admin::AdminConfig;
+use crate::admission::AdmissionConfig;
+use crate::buffered_writer::BufferingConfig;
+use crate::compression::Compression;
+use crate::config_file::{flatten, read_config_file};
+use crate::coordination::CoordinationConfig;
 use crate::error_messages::TextEgressError;
+use crate::join_tokens::JoinTokenConfig;
+use crate::rotation::RotationConfig;
+use crate::secrets::{resolve, resolve_secret_files, Secret, SecretProvider, SecretsConfig};
+use crate::status_reporter::StatusReportingConfig;
+use crate::supervision::{ReconnectConfig, RestartPolicy};
 
 fn load_env() {
     match dotenv() {
@@ -15,7 +29,7 @@ fn load_env() {
     };
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct TextEgressConfig {
     pub syncflow_server_url: String,
     pub projects: Vec<Projects>,
@@ -23,33 +37,406 @@ pub struct TextEgressConfig {
     pub rabbitmq_port: u16,
     pub rabbitmq_vhost_name: String,
     pub device_group_name: String,
+    #[serde(default)]
+    pub tracing: TracingConfig,
+    #[serde(default)]
+    pub admission: AdmissionConfig,
+    #[serde(default)]
+    pub coordination: CoordinationConfig,
+    /// Providers for secrets referenced as `<scheme>:<reference>`
+    #[serde(default)]
+    pub secrets: SecretsConfig,
+    #[serde(default)]
+    pub admin: AdminConfig,
+    #[serde(default)]
+    pub status_reporting: StatusReportingConfig,
+    /// Check every dependency before registering any project
+    #[serde(default = "default_preflight")]
+    pub preflight: bool,
+    /// Write a test object to every bucket during the checks on startup, `check` always does
+    #[serde(default)]
+    pub preflight_s3_write: bool,
+    /// How long a removed project waits for its egresses before stopping their room listeners
+    #[serde(default = "default_drain_timeout_secs")]
+    pub drain_timeout_secs: u64,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+fn default_preflight() -> bool {
+    true
+}
+
+fn default_drain_timeout_secs() -> u64 {
+    3600
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Projects {
     pub key: String,
-    pub secret: String,
+    pub secret: Secret,
     pub project_id: String,
     pub s3_config: S3Config,
+    #[serde(default)]
+    pub recording: RecordingConfig,
+    /// Limit on concurrent egresses for this project
+    #[serde(default)]
+    pub max_concurrent_egresses: Option<usize>,
+    /// Start a new egress when a session is notified again after its egress failed
+    #[serde(default)]
+    pub retry_failed_sessions: bool,
+}
+
+impl Projects {
+    /// Fetches the secrets of the project referenced as `<scheme>:<reference>`.
+    pub async fn resolve_secrets(
+        &mut self,
+        providers: &[Box<dyn SecretProvider>],
+    ) -> Result<(), TextEgressError> {
+        if providers.is_empty() {
+            return Ok(());
+        }
+        let recording = &mut self.recording;
+        let secrets = [
+            Some(&mut self.secret),
+            Some(&mut self.s3_config.secret_key),
+            self.s3_config.encryption.customer_key.as_mut(),
+            recording.timestamps.livekit_api_secret.as_mut(),
+            recording.pseudonymization.key.as_mut(),
+        ];
+        for secret in secrets.into_iter().flatten() {
+            resolve(secret, providers).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Per project options applied by the room listeners while recording.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RecordingConfig {
+    #[serde(default)]
+    pub redaction: RedactionConfig,
+    #[serde(default)]
+    pub participants: ParticipantFilterConfig,
+    #[serde(default)]
+    pub pseudonymization: PseudonymizationConfig,
+    #[serde(default)]
+    pub consent: ConsentConfig,
+    #[serde(default)]
+    pub timestamps: TimestampsConfig,
+    #[serde(default)]
+    pub rotation: RotationConfig,
+    #[serde(default)]
+    pub buffering: BufferingConfig,
+    #[serde(default)]
+    pub restart: RestartPolicy,
+    #[serde(default)]
+    pub reconnect: ReconnectConfig,
+    #[serde(default)]
+    pub join_token: JoinTokenConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TimestampsConfig {
+    /// Key of the sender timestamp in JSON payloads
+    #[serde(default = "default_sender_timestamp_key")]
+    pub sender_timestamp_key: String,
+    /// LiveKit API credentials, used to look up the room's creation time
+    #[serde(default)]
+    pub livekit_api_key: Option<String>,
+    #[serde(default)]
+    pub livekit_api_secret: Option<Secret>,
+}
+
+impl Default for TimestampsConfig {
+    fn default() -> Self {
+        TimestampsConfig {
+            sender_timestamp_key: default_sender_timestamp_key(),
+            livekit_api_key: None,
+            livekit_api_secret: None,
+        }
+    }
+}
+
+fn default_sender_timestamp_key() -> String {
+    "timestamp".to_string()
+}
+
+/// Only records participants whose attributes or metadata carry the consent flag.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConsentConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Attribute name, or top level key when the metadata is a JSON object
+    #[serde(default = "default_consent_key")]
+    pub key: String,
+    #[serde(default = "default_consent_value")]
+    pub value: String,
+}
+
+impl Default for ConsentConfig {
+    fn default() -> Self {
+        ConsentConfig {
+            enabled: false,
+            key: default_consent_key(),
+            value: default_consent_value(),
+        }
+    }
+}
+
+fn default_consent_key() -> String {
+    "recording_consent".to_string()
+}
+
+fn default_consent_value() -> String {
+    "true".to_string()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ParticipantKindConfig {
+    Standard,
+    Ingress,
+    Egress,
+    Sip,
+    Agent,
+}
+
+/// Decides which participants are recorded, everyone is recorded by default.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ParticipantFilterConfig {
+    /// Identity patterns to record, all identities when empty
+    #[serde(default)]
+    pub include_identities: Vec<String>,
+    #[serde(default)]
+    pub exclude_identities: Vec<String>,
+    /// Participant kinds to record, all kinds when empty
+    #[serde(default)]
+    pub include_kinds: Vec<ParticipantKindConfig>,
+    #[serde(default)]
+    pub exclude_kinds: Vec<ParticipantKindConfig>,
+}
+
+/// Replaces participant identities with keyed HMAC-SHA256 aliases.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PseudonymizationConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub key: Option<Secret>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RedactionRuleConfig {
+    /// Placeholder label, matches are replaced with `[LABEL]`
+    pub label: String,
+    pub pattern: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RedactionConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_true")]
+    pub emails: bool,
+    #[serde(default = "default_true")]
+    pub phone_numbers: bool,
+    #[serde(default)]
+    pub student_id_pattern: Option<String>,
+    #[serde(default)]
+    pub rules: Vec<RedactionRuleConfig>,
+    /// Exact terms (whole words, case insensitive) replaced with `[DICTIONARY]`
+    #[serde(default)]
+    pub dictionary: Vec<String>,
+    /// File with one dictionary term per line
+    #[serde(default)]
+    pub dictionary_path: Option<String>,
+    /// Known person names (whole words, case insensitive) replaced with `[NAME]`
+    #[serde(default)]
+    pub names: Vec<String>,
+    /// File with one name per line
+    #[serde(default)]
+    pub names_path: Option<String>,
+    /// Also replace capitalized words that do not start a sentence with `[NAME]`
+    #[serde(default = "default_true")]
+    pub capitalized_names: bool,
+}
+
+impl Default for RedactionConfig {
+    fn default() -> Self {
+        RedactionConfig {
+            enabled: false,
+            emails: true,
+            phone_numbers: true,
+            student_id_pattern: None,
+            rules: vec![],
+            dictionary: vec![],
+            dictionary_path: None,
+            names: vec![],
+            names_path: None,
+            capitalized_names: true,
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct S3Config {
     pub access_key: String,
-    pub secret_key: String,
+    pub secret_key: Secret,
     pub bucket_name: String,
     pub endpoint: String,
     pub region: String,
+    #[serde(default)]
+    pub compression: Compression,
+    #[serde(default)]
+    pub encryption: EncryptionConfig,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ServerSideEncryption {
+    #[default]
+    None,
+    /// SSE-S3, objects are encrypted with keys managed by the bucket
+    S3,
+    /// SSE-C, objects are encrypted with `customer_key`
+    Customer,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct EncryptionConfig {
+    /// PEM encoded RSA public key, enables client side envelope encryption when set
+    #[serde(default)]
+    pub public_key_path: Option<String>,
+    #[serde(default)]
+    pub server_side: ServerSideEncryption,
+    /// Base64 encoded 256-bit key for SSE-C
+    #[serde(default)]
+    pub customer_key: Option<Secret>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TracingExporter {
+    #[default]
+    None,
+    OtlpGrpc,
+    OtlpHttp,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TracingConfig {
+    #[serde(default)]
+    pub exporter: TracingExporter,
+    /// Collector endpoint, defaults to the exporter's standard OTLP endpoint when unset
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+    #[serde(default = "default_sampling_ratio")]
+    pub sampling_ratio: f64,
+}
+
+impl Default for TracingConfig {
+    fn default() -> Self {
+        TracingConfig {
+            exporter: TracingExporter::None,
+            endpoint: None,
+            service_name: default_service_name(),
+            sampling_ratio: default_sampling_ratio(),
+        }
+    }
+}
+
+fn default_service_name() -> String {
+    "syncflow-text-egress-actor".to_string()
+}
+
+fn default_sampling_ratio() -> f64 {
+    1.0
+}
+
+/// Variable naming the config file when `--config` is not given
+pub const CONFIG_FILE_VAR: &str = "TEXT_EGRESS_CONFIG";
+
+/// Where the configuration is read from, in increasing precedence: the config file,
+/// environment variables and `--set` overrides from the command line.
+#[derive(Debug, Clone, Default, Args)]
+pub struct ConfigSources {
+    /// TOML or YAML configuration file, also read from `TEXT_EGRESS_CONFIG`
+    #[arg(short = 'c', long = "config", global = true)]
+    pub file: Option<PathBuf>,
+    /// Overrides a configuration value, such as `--set projects.0.s3_config.bucket_name=recordings`
+    #[arg(long = "set", value_name = "KEY=VALUE", value_parser = parse_override, global = true)]
+    pub overrides: Vec<(String, String)>,
+}
+
+fn parse_override(arg: &str) -> Result<(String, String), String> {
+    let (key, value) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected <key>=<value>, got {}", arg))?;
+    Ok((key.to_string(), value.to_string()))
+}
+
+impl ConfigSources {
+    /// The config file given with `--config` or `TEXT_EGRESS_CONFIG`, if any.
+    pub fn file_path(&self) -> Option<PathBuf> {
+        self.file
+            .clone()
+            .or_else(|| std::env::var_os(CONFIG_FILE_VAR).map(PathBuf::from))
+    }
 }
 
 impl TextEgressConfig {
     #[allow(clippy::result_large_err)]
     pub fn load() -> Result<Self, TextEgressError> {
+        Self::load_from(&ConfigSources::default())
+    }
+
+    /// Merges the config file, the environment and command line overrides.
+    #[allow(clippy::result_large_err)]
+    pub fn load_from(sources: &ConfigSources) -> Result<Self, TextEgressError> {
         load_env();
-        let config = envious::Config::default().build_from_env::<TextEgressConfig>()?;
+        let file = sources.file_path();
+
+        let mut file_vars = BTreeMap::new();
+        if let Some(file) = file {
+            log::info!("Loading configuration from {}", file.display());
+            file_vars.extend(flatten(&read_config_file(&file)?));
+        }
+        let env_vars = std::env::vars()
+            .map(|(key, value)| (key.to_uppercase(), value))
+            .collect();
+        let override_vars = sources
+            .overrides
+            .iter()
+            .map(|(key, value)| (key.replace('.', "__").to_uppercase(), value.clone()))
+            .collect();
+
+        // Keys are case insensitive, later sources replace earlier ones
+        let mut vars = BTreeMap::new();
+        for mut source in [file_vars, env_vars, override_vars] {
+            resolve_secret_files(&mut source)?;
+            vars.extend(source);
+        }
+
+        let config =
+            envious::Config::default().build_from_iter::<TextEgressConfig, _, _, _>(vars)?;
+        config.admin.validate()?;
         Ok(config)
     }
 
+    /// Fetches secrets referenced as `<scheme>:<reference>` from the configured providers.
+    pub async fn resolve_secrets(&mut self) -> Result<(), TextEgressError> {
+        let providers = self.secrets.providers();
+        for project in self.projects.iter_mut() {
+            project.resolve_secrets(&providers).await?;
+        }
+        Ok(())
+    }
+
     #[allow(clippy::result_large_err)]
     pub fn from_filename(file_path: &str) -> Result<Self, TextEgressError> {
         let _ = dotenvy::from_filename(file_path)?;
@@ -57,3 +444,47 @@ impl TextEgressConfig {
         Ok(config)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Debug, Parser)]
+    struct TestCli {
+        #[command(flatten)]
+        sources: ConfigSources,
+    }
+
+    #[test]
+    fn parses_config_sources_from_arguments() {
+        let cli = TestCli::try_parse_from([
+            "syncflow-text-egress-actor",
+            "-c",
+            "config.toml",
+            "--set",
+            "projects.0.s3_config.bucket_name=recordings",
+            "--set",
+            "admission.policy=reject=now",
+        ])
+        .unwrap();
+        assert_eq!(cli.sources.file_path(), Some(PathBuf::from("config.toml")));
+        assert_eq!(
+            cli.sources.overrides,
+            vec![
+                (
+                    "projects.0.s3_config.bucket_name".to_string(),
+                    "recordings".to_string()
+                ),
+                ("admission.policy".to_string(), "reject=now".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_overrides_without_a_value() {
+        assert!(
+            TestCli::try_parse_from(["syncflow-text-egress-actor", "--set", "projects"]).is_err()
+        );
+    }
+}
@@ -0,0 +1,181 @@
+use regex::{Captures, Regex};
+use serde_json::Value;
+use std::path::Path;
+use std::sync::LazyLock;
+
+use crate::error_messages::TextEgressError;
+
+/// `${VAR}` or `${VAR:-default}`
+static ENV_REFERENCE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\$\{([A-Za-z_][A-Za-z0-9_]*)(?::-([^}]*))?\}").unwrap());
+
+/// Reads a TOML or YAML config file, chosen by extension, and interpolates `${VAR}` and
+/// `${VAR:-default}` references to environment variables in its string values.
+#[allow(clippy::result_large_err)]
+pub fn read_config_file(path: &Path) -> Result<Value, TextEgressError> {
+    let content = std::fs::read_to_string(path)?;
+    let error = |e: String| TextEgressError::ConfigFileError(format!("{}: {}", path.display(), e));
+    let mut value: Value = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => toml::from_str(&content).map_err(|e| error(e.to_string()))?,
+        Some("yaml") | Some("yml") => {
+            serde_yaml::from_str(&content).map_err(|e| error(e.to_string()))?
+        }
+        _ => return Err(error("expected a .toml, .yaml or .yml file".to_string())),
+    };
+    interpolate(&mut value).map_err(error)?;
+    Ok(value)
+}
+
+fn interpolate(value: &mut Value) -> Result<(), String> {
+    match value {
+        Value::String(s) => *s = interpolate_str(s)?,
+        Value::Array(items) => items.iter_mut().try_for_each(interpolate)?,
+        Value::Object(fields) => fields.values_mut().try_for_each(interpolate)?,
+        _ => {}
+    }
+    Ok(())
+}
+
+fn interpolate_str(s: &str) -> Result<String, String> {
+    let mut missing = None;
+    let interpolated = ENV_REFERENCE.replace_all(s, |caps: &Captures| {
+        match (std::env::var(&caps[1]), caps.get(2)) {
+            (Ok(value), _) => value,
+            (Err(_), Some(default)) => default.as_str().to_string(),
+            (Err(_), None) => {
+                missing = Some(caps[1].to_string());
+                String::new()
+            }
+        }
+    });
+    match missing {
+        Some(name) => Err(format!("environment variable {} is not set", name)),
+        None => Ok(interpolated.into_owned()),
+    }
+}
+
+/// Flattens a config document into the `PROJECTS__0__S3_CONFIG__...` variables read from
+/// the environment, so that files and variables share one schema.
+pub fn flatten(value: &Value) -> Vec<(String, String)> {
+    let mut vars = vec![];
+    flatten_into(value, String::new(), &mut vars);
+    vars
+}
+
+fn flatten_into(value: &Value, key: String, vars: &mut Vec<(String, String)>) {
+    let child_key = |child: &str| match key.is_empty() {
+        true => child.to_uppercase(),
+        false => format!("{}__{}", key, child.to_uppercase()),
+    };
+    match value {
+        Value::Null => {}
+        Value::String(s) => vars.push((key, s.clone())),
+        Value::Bool(_) | Value::Number(_) => vars.push((key, value.to_string())),
+        Value::Array(items) => {
+            for (index, item) in items.iter().enumerate() {
+                flatten_into(item, child_key(&index.to_string()), vars);
+            }
+        }
+        Value::Object(fields) => {
+            for (field, item) in fields {
+                flatten_into(item, child_key(field), vars);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tempdir::TempDir;
+
+    #[test]
+    fn interpolates_environment_variables() {
+        std::env::set_var("CONFIG_FILE_TEST_BUCKET", "recordings");
+        assert_eq!(
+            interpolate_str("s3://${CONFIG_FILE_TEST_BUCKET}/text").unwrap(),
+            "s3://recordings/text"
+        );
+        assert_eq!(
+            interpolate_str("${CONFIG_FILE_TEST_UNSET:-fallback}").unwrap(),
+            "fallback"
+        );
+        assert_eq!(interpolate_str("${CONFIG_FILE_TEST_UNSET:-}").unwrap(), "");
+        assert_eq!(interpolate_str("no references").unwrap(), "no references");
+    }
+
+    #[test]
+    fn fails_on_unset_variables_without_a_default() {
+        assert_eq!(
+            interpolate_str("${CONFIG_FILE_TEST_MISSING}").unwrap_err(),
+            "environment variable CONFIG_FILE_TEST_MISSING is not set"
+        );
+    }
+
+    #[test]
+    fn flattens_documents_into_variables() {
+        let value = json!({
+            "device_group_name": "text-egress",
+            "admission": { "max_concurrent_egresses": 4 },
+            "projects": [{ "retry_failed_sessions": true, "s3_config": { "region": null } }],
+        });
+        let mut vars = flatten(&value);
+        vars.sort();
+        assert_eq!(
+            vars,
+            vec![
+                (
+                    "ADMISSION__MAX_CONCURRENT_EGRESSES".to_string(),
+                    "4".to_string()
+                ),
+                ("DEVICE_GROUP_NAME".to_string(), "text-egress".to_string()),
+                (
+                    "PROJECTS__0__RETRY_FAILED_SESSIONS".to_string(),
+                    "true".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_toml_and_yaml_files() {
+        let dir = TempDir::new("config-file").unwrap();
+        std::env::set_var("CONFIG_FILE_TEST_PROJECT", "project-1");
+
+        let toml = dir.path().join("config.toml");
+        std::fs::write(
+            &toml,
+            "[[projects]]\nproject_id = \"${CONFIG_FILE_TEST_PROJECT}\"\n",
+        )
+        .unwrap();
+        let yaml = dir.path().join("config.yml");
+        std::fs::write(
+            &yaml,
+            "projects:\n  - project_id: ${CONFIG_FILE_TEST_PROJECT}\n",
+        )
+        .unwrap();
+
+        let expected = json!({ "projects": [{ "project_id": "project-1" }] });
+        assert_eq!(read_config_file(&toml).unwrap(), expected);
+        assert_eq!(read_config_file(&yaml).unwrap(), expected);
+    }
+
+    #[test]
+    fn rejects_unknown_extensions_and_invalid_files() {
+        let dir = TempDir::new("config-file").unwrap();
+        let json = dir.path().join("config.json");
+        std::fs::write(&json, "{}").unwrap();
+        assert!(matches!(
+            read_config_file(&json),
+            Err(TextEgressError::ConfigFileError(_))
+        ));
+
+        let toml = dir.path().join("config.toml");
+        std::fs::write(&toml, "projects = [").unwrap();
+        assert!(matches!(
+            read_config_file(&toml),
+            Err(TextEgressError::ConfigFileError(_))
+        ));
+    }
+}
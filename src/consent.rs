@@ -0,0 +1,153 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::config::ConsentConfig;
+
+/// A change in a participant's consent, written to the egress metadata.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConsentTransition {
+    pub participant: String,
+    pub granted: bool,
+    /// Unix timestamp in nanoseconds
+    pub at: i64,
+}
+
+/// Tracks the consent state of every participant seen in the room.
+#[derive(Debug)]
+pub struct ConsentTracker {
+    config: ConsentConfig,
+    states: HashMap<String, bool>,
+    transitions: Vec<ConsentTransition>,
+}
+
+impl ConsentTracker {
+    pub fn new(config: &ConsentConfig) -> Self {
+        ConsentTracker {
+            config: config.clone(),
+            states: HashMap::new(),
+            transitions: vec![],
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    fn has_consent(&self, attributes: &HashMap<String, String>, metadata: &str) -> bool {
+        if let Some(value) = attributes.get(&self.config.key) {
+            return value.eq_ignore_ascii_case(&self.config.value);
+        }
+
+        match serde_json::from_str::<serde_json::Value>(metadata) {
+            Ok(serde_json::Value::Object(metadata)) => match metadata.get(&self.config.key) {
+                Some(serde_json::Value::String(value)) => {
+                    value.eq_ignore_ascii_case(&self.config.value)
+                }
+                Some(value) => value.to_string().eq_ignore_ascii_case(&self.config.value),
+                None => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// Re-evaluates consent from the participant's current attributes and metadata,
+    /// logging a transition when it changed. Returns whether the participant may be recorded.
+    pub fn update(
+        &mut self,
+        participant: &str,
+        attributes: &HashMap<String, String>,
+        metadata: &str,
+    ) -> bool {
+        if !self.config.enabled {
+            return true;
+        }
+
+        let granted = self.has_consent(attributes, metadata);
+        let previous = self.states.insert(participant.to_string(), granted);
+        if previous != Some(granted) {
+            log::info!(
+                "Consent {} for participant: {:?}",
+                if granted { "granted" } else { "not granted" },
+                participant
+            );
+            self.transitions.push(ConsentTransition {
+                participant: participant.to_string(),
+                granted,
+                at: chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default(),
+            });
+        }
+        granted
+    }
+
+    pub fn transitions(&self) -> &[ConsentTransition] {
+        &self.transitions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracker() -> ConsentTracker {
+        ConsentTracker::new(&ConsentConfig {
+            enabled: true,
+            ..Default::default()
+        })
+    }
+
+    fn attributes(value: &str) -> HashMap<String, String> {
+        HashMap::from([("recording_consent".to_string(), value.to_string())])
+    }
+
+    #[test]
+    fn records_everyone_when_disabled() {
+        let mut tracker = ConsentTracker::new(&ConsentConfig::default());
+        assert!(!tracker.is_enabled());
+        assert!(tracker.update("alice", &HashMap::new(), ""));
+        assert!(tracker.transitions().is_empty());
+    }
+
+    #[test]
+    fn reads_consent_from_attributes() {
+        let mut tracker = tracker();
+        assert!(tracker.update("alice", &attributes("TRUE"), ""));
+        assert!(!tracker.update("bob", &attributes("false"), ""));
+        assert!(!tracker.update("carol", &HashMap::new(), ""));
+    }
+
+    #[test]
+    fn reads_consent_from_json_metadata() {
+        let mut tracker = tracker();
+        assert!(tracker.update("alice", &HashMap::new(), r#"{"recording_consent": true}"#));
+        assert!(tracker.update("bob", &HashMap::new(), r#"{"recording_consent": "true"}"#));
+        assert!(!tracker.update("carol", &HashMap::new(), r#"{"recording_consent": false}"#));
+        assert!(!tracker.update("dave", &HashMap::new(), "recording_consent=true"));
+    }
+
+    #[test]
+    fn prefers_attributes_over_metadata() {
+        let mut tracker = tracker();
+        assert!(!tracker.update(
+            "alice",
+            &attributes("false"),
+            r#"{"recording_consent": true}"#
+        ));
+    }
+
+    #[test]
+    fn logs_transitions_only_on_change() {
+        let mut tracker = tracker();
+        tracker.update("alice", &HashMap::new(), "");
+        tracker.update("alice", &HashMap::new(), "");
+        tracker.update("alice", &attributes("true"), "");
+        tracker.update("alice", &attributes("true"), "");
+        tracker.update("alice", &attributes("false"), "");
+
+        let granted: Vec<bool> = tracker.transitions().iter().map(|t| t.granted).collect();
+        assert_eq!(granted, vec![false, true, false]);
+        assert!(tracker
+            .transitions()
+            .iter()
+            .all(|t| t.participant == "alice"));
+    }
+}
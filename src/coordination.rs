@@ -0,0 +1,301 @@
+use amqprs::channel::{BasicAckArguments, BasicNackArguments, Channel};
+use amqprs::connection::Connection;
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// How replicas of the actor share session notifications.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CoordinationMode {
+    /// Every instance receives and records every session
+    #[default]
+    None,
+    /// Instances consume from one shared queue, each session is claimed by exactly one of them
+    SharedQueue,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CoordinationConfig {
+    #[serde(default)]
+    pub mode: CoordinationMode,
+    /// Name of the shared queue, `syncflow-text-egress-<project id>` by default
+    #[serde(default)]
+    pub queue_name: Option<String>,
+    /// Sessions an instance may hold claims for at once, unlimited when 0
+    #[serde(default)]
+    pub max_claimed_sessions: u16,
+}
+
+impl CoordinationConfig {
+    pub fn queue_name(&self, project_id: &str) -> String {
+        self.queue_name
+            .clone()
+            .unwrap_or_else(|| format!("syncflow-text-egress-{}", project_id))
+    }
+}
+
+/// A claimed session notification. The claim is held until the egress reaches a final state,
+/// so that the session is handed to another instance if this one dies.
+pub trait SessionClaim: Debug + Send + Sync {
+    /// Gives up the claim, `requeue` hands the session to another instance.
+    fn release(&self, requeue: bool);
+}
+
+/// Keeps the connection session notifications are consumed on open while the listener
+/// consumes from it or any claim taken on it is held. The broker requeues unacknowledged
+/// deliveries when their connection closes, so a connection refresh must not close a
+/// connection that still holds claims.
+pub struct ConnectionLease {
+    close: Mutex<Option<Box<dyn FnOnce() + Send>>>,
+}
+
+impl ConnectionLease {
+    pub fn new(connection: Connection) -> Arc<Self> {
+        Self::with_close(move || {
+            tokio::spawn(async move {
+                if let Err(e) = connection.close().await {
+                    log::warn!("Failed to close RabbitMQ connection: {:?}", e);
+                }
+            });
+        })
+    }
+
+    /// A lease that runs `close` once the last reference to it is dropped.
+    pub fn with_close(close: impl FnOnce() + Send + 'static) -> Arc<Self> {
+        Arc::new(ConnectionLease {
+            close: Mutex::new(Some(Box::new(close))),
+        })
+    }
+}
+
+impl Debug for ConnectionLease {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConnectionLease").finish()
+    }
+}
+
+impl Drop for ConnectionLease {
+    fn drop(&mut self) {
+        if let Some(close) = self.close.get_mut().unwrap().take() {
+            close();
+        }
+    }
+}
+
+/// A session claimed by an unacknowledged delivery on a shared AMQP queue.
+pub struct AmqpSessionClaim {
+    channel: Channel,
+    delivery_tag: u64,
+    released: AtomicBool,
+    connection: Arc<ConnectionLease>,
+}
+
+impl AmqpSessionClaim {
+    pub fn new(channel: Channel, delivery_tag: u64, connection: Arc<ConnectionLease>) -> Self {
+        AmqpSessionClaim {
+            channel,
+            delivery_tag,
+            released: AtomicBool::new(false),
+            connection,
+        }
+    }
+}
+
+impl Debug for AmqpSessionClaim {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AmqpSessionClaim")
+            .field("delivery_tag", &self.delivery_tag)
+            .finish()
+    }
+}
+
+impl SessionClaim for AmqpSessionClaim {
+    fn release(&self, requeue: bool) {
+        if self.released.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let channel = self.channel.clone();
+        let delivery_tag = self.delivery_tag;
+        // The connection stays open until the delivery is settled
+        let connection = self.connection.clone();
+        tokio::spawn(async move {
+            let result = if requeue {
+                channel
+                    .basic_nack(BasicNackArguments::new(delivery_tag, false, true))
+                    .await
+            } else {
+                channel
+                    .basic_ack(BasicAckArguments::new(delivery_tag, false))
+                    .await
+            };
+            // The broker has already redelivered the session if the connection was lost
+            if let Err(e) = result {
+                log::warn!("Failed to release session claim {}: {:?}", delivery_tag, e);
+            }
+            drop(connection);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::{HashMap, VecDeque};
+
+    /// Models the broker side of a shared queue: deliveries that are not settled when
+    /// their connection closes are requeued.
+    #[derive(Default)]
+    struct Broker {
+        queue: Mutex<VecDeque<String>>,
+        unacked: Mutex<HashMap<usize, Vec<String>>>,
+    }
+
+    impl Broker {
+        fn publish(&self, session_id: &str) {
+            self.queue.lock().unwrap().push_back(session_id.to_string());
+        }
+
+        fn queued(&self) -> Vec<String> {
+            self.queue.lock().unwrap().iter().cloned().collect()
+        }
+
+        fn connect(self: &Arc<Self>, id: usize, closed: Arc<AtomicBool>) -> Arc<ConnectionLease> {
+            let broker = self.clone();
+            ConnectionLease::with_close(move || {
+                closed.store(true, Ordering::Release);
+                let unacked = broker.unacked.lock().unwrap().remove(&id);
+                broker
+                    .queue
+                    .lock()
+                    .unwrap()
+                    .extend(unacked.unwrap_or_default());
+            })
+        }
+
+        /// Delivers the next session on the connection `id`, claimed until settled.
+        fn consume(
+            self: &Arc<Self>,
+            id: usize,
+            connection: &Arc<ConnectionLease>,
+        ) -> Option<Arc<dyn SessionClaim>> {
+            let session_id = self.queue.lock().unwrap().pop_front()?;
+            self.unacked
+                .lock()
+                .unwrap()
+                .entry(id)
+                .or_default()
+                .push(session_id.clone());
+            Some(Arc::new(TestClaim {
+                session_id,
+                connection_id: id,
+                broker: self.clone(),
+                connection: Mutex::new(Some(connection.clone())),
+            }))
+        }
+    }
+
+    impl Debug for Broker {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("Broker").finish()
+        }
+    }
+
+    #[derive(Debug)]
+    struct TestClaim {
+        session_id: String,
+        connection_id: usize,
+        broker: Arc<Broker>,
+        connection: Mutex<Option<Arc<ConnectionLease>>>,
+    }
+
+    impl SessionClaim for TestClaim {
+        fn release(&self, requeue: bool) {
+            let Some(connection) = self.connection.lock().unwrap().take() else {
+                return;
+            };
+            if let Some(unacked) = self
+                .broker
+                .unacked
+                .lock()
+                .unwrap()
+                .get_mut(&self.connection_id)
+            {
+                unacked.retain(|session_id| *session_id != self.session_id);
+            }
+            if requeue {
+                self.broker.publish(&self.session_id);
+            }
+            drop(connection);
+        }
+    }
+
+    #[test]
+    fn closes_the_connection_with_its_last_reference() {
+        let closed = Arc::new(AtomicBool::new(false));
+        let connection = ConnectionLease::with_close({
+            let closed = closed.clone();
+            move || closed.store(true, Ordering::Release)
+        });
+        let claim = connection.clone();
+
+        drop(connection);
+        assert!(!closed.load(Ordering::Acquire));
+        drop(claim);
+        assert!(closed.load(Ordering::Acquire));
+    }
+
+    #[test]
+    fn keeps_claimed_sessions_across_a_connection_refresh() {
+        let broker = Arc::new(Broker::default());
+        broker.publish("session-1");
+        let first_closed = Arc::new(AtomicBool::new(false));
+        let first = broker.connect(1, first_closed.clone());
+        let claim = broker.consume(1, &first).unwrap();
+
+        // The refresh retires the first connection in favor of a second one
+        let second = broker.connect(2, Arc::new(AtomicBool::new(false)));
+        drop(first);
+        assert!(!first_closed.load(Ordering::Acquire));
+        assert!(broker.queued().is_empty());
+        assert!(broker.consume(2, &second).is_none());
+
+        claim.release(false);
+        assert!(first_closed.load(Ordering::Acquire));
+        assert!(broker.queued().is_empty());
+    }
+
+    #[test]
+    fn hands_sessions_to_another_instance_when_one_dies() {
+        let broker = Arc::new(Broker::default());
+        broker.publish("session-1");
+        let first_closed = Arc::new(AtomicBool::new(false));
+        let first = broker.connect(1, first_closed.clone());
+        let claim = broker.consume(1, &first).unwrap();
+        let other = broker.connect(2, Arc::new(AtomicBool::new(false)));
+        assert!(broker.consume(2, &other).is_none());
+
+        // The instance dies without settling its claim
+        drop(claim);
+        drop(first);
+        assert!(first_closed.load(Ordering::Acquire));
+        assert_eq!(broker.queued(), vec!["session-1".to_string()]);
+
+        let claim = broker.consume(2, &other).unwrap();
+        claim.release(false);
+        assert!(broker.queued().is_empty());
+    }
+
+    #[test]
+    fn requeues_released_claims_for_retries() {
+        let broker = Arc::new(Broker::default());
+        broker.publish("session-1");
+        let connection = broker.connect(1, Arc::new(AtomicBool::new(false)));
+        let claim = broker.consume(1, &connection).unwrap();
+
+        claim.release(true);
+        claim.release(true);
+        assert_eq!(broker.queued(), vec!["session-1".to_string()]);
+    }
+}
@@ -0,0 +1,278 @@
+use openssl::encrypt::{Decrypter, Encrypter};
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, PKeyRef, Private, Public};
+use openssl::rsa::Padding;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+
+use crate::config::{EncryptionConfig, ServerSideEncryption};
+use crate::error_messages::TextEgressError;
+
+/// Envelope encrypted files start with this magic, followed by the format version.
+pub const ENCRYPTED_FILE_MAGIC: &[u8; 4] = b"SFTE";
+pub const ENCRYPTED_FILE_EXTENSION: &str = "enc";
+pub const ENVELOPE_ALGORITHM: &str = "AES-256-GCM+RSA-OAEP-SHA256";
+
+const FORMAT_VERSION: u8 = 1;
+const DATA_KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// Wraps per-egress data keys with the configured RSA public key.
+#[derive(Clone)]
+pub struct EnvelopeEncryptor {
+    public_key: PKey<Public>,
+}
+
+impl EnvelopeEncryptor {
+    #[allow(clippy::result_large_err)]
+    pub fn from_pem_file(path: &str) -> Result<Self, TextEgressError> {
+        let pem = std::fs::read(path)?;
+        let public_key = PKey::public_key_from_pem(&pem)?;
+        Ok(EnvelopeEncryptor { public_key })
+    }
+
+    /// Generates a fresh data key for an egress and wraps it with the public key.
+    #[allow(clippy::result_large_err)]
+    pub fn data_key(&self) -> Result<DataKey, TextEgressError> {
+        let mut key = [0u8; DATA_KEY_LEN];
+        openssl::rand::rand_bytes(&mut key)?;
+
+        let mut encrypter = Encrypter::new(&self.public_key)?;
+        encrypter.set_rsa_padding(Padding::PKCS1_OAEP)?;
+        encrypter.set_rsa_oaep_md(MessageDigest::sha256())?;
+        encrypter.set_rsa_mgf1_md(MessageDigest::sha256())?;
+        let mut wrapped_key = vec![0u8; encrypter.encrypt_len(&key)?];
+        let length = encrypter.encrypt(&key, &mut wrapped_key)?;
+        wrapped_key.truncate(length);
+
+        Ok(DataKey { key, wrapped_key })
+    }
+}
+
+/// A plaintext data key along with its wrapped form, which is stored in every file header.
+pub struct DataKey {
+    key: [u8; DATA_KEY_LEN],
+    wrapped_key: Vec<u8>,
+}
+
+impl DataKey {
+    /// Encrypts `plaintext` into the self-describing envelope format:
+    /// magic | version | wrapped key length (u16 BE) | wrapped key | nonce | tag | ciphertext
+    #[allow(clippy::result_large_err)]
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, TextEgressError> {
+        let mut header = Vec::with_capacity(7 + self.wrapped_key.len());
+        header.extend_from_slice(ENCRYPTED_FILE_MAGIC);
+        header.push(FORMAT_VERSION);
+        header.extend_from_slice(&(self.wrapped_key.len() as u16).to_be_bytes());
+        header.extend_from_slice(&self.wrapped_key);
+
+        let mut nonce = [0u8; NONCE_LEN];
+        openssl::rand::rand_bytes(&mut nonce)?;
+        let mut tag = [0u8; TAG_LEN];
+        let ciphertext = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.key,
+            Some(&nonce),
+            &header,
+            plaintext,
+            &mut tag,
+        )?;
+
+        let mut output = header;
+        output.extend_from_slice(&nonce);
+        output.extend_from_slice(&tag);
+        output.extend_from_slice(&ciphertext);
+        Ok(output)
+    }
+}
+
+/// Decrypts a file produced by [`DataKey::encrypt`] with the matching RSA private key.
+#[allow(clippy::result_large_err)]
+pub fn decrypt(private_key: &PKeyRef<Private>, content: &[u8]) -> Result<Vec<u8>, TextEgressError> {
+    let invalid = |reason: &str| TextEgressError::InvalidEncryptedFile(reason.to_string());
+
+    if content.len() < 7 || &content[..4] != ENCRYPTED_FILE_MAGIC {
+        return Err(invalid("missing envelope header"));
+    }
+    if content[4] != FORMAT_VERSION {
+        return Err(invalid("unsupported format version"));
+    }
+    let wrapped_key_len = u16::from_be_bytes([content[5], content[6]]) as usize;
+    let header_len = 7 + wrapped_key_len;
+    if content.len() < header_len + NONCE_LEN + TAG_LEN {
+        return Err(invalid("file is truncated"));
+    }
+    let (header, rest) = content.split_at(header_len);
+    let (nonce, rest) = rest.split_at(NONCE_LEN);
+    let (tag, ciphertext) = rest.split_at(TAG_LEN);
+
+    let mut decrypter = Decrypter::new(private_key)?;
+    decrypter.set_rsa_padding(Padding::PKCS1_OAEP)?;
+    decrypter.set_rsa_oaep_md(MessageDigest::sha256())?;
+    decrypter.set_rsa_mgf1_md(MessageDigest::sha256())?;
+    let wrapped_key = &header[7..];
+    let mut key = vec![0u8; decrypter.decrypt_len(wrapped_key)?];
+    let length = decrypter.decrypt(wrapped_key, &mut key)?;
+    key.truncate(length);
+    if key.len() != DATA_KEY_LEN {
+        return Err(invalid("unexpected data key length"));
+    }
+
+    Ok(decrypt_aead(
+        Cipher::aes_256_gcm(),
+        &key,
+        Some(nonce),
+        header,
+        ciphertext,
+        tag,
+    )?)
+}
+
+/// Server side encryption headers applied to every `PutObjectRequest`.
+#[derive(Clone, Default)]
+pub struct ServerSideEncryptionHeaders {
+    server_side_encryption: Option<String>,
+    sse_customer_algorithm: Option<String>,
+    sse_customer_key: Option<String>,
+    sse_customer_key_md5: Option<String>,
+}
+
+impl ServerSideEncryptionHeaders {
+    #[allow(clippy::result_large_err)]
+    pub fn from_config(config: &EncryptionConfig) -> Result<Self, TextEgressError> {
+        match config.server_side {
+            ServerSideEncryption::None => Ok(Self::default()),
+            ServerSideEncryption::S3 => Ok(ServerSideEncryptionHeaders {
+                server_side_encryption: Some("AES256".to_string()),
+                ..Default::default()
+            }),
+            ServerSideEncryption::Customer => {
+                let encoded_key = config
+                    .customer_key
+                    .as_ref()
+                    .map(|key| key.expose().to_string())
+                    .ok_or_else(|| {
+                        TextEgressError::EncryptionConfigError(
+                            "customer_key is required for customer provided server side encryption"
+                                .to_string(),
+                        )
+                    })?;
+                let key = openssl::base64::decode_block(&encoded_key)?;
+                if key.len() != DATA_KEY_LEN {
+                    return Err(TextEgressError::EncryptionConfigError(
+                        "customer_key must be a base64 encoded 256-bit key".to_string(),
+                    ));
+                }
+                let key_md5 = openssl::hash::hash(MessageDigest::md5(), &key)?;
+                Ok(ServerSideEncryptionHeaders {
+                    sse_customer_algorithm: Some("AES256".to_string()),
+                    sse_customer_key: Some(encoded_key),
+                    sse_customer_key_md5: Some(openssl::base64::encode_block(&key_md5)),
+                    ..Default::default()
+                })
+            }
+        }
+    }
+
+    pub fn apply(&self, request: &mut rusoto_s3::PutObjectRequest) {
+        request.server_side_encryption = self.server_side_encryption.clone();
+        request.sse_customer_algorithm = self.sse_customer_algorithm.clone();
+        request.sse_customer_key = self.sse_customer_key.clone();
+        request.sse_customer_key_md5 = self.sse_customer_key_md5.clone();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secrets::Secret;
+    use openssl::rsa::Rsa;
+
+    fn key_pair() -> (EnvelopeEncryptor, PKey<Private>) {
+        let rsa = Rsa::generate(2048).unwrap();
+        let private_key = PKey::from_rsa(rsa).unwrap();
+        let public_pem = private_key.public_key_to_pem().unwrap();
+        let encryptor = EnvelopeEncryptor {
+            public_key: PKey::public_key_from_pem(&public_pem).unwrap(),
+        };
+        (encryptor, private_key)
+    }
+
+    #[test]
+    fn round_trips_through_the_envelope() {
+        let (encryptor, private_key) = key_pair();
+        let data_key = encryptor.data_key().unwrap();
+        let plaintext = b"2024-01-01T00:00:00UTC|1|1||hello\n";
+
+        let encrypted = data_key.encrypt(plaintext).unwrap();
+        assert_eq!(&encrypted[..4], ENCRYPTED_FILE_MAGIC);
+        assert_eq!(encrypted[4], FORMAT_VERSION);
+        assert_ne!(&encrypted[encrypted.len() - plaintext.len()..], plaintext);
+
+        assert_eq!(decrypt(&private_key, &encrypted).unwrap(), plaintext);
+    }
+
+    #[test]
+    fn uses_a_fresh_nonce_per_file() {
+        let (encryptor, private_key) = key_pair();
+        let data_key = encryptor.data_key().unwrap();
+
+        let first = data_key.encrypt(b"hello").unwrap();
+        let second = data_key.encrypt(b"hello").unwrap();
+        assert_ne!(first, second);
+        assert_eq!(decrypt(&private_key, &second).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn rejects_tampered_files() {
+        let (encryptor, private_key) = key_pair();
+        let encrypted = encryptor.data_key().unwrap().encrypt(b"hello").unwrap();
+
+        let mut ciphertext = encrypted.clone();
+        *ciphertext.last_mut().unwrap() ^= 1;
+        assert!(decrypt(&private_key, &ciphertext).is_err());
+
+        // The header is authenticated as associated data
+        let mut header = encrypted.clone();
+        header[5] ^= 1;
+        assert!(decrypt(&private_key, &header).is_err());
+
+        assert!(matches!(
+            decrypt(&private_key, &encrypted[..20]),
+            Err(TextEgressError::InvalidEncryptedFile(_))
+        ));
+        assert!(matches!(
+            decrypt(&private_key, b"plain text, not an envelope"),
+            Err(TextEgressError::InvalidEncryptedFile(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_the_wrong_key() {
+        let (encryptor, _) = key_pair();
+        let (_, other_private_key) = key_pair();
+        let encrypted = encryptor.data_key().unwrap().encrypt(b"hello").unwrap();
+
+        assert!(decrypt(&other_private_key, &encrypted).is_err());
+    }
+
+    #[test]
+    fn validates_customer_keys() {
+        let config = |key: &[u8]| EncryptionConfig {
+            server_side: ServerSideEncryption::Customer,
+            customer_key: Some(Secret::new(openssl::base64::encode_block(key))),
+            ..Default::default()
+        };
+
+        let headers = ServerSideEncryptionHeaders::from_config(&config(&[7u8; 32])).unwrap();
+        assert_eq!(headers.sse_customer_algorithm.as_deref(), Some("AES256"));
+        assert!(headers.sse_customer_key_md5.is_some());
+
+        assert!(ServerSideEncryptionHeaders::from_config(&config(&[7u8; 16])).is_err());
+        assert!(ServerSideEncryptionHeaders::from_config(&EncryptionConfig {
+            server_side: ServerSideEncryption::Customer,
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
@@ -47,4 +47,52 @@ pub enum TextEgressError {
 
     #[error("S3 Uploader Error: {0}")]
     S3UploaderError(String),
+
+    #[error("Tracing error: {0}")]
+    TracingError(#[from] opentelemetry::trace::TraceError),
+
+    #[error("Failed to initialize tracing: {0}")]
+    TracingInitError(String),
+
+    #[error("Compression error: {0}")]
+    CompressionError(String),
+
+    #[error("Encryption error: {0}")]
+    EncryptionError(#[from] openssl::error::ErrorStack),
+
+    #[error("Invalid encryption configuration: {0}")]
+    EncryptionConfigError(String),
+
+    #[error("Invalid encrypted file: {0}")]
+    InvalidEncryptedFile(String),
+
+    #[error("Invalid pattern: {0}")]
+    RegexError(#[from] regex::Error),
+
+    #[error("Invalid pseudonymization configuration: {0}")]
+    PseudonymizationConfigError(String),
+
+    #[error("LiveKit service error: {0}")]
+    LiveKitServiceError(#[from] livekit_api::services::ServiceError),
+
+    #[error("Session refused: {0}")]
+    AdmissionRefused(String),
+
+    #[error("Invalid configuration: {0}")]
+    ConfigFileError(String),
+
+    #[error("Actor mailbox error: {0}")]
+    MailboxError(#[from] actix::MailboxError),
+
+    #[error("Failed to fetch secret: {0}")]
+    SecretError(String),
+
+    #[error("Room listener gave up: {0}")]
+    RoomListenerError(String),
+
+    #[error("Invalid join token: {0}")]
+    JoinTokenError(String),
+
+    #[error("Preflight checks failed: {0}")]
+    PreflightFailed(String),
 }
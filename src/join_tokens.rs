@@ -0,0 +1,256 @@
+use chrono::{DateTime, Utc};
+use jsonwebtoken::{decode, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use syncflow_client::ProjectClient;
+use syncflow_shared::livekit_models::{TokenRequest, VideoGrantsWrapper};
+use tokio::sync::Mutex;
+
+use crate::error_messages::TextEgressError;
+use crate::secrets::Secret;
+
+/// The LiveKit token requested for room listeners. Joining and subscribing are always granted.
+///
+/// There is no TTL setting: SyncFlow's `TokenRequest` has no lifetime field and the server
+/// decides how long a token lives. Its expiry is read from the token's `exp` claim instead.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JoinTokenConfig {
+    #[serde(default = "default_identity")]
+    pub identity: String,
+    #[serde(default = "default_name")]
+    pub name: String,
+    /// A token expiring sooner than this is replaced before it is handed out. Keep it
+    /// below the lifetime SyncFlow gives tokens, or every request gets a new one.
+    #[serde(default = "default_refresh_margin_secs")]
+    pub refresh_margin_secs: u64,
+    /// Hide the egress from the other participants
+    #[serde(default)]
+    pub hidden: bool,
+    #[serde(default)]
+    pub room_record: bool,
+    #[serde(default)]
+    pub can_publish_data: bool,
+}
+
+impl Default for JoinTokenConfig {
+    fn default() -> Self {
+        JoinTokenConfig {
+            identity: default_identity(),
+            name: default_name(),
+            refresh_margin_secs: default_refresh_margin_secs(),
+            hidden: false,
+            room_record: false,
+            can_publish_data: false,
+        }
+    }
+}
+
+fn default_identity() -> String {
+    "text-egress-actor".to_string()
+}
+
+fn default_name() -> String {
+    "Text Egress Actor".to_string()
+}
+
+fn default_refresh_margin_secs() -> u64 {
+    300
+}
+
+impl JoinTokenConfig {
+    pub fn request(&self, room_name: &str) -> TokenRequest {
+        TokenRequest {
+            identity: self.identity.clone(),
+            name: Some(self.name.clone()),
+            video_grants: VideoGrantsWrapper {
+                room: room_name.to_string(),
+                room_join: true,
+                room_create: false,
+                can_subscribe: true,
+                hidden: self.hidden,
+                room_record: self.room_record,
+                can_publish_data: self.can_publish_data,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct JoinToken {
+    pub token: Secret,
+    pub server_url: Option<String>,
+    /// Taken from the token's `exp` claim, `None` if it could not be read
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExpiryClaims {
+    exp: i64,
+}
+
+/// Reads the expiry of a token without verifying it, the token is only passed on to LiveKit.
+fn expiry(token: &str) -> Result<DateTime<Utc>, jsonwebtoken::errors::Error> {
+    let mut validation = Validation::default();
+    validation.insecure_disable_signature_validation();
+    validation.validate_exp = false;
+    validation.validate_aud = false;
+    validation.required_spec_claims.clear();
+    let claims = decode::<ExpiryClaims>(token, &DecodingKey::from_secret(&[]), &validation)?;
+    DateTime::from_timestamp(claims.claims.exp, 0)
+        .ok_or_else(|| jsonwebtoken::errors::ErrorKind::InvalidToken.into())
+}
+
+/// The tokens of running egresses by session, renewed when they are about to expire.
+#[derive(Debug)]
+pub struct JoinTokens {
+    config: JoinTokenConfig,
+    tokens: Mutex<HashMap<String, JoinToken>>,
+}
+
+impl JoinTokens {
+    pub fn new(config: &JoinTokenConfig) -> Self {
+        JoinTokens {
+            config: config.clone(),
+            tokens: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// A token for the session's room, the previous one unless it is about to expire
+    /// or `renew` is set, such as after it was refused.
+    pub async fn get(
+        &self,
+        client: &Mutex<ProjectClient>,
+        session_id: &str,
+        room_name: &str,
+        renew: bool,
+    ) -> Result<JoinToken, TextEgressError> {
+        if let Some(token) = self.cached(session_id, renew).await {
+            return Ok(token);
+        }
+
+        // Requested without holding the cache, so other sessions are not held up
+        let response = client
+            .lock()
+            .await
+            .generate_session_token(session_id, &self.config.request(room_name))
+            .await?;
+        let expires_at = match expiry(&response.token) {
+            Ok(expires_at) => Some(expires_at),
+            Err(e) => {
+                log::warn!(
+                    "Failed to read the expiry of the join token of session {}, it is renewed on every use: {:?}",
+                    session_id,
+                    e
+                );
+                None
+            }
+        };
+        let token = JoinToken {
+            token: Secret::new(response.token),
+            server_url: response.livekit_server_url,
+            expires_at,
+        };
+        self.tokens
+            .lock()
+            .await
+            .insert(session_id.to_string(), token.clone());
+        Ok(token)
+    }
+
+    /// The cached token of the session, unless it expires within the refresh margin.
+    async fn cached(&self, session_id: &str, renew: bool) -> Option<JoinToken> {
+        let margin = chrono::Duration::seconds(self.config.refresh_margin_secs as i64);
+        let tokens = self.tokens.lock().await;
+        let token = tokens.get(session_id)?;
+        let fresh = token
+            .expires_at
+            .is_some_and(|expires_at| expires_at - margin > Utc::now());
+        if !renew && fresh {
+            return Some(token.clone());
+        }
+        log::info!("Renewing the join token of session {}", session_id);
+        None
+    }
+
+    pub async fn forget(&self, session_id: &str) {
+        self.tokens.lock().await.remove(session_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+
+    fn token(claims: serde_json::Value) -> String {
+        encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(b"livekit-secret"),
+        )
+        .unwrap()
+    }
+
+    async fn cache(expires_at: Option<DateTime<Utc>>) -> JoinTokens {
+        let tokens = JoinTokens::new(&JoinTokenConfig::default());
+        tokens.tokens.lock().await.insert(
+            "session".to_string(),
+            JoinToken {
+                token: Secret::new("token"),
+                server_url: None,
+                expires_at,
+            },
+        );
+        tokens
+    }
+
+    #[test]
+    fn reads_the_expiry_of_a_token() {
+        let exp = Utc::now().timestamp() + 600;
+        let token = token(json!({ "exp": exp, "sub": "text-egress-actor", "video": {} }));
+        assert_eq!(expiry(&token).unwrap().timestamp(), exp);
+        // Expired tokens still tell when they expired
+        let expired = self::token(json!({ "exp": 1000 }));
+        assert_eq!(expiry(&expired).unwrap().timestamp(), 1000);
+    }
+
+    #[test]
+    fn fails_on_tokens_without_an_expiry() {
+        assert!(expiry(&token(json!({ "sub": "text-egress-actor" }))).is_err());
+        assert!(expiry("not a token").is_err());
+    }
+
+    #[test]
+    fn requests_the_configured_grants() {
+        let config = JoinTokenConfig {
+            hidden: true,
+            ..Default::default()
+        };
+        let request = config.request("room");
+        assert_eq!(request.identity, "text-egress-actor");
+        assert_eq!(request.video_grants.room, "room");
+        assert!(request.video_grants.room_join);
+        assert!(request.video_grants.can_subscribe);
+        assert!(request.video_grants.hidden);
+        assert!(!request.video_grants.can_publish_data);
+    }
+
+    #[tokio::test]
+    async fn reuses_tokens_until_the_refresh_margin() {
+        let fresh = cache(Some(Utc::now() + chrono::Duration::seconds(3600))).await;
+        assert!(fresh.cached("session", false).await.is_some());
+        assert!(fresh.cached("session", true).await.is_none());
+        assert!(fresh.cached("other", false).await.is_none());
+
+        let expiring = cache(Some(Utc::now() + chrono::Duration::seconds(60))).await;
+        assert!(expiring.cached("session", false).await.is_none());
+
+        let unknown_expiry = cache(None).await;
+        assert!(unknown_expiry.cached("session", false).await.is_none());
+
+        fresh.forget("session").await;
+        assert!(fresh.cached("session", false).await.is_none());
+    }
+}
@@ -1,7 +1,30 @@
+pub mod admin;
+pub mod admission;
+pub mod buffered_writer;
+pub mod cli;
+pub mod compression;
 pub mod config;
+pub mod config_file;
+pub mod consent;
+pub mod coordination;
+pub mod encryption;
 pub mod error_messages;
+pub mod join_tokens;
+pub mod manifest;
+pub mod participants;
+pub mod preflight;
+pub mod recording;
+pub mod redaction;
+pub mod reload;
 pub(crate) mod room_listener_actor;
+pub mod room_source;
+pub mod rotation;
 pub(crate) mod s3_uploader_actor;
+pub mod secrets;
 pub mod session_listener_actor;
+pub mod status_reporter;
+pub mod supervision;
+pub mod telemetry;
+pub mod timestamps;
 
 pub mod utils;
@@ -1,59 +1,150 @@
-use actix::Actor;
+use clap::Parser;
 use rustls::crypto::aws_lc_rs::default_provider;
+use std::collections::HashMap;
+use std::env;
 use std::error::Error;
-use std::{env, vec};
+use std::time::Duration;
+use syncflow_text_egress_actor::cli::{self, Cli, Command};
 use syncflow_text_egress_actor::config::TextEgressConfig;
-use syncflow_text_egress_actor::session_listener_actor::{ProjectMessages, SessionListenerActor};
+use syncflow_text_egress_actor::error_messages::TextEgressError;
+use syncflow_text_egress_actor::reload::{ConfigFileWatcher, ProjectRegistry};
+use syncflow_text_egress_actor::{admin, preflight, telemetry};
 use tokio::signal;
 
+/// How often the config file is checked for changes
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often stopped and degraded projects are restarted
+const SUPERVISE_INTERVAL: Duration = Duration::from_secs(5);
+
 #[actix_rt::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     default_provider().install_default().unwrap();
-    let config = TextEgressConfig::load()?;
+    let cli = Cli::parse();
+    let sources = &cli.sources;
+
+    // Commands talking to a running instance or reading local files need no configuration
+    match &cli.command {
+        Some(Command::Inspect {
+            file,
+            json,
+            records,
+        }) => {
+            if !cli::inspect(file, *json, *records)? {
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Command::Status(args)) => return Ok(cli::status(args).await?),
+        Some(Command::List(args)) => return Ok(cli::list(args).await?),
+        Some(Command::Stop { egress_id, admin }) => return Ok(cli::stop(admin, egress_id).await?),
+        _ => {}
+    }
+
+    let mut config = TextEgressConfig::load_from(sources)?;
     env::set_var(
         "RUST_LOG",
         "actix_web=debug,actix_rt=debug,syncflow_text_egress_actor=debug",
     );
     env_logger::init();
-    log::info!("Initializing TextEgressActor");
-    let mut actors = vec![];
-    for project in config.projects.iter() {
-        let session_listener_actor = SessionListenerActor::new(
-            &config.rabbitmq_host,
-            config.rabbitmq_port,
-            true,
-            &project.project_id,
-            &config.syncflow_server_url,
-            &project.key,
-            &project.secret,
-            &project.s3_config,
+    let tracer_provider = telemetry::init_tracing(&config.tracing)?;
+
+    if let Some(Command::Upload {
+        dir,
+        project,
+        egress_id,
+        session_id,
+        prefix,
+    }) = cli.command
+    {
+        config.resolve_secrets().await?;
+        cli::upload(
+            &config,
+            &dir,
+            project.as_deref(),
+            egress_id,
+            session_id,
+            prefix,
         )
-        .start();
+        .await?;
+        telemetry::shutdown_tracing(tracer_provider);
+        return Ok(());
+    }
 
-        let register = session_listener_actor
-            .send(ProjectMessages::Register)
-            .await??;
+    let check_only = matches!(cli.command, Some(Command::Check));
 
-        actors.push(session_listener_actor);
+    let mut preflight_failures = HashMap::new();
+    if check_only || config.preflight {
+        // The write probe leaves objects behind where deletes are not allowed, so it
+        // only runs on startup when asked for
+        let write_s3 = check_only || config.preflight_s3_write;
+        let report = preflight::run(&config, write_s3).await;
+        println!("{}", report);
+        if check_only {
+            if !report.passed() {
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        preflight_failures = report.failures();
+    }
 
-        log::info!("Registered to project: {:#?}", register);
+    log::info!("Initializing TextEgressActor");
+    let admin_config = config.admin.clone();
+    let mut projects = ProjectRegistry::new(config);
+    // Projects that failed their checks are retried like any other degraded project
+    for (project_id, error) in preflight_failures {
+        projects.defer(&project_id, TextEgressError::PreflightFailed(error));
     }
+    projects.start().await;
+    let admin_server = admin::start(&admin_config, projects.listeners(), projects.degraded())?;
 
+    let mut config_watcher = sources.file_path().map(ConfigFileWatcher::new);
+    let mut config_poll = tokio::time::interval(CONFIG_POLL_INTERVAL);
+    let mut supervise_poll = tokio::time::interval(SUPERVISE_INTERVAL);
     let mut terminate_signal = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+    let mut hangup_signal = signal::unix::signal(signal::unix::SignalKind::hangup())?;
 
-    tokio::select! {
-        _ = tokio::signal::ctrl_c() => {
-            log::info!("Ctrl+C received, initiating shutdown...");
-        },
-        _ = terminate_signal.recv() => {
-            log::info!("SIGTERM received, initiating shutdown...");
-        },
+    loop {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {
+                log::info!("Ctrl+C received, initiating shutdown...");
+                break;
+            },
+            _ = terminate_signal.recv() => {
+                log::info!("SIGTERM received, initiating shutdown...");
+                break;
+            },
+            _ = hangup_signal.recv() => {
+                log::info!("SIGHUP received, reloading configuration...");
+            },
+            _ = config_poll.tick() => {
+                if !config_watcher.as_mut().is_some_and(|watcher| watcher.changed()) {
+                    continue;
+                }
+                log::info!("Configuration file changed, reloading...");
+            },
+            _ = supervise_poll.tick() => {
+                projects.supervise().await;
+                continue;
+            },
+        }
+        // Secrets are resolved when a project starts
+        match TextEgressConfig::load_from(sources) {
+            Ok(config) => projects.reload(config).await,
+            Err(e) => log::error!(
+                "Failed to reload configuration, keeping the current one: {}",
+                e
+            ),
+        }
     }
 
-    for actor in actors {
-        let _ = actor.send(ProjectMessages::Deregister).await??;
-        tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+    if let Some(admin_server) = admin_server {
+        admin_server.stop(true).await;
     }
+    projects.shutdown().await?;
+
+    telemetry::shutdown_tracing(tracer_provider);
 
     Ok(())
 }
@@ -0,0 +1,137 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::room_listener_actor::DataEgressResultFiles;
+use crate::session_listener_actor::{TextEgressInfo, TextEgressStatus};
+
+pub const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+/// A single uploaded object, as listed in the egress manifest.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestObject {
+    pub key: String,
+    pub size: u64,
+    pub sha256: String,
+    pub content_type: String,
+    pub content_encoding: Option<String>,
+    /// Envelope encryption applied after compression, if any
+    pub encryption: Option<String>,
+    /// Size of the recording before compression
+    pub uncompressed_size: u64,
+    pub participant: String,
+    pub topic: Option<String>,
+    pub message_count: usize,
+    pub first_message_at: Option<i64>,
+    pub last_message_at: Option<i64>,
+    pub segment: u32,
+}
+
+impl ManifestObject {
+    pub fn new(
+        key: &str,
+        content: &[u8],
+        content_type: &str,
+        content_encoding: Option<&str>,
+        encryption: Option<&str>,
+        uncompressed_size: u64,
+        file: &DataEgressResultFiles,
+    ) -> Self {
+        ManifestObject {
+            key: key.to_string(),
+            size: content.len() as u64,
+            sha256: sha256_hex(content),
+            content_type: content_type.to_string(),
+            content_encoding: content_encoding.map(|e| e.to_string()),
+            encryption: encryption.map(|e| e.to_string()),
+            uncompressed_size,
+            participant: file.participant.clone(),
+            topic: file.topic.clone(),
+            message_count: file.message_count,
+            first_message_at: file.first_message_at,
+            last_message_at: file.last_message_at,
+            segment: file.segment,
+        }
+    }
+}
+
+/// Lists every object uploaded for an egress so that consumers can verify completeness.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EgressManifest {
+    pub egress_id: String,
+    pub session_id: String,
+    pub room_name: String,
+    pub topic: Option<String>,
+    pub status: TextEgressStatus,
+    pub started_at: Option<usize>,
+    pub stopped_at: Option<usize>,
+    pub bucket: Option<String>,
+    pub objects: Vec<ManifestObject>,
+}
+
+impl From<&TextEgressInfo> for EgressManifest {
+    fn from(info: &TextEgressInfo) -> Self {
+        EgressManifest {
+            egress_id: info.egress_id.clone(),
+            session_id: info.session_id.clone(),
+            room_name: info.room_name.clone(),
+            topic: info.topic.clone(),
+            status: info.status.clone(),
+            started_at: info.started_at,
+            stopped_at: info.stopped_at,
+            bucket: None,
+            objects: vec![],
+        }
+    }
+}
+
+pub fn sha256_hex(content: &[u8]) -> String {
+    hex::encode(Sha256::digest(content))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_content_with_sha256() {
+        assert_eq!(
+            sha256_hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn describes_the_uploaded_object() {
+        let file = DataEgressResultFiles {
+            participant: "alice".to_string(),
+            file_path: "/tmp/alice.txt".to_string(),
+            topic: Some("transcript".to_string()),
+            message_count: 3,
+            first_message_at: Some(1),
+            last_message_at: Some(3),
+            segment: 2,
+        };
+        let object = ManifestObject::new(
+            "prefix/alice.txt.gz",
+            b"compressed",
+            "text/plain",
+            Some("gzip"),
+            None,
+            42,
+            &file,
+        );
+
+        assert_eq!(object.size, 10);
+        assert_eq!(object.sha256, sha256_hex(b"compressed"));
+        assert_eq!(object.content_encoding.as_deref(), Some("gzip"));
+        assert_eq!(object.uncompressed_size, 42);
+        assert_eq!(object.participant, "alice");
+        assert_eq!(object.topic.as_deref(), Some("transcript"));
+        assert_eq!(object.message_count, 3);
+        assert_eq!(
+            (object.first_message_at, object.last_message_at),
+            (Some(1), Some(3))
+        );
+        assert_eq!(object.segment, 2);
+    }
+}
@@ -0,0 +1,228 @@
+use livekit::ParticipantKind;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::sign::Signer;
+use regex::Regex;
+
+use crate::config::{ParticipantKindConfig, RecordingConfig};
+use crate::error_messages::TextEgressError;
+use crate::secrets::Secret;
+
+const ALIAS_PREFIX: &str = "participant-";
+const ALIAS_HEX_LEN: usize = 16;
+
+impl From<ParticipantKind> for ParticipantKindConfig {
+    fn from(kind: ParticipantKind) -> Self {
+        match kind {
+            ParticipantKind::Standard => ParticipantKindConfig::Standard,
+            ParticipantKind::Ingress => ParticipantKindConfig::Ingress,
+            ParticipantKind::Egress => ParticipantKindConfig::Egress,
+            ParticipantKind::Sip => ParticipantKindConfig::Sip,
+            ParticipantKind::Agent => ParticipantKindConfig::Agent,
+        }
+    }
+}
+
+/// Decides which participants are recorded and how their identities are written.
+pub struct ParticipantPolicy {
+    include_identities: Vec<Regex>,
+    exclude_identities: Vec<Regex>,
+    include_kinds: Vec<ParticipantKindConfig>,
+    exclude_kinds: Vec<ParticipantKindConfig>,
+    pseudonymization_key: Option<PKey<Private>>,
+}
+
+impl ParticipantPolicy {
+    #[allow(clippy::result_large_err)]
+    pub fn from_config(config: &RecordingConfig) -> Result<Self, TextEgressError> {
+        let compile = |patterns: &[String]| -> Result<Vec<Regex>, TextEgressError> {
+            Ok(patterns
+                .iter()
+                .map(|pattern| Regex::new(pattern))
+                .collect::<Result<_, _>>()?)
+        };
+
+        let pseudonymization_key = if config.pseudonymization.enabled {
+            let key = config
+                .pseudonymization
+                .key
+                .as_ref()
+                .map(Secret::expose)
+                .filter(|key| !key.is_empty())
+                .ok_or_else(|| {
+                    TextEgressError::PseudonymizationConfigError(
+                        "key is required when pseudonymization is enabled".to_string(),
+                    )
+                })?;
+            Some(PKey::hmac(key.as_bytes())?)
+        } else {
+            None
+        };
+
+        Ok(ParticipantPolicy {
+            include_identities: compile(&config.participants.include_identities)?,
+            exclude_identities: compile(&config.participants.exclude_identities)?,
+            include_kinds: config.participants.include_kinds.clone(),
+            exclude_kinds: config.participants.exclude_kinds.clone(),
+            pseudonymization_key,
+        })
+    }
+
+    pub fn is_pseudonymized(&self) -> bool {
+        self.pseudonymization_key.is_some()
+    }
+
+    /// Whether a participant with `identity` and `kind` should be recorded.
+    pub fn should_record(&self, identity: &str, kind: ParticipantKind) -> bool {
+        let kind = ParticipantKindConfig::from(kind);
+
+        if !self.include_kinds.is_empty() && !self.include_kinds.contains(&kind) {
+            return false;
+        }
+        if self.exclude_kinds.contains(&kind) {
+            return false;
+        }
+        if !self.include_identities.is_empty()
+            && !self
+                .include_identities
+                .iter()
+                .any(|re| re.is_match(identity))
+        {
+            return false;
+        }
+        !self
+            .exclude_identities
+            .iter()
+            .any(|re| re.is_match(identity))
+    }
+
+    /// The name written to files, records and metadata for `identity`.
+    /// Aliases are stable for a given key, so the same participant maps to the same alias across egresses.
+    /// Fails rather than falling back to the raw identity or a shared alias.
+    #[allow(clippy::result_large_err)]
+    pub fn alias(&self, identity: &str) -> Result<String, TextEgressError> {
+        let Some(key) = &self.pseudonymization_key else {
+            return Ok(identity.to_string());
+        };
+
+        let mut signer = Signer::new(MessageDigest::sha256(), key)?;
+        signer.update(identity.as_bytes())?;
+        let digest = signer.sign_to_vec()?;
+        Ok(format!(
+            "{}{}",
+            ALIAS_PREFIX,
+            &hex::encode(digest)[..ALIAS_HEX_LEN]
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ParticipantFilterConfig, PseudonymizationConfig};
+
+    fn policy(participants: ParticipantFilterConfig) -> ParticipantPolicy {
+        ParticipantPolicy::from_config(&RecordingConfig {
+            participants,
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn pseudonymized(key: Option<&str>) -> Result<ParticipantPolicy, TextEgressError> {
+        ParticipantPolicy::from_config(&RecordingConfig {
+            pseudonymization: PseudonymizationConfig {
+                enabled: true,
+                key: key.map(Secret::new),
+            },
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn records_everyone_by_default() {
+        let policy = policy(Default::default());
+        assert!(policy.should_record("alice", ParticipantKind::Standard));
+        assert!(policy.should_record("bot", ParticipantKind::Agent));
+        assert!(!policy.is_pseudonymized());
+        assert_eq!(policy.alias("alice").unwrap(), "alice");
+    }
+
+    #[test]
+    fn filters_identities() {
+        let policy = policy(ParticipantFilterConfig {
+            include_identities: vec!["^student-".to_string()],
+            exclude_identities: vec!["-observer$".to_string()],
+            ..Default::default()
+        });
+        assert!(policy.should_record("student-1", ParticipantKind::Standard));
+        assert!(!policy.should_record("teacher", ParticipantKind::Standard));
+        assert!(!policy.should_record("student-1-observer", ParticipantKind::Standard));
+    }
+
+    #[test]
+    fn filters_kinds() {
+        let policy = policy(ParticipantFilterConfig {
+            include_kinds: vec![
+                ParticipantKindConfig::Standard,
+                ParticipantKindConfig::Agent,
+            ],
+            exclude_kinds: vec![ParticipantKindConfig::Agent],
+            ..Default::default()
+        });
+        assert!(policy.should_record("alice", ParticipantKind::Standard));
+        assert!(!policy.should_record("bot", ParticipantKind::Agent));
+        assert!(!policy.should_record("sip", ParticipantKind::Sip));
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        assert!(ParticipantPolicy::from_config(&RecordingConfig {
+            participants: ParticipantFilterConfig {
+                exclude_identities: vec!["(".to_string()],
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn aliases_identities_with_hmac() {
+        let policy = pseudonymized(Some("secret-key")).unwrap();
+        assert!(policy.is_pseudonymized());
+        // HMAC-SHA256("secret-key", "alice"), truncated
+        assert_eq!(
+            policy.alias("alice").unwrap(),
+            "participant-5c7dff8695e1bf7e"
+        );
+        assert_eq!(
+            policy.alias("alice").unwrap(),
+            pseudonymized(Some("secret-key"))
+                .unwrap()
+                .alias("alice")
+                .unwrap()
+        );
+        assert_ne!(policy.alias("alice").unwrap(), policy.alias("bob").unwrap());
+        assert_ne!(
+            policy.alias("alice").unwrap(),
+            pseudonymized(Some("other-key"))
+                .unwrap()
+                .alias("alice")
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn requires_a_pseudonymization_key() {
+        assert!(matches!(
+            pseudonymized(None),
+            Err(TextEgressError::PseudonymizationConfigError(_))
+        ));
+        assert!(matches!(
+            pseudonymized(Some("")),
+            Err(TextEgressError::PseudonymizationConfigError(_))
+        ));
+    }
+}
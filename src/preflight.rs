@@ -0,0 +1,355 @@
+use rusoto_s3::S3;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use syncflow_client::ProjectClient;
+use uuid::Uuid;
+
+use crate::admission::AdmissionController;
+use crate::config::{Projects, TextEgressConfig};
+use crate::encryption::{EnvelopeEncryptor, ServerSideEncryptionHeaders};
+use crate::participants::ParticipantPolicy;
+use crate::redaction::Redactor;
+use crate::s3_uploader_actor::s3_client;
+use crate::secrets::Secret;
+use crate::session_listener_actor::{
+    open_rabbitmq_connection, EGRESS_DEVICE_GROUP, RABBITMQ_VHOST_NAME,
+};
+
+/// Prefix of the test objects written to check bucket permissions
+const CHECK_OBJECT_PREFIX: &str = ".syncflow-text-egress-check";
+
+/// A short description of what was verified, or why the check failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheckOutcome {
+    Ok(String),
+    /// The check passed but left something behind to look at
+    Warning(String),
+    Failed(String),
+}
+
+#[derive(Debug)]
+pub struct CheckResult {
+    pub name: &'static str,
+    pub outcome: CheckOutcome,
+}
+
+impl CheckResult {
+    async fn run<F, E>(name: &'static str, check: F) -> Self
+    where
+        F: Future<Output = Result<String, E>>,
+        E: fmt::Display,
+    {
+        let outcome = match check.await {
+            Ok(detail) => CheckOutcome::Ok(detail),
+            Err(e) => CheckOutcome::Failed(e.to_string()),
+        };
+        CheckResult { name, outcome }
+    }
+}
+
+#[derive(Debug)]
+pub struct ProjectReport {
+    pub project_id: String,
+    pub checks: Vec<CheckResult>,
+}
+
+#[derive(Debug)]
+pub struct PreflightReport {
+    pub checks: Vec<CheckResult>,
+    pub projects: Vec<ProjectReport>,
+}
+
+impl PreflightReport {
+    pub fn passed(&self) -> bool {
+        self.checks
+            .iter()
+            .chain(self.projects.iter().flat_map(|p| p.checks.iter()))
+            .all(|check| !matches!(check.outcome, CheckOutcome::Failed(_)))
+    }
+}
+
+impl PreflightReport {
+    /// The failed checks of every project that should not be started, by project id.
+    /// A failed global check applies to every project.
+    pub fn failures(&self) -> HashMap<String, String> {
+        let failed = |checks: &[CheckResult]| -> Vec<String> {
+            checks
+                .iter()
+                .filter_map(|check| match &check.outcome {
+                    CheckOutcome::Failed(error) => Some(format!("{}: {}", check.name, error)),
+                    _ => None,
+                })
+                .collect()
+        };
+        let global = failed(&self.checks);
+        self.projects
+            .iter()
+            .filter_map(|project| {
+                let failures = [global.clone(), failed(&project.checks)].concat();
+                (!failures.is_empty()).then(|| (project.project_id.clone(), failures.join("; ")))
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for PreflightReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Global")?;
+        write_checks(f, &self.checks)?;
+        for project in &self.projects {
+            writeln!(f, "Project {}", project.project_id)?;
+            write_checks(f, &project.checks)?;
+        }
+        let verdict = if self.passed() { "passed" } else { "failed" };
+        write!(f, "Preflight checks {}", verdict)
+    }
+}
+
+fn write_checks(f: &mut fmt::Formatter<'_>, checks: &[CheckResult]) -> fmt::Result {
+    for check in checks {
+        match &check.outcome {
+            CheckOutcome::Ok(detail) => writeln!(f, "  [ok]   {:<12} {}", check.name, detail)?,
+            CheckOutcome::Warning(detail) => writeln!(f, "  [warn] {:<12} {}", check.name, detail)?,
+            CheckOutcome::Failed(error) => writeln!(f, "  [FAIL] {:<12} {}", check.name, error)?,
+        }
+    }
+    Ok(())
+}
+
+/// Validates the configuration and tests every dependency of every project,
+/// without registering any of them. With `write_s3`, bucket access is verified by
+/// writing a test object instead of only looking the bucket up.
+pub async fn run(config: &TextEgressConfig, write_s3: bool) -> PreflightReport {
+    let admission = AdmissionController::new(&config.admission, None, None);
+    let checks = vec![CheckResult::run("work_dir", check_work_dir(&admission)).await];
+
+    let mut projects = vec![];
+    for project in &config.projects {
+        projects.push(ProjectReport {
+            project_id: project.project_id.clone(),
+            checks: check_project(config, project, write_s3).await,
+        });
+    }
+
+    PreflightReport { checks, projects }
+}
+
+async fn check_project(
+    config: &TextEgressConfig,
+    project: &Projects,
+    write_s3: bool,
+) -> Vec<CheckResult> {
+    let providers = config.secrets.providers();
+    let mut project = project.clone();
+    let mut checks = vec![];
+    if !providers.is_empty() {
+        let secrets = CheckResult::run("secrets", async {
+            project.resolve_secrets(&providers).await?;
+            Ok::<_, crate::error_messages::TextEgressError>("references resolved".to_string())
+        })
+        .await;
+        // The other checks need the resolved secrets
+        let resolved = matches!(secrets.outcome, CheckOutcome::Ok(_));
+        checks.push(secrets);
+        if !resolved {
+            return checks;
+        }
+    }
+    let project = &project;
+
+    let client = ProjectClient::new(
+        &config.syncflow_server_url,
+        &project.project_id,
+        &project.key,
+        project.secret.expose(),
+    );
+    checks.extend([
+        CheckResult::run("config", check_project_config(project)).await,
+        CheckResult::run("syncflow", async {
+            let details = client.get_project_details().await?;
+            Ok::<_, syncflow_client::ProjectClientError>(format!(
+                "credentials accepted for {}",
+                details.name
+            ))
+        })
+        .await,
+        CheckResult::run("rabbitmq", check_rabbitmq(config, &client)).await,
+        if write_s3 {
+            check_s3_write(project).await
+        } else {
+            CheckResult::run("s3", check_s3_bucket(project)).await
+        },
+    ]);
+    checks
+}
+
+async fn check_project_config(project: &Projects) -> Result<String, String> {
+    let recording = &project.recording;
+    let encryption = &project.s3_config.encryption;
+    let validation = async {
+        ParticipantPolicy::from_config(recording)?;
+        Redactor::from_config(&recording.redaction)?;
+        ServerSideEncryptionHeaders::from_config(encryption)?;
+        if let Some(path) = &encryption.public_key_path {
+            EnvelopeEncryptor::from_pem_file(path)?;
+        }
+        Ok::<_, crate::error_messages::TextEgressError>(())
+    };
+    validation.await.map_err(|e| e.to_string())?;
+    Ok("recording and encryption settings are valid".to_string())
+}
+
+async fn check_rabbitmq(
+    config: &TextEgressConfig,
+    client: &ProjectClient,
+) -> Result<String, String> {
+    let api_token = Secret::new(client.generate_api_token().map_err(|e| e.to_string())?);
+    let connection = open_rabbitmq_connection(
+        &config.rabbitmq_host,
+        config.rabbitmq_port,
+        RABBITMQ_VHOST_NAME,
+        EGRESS_DEVICE_GROUP,
+        &api_token,
+        true,
+    )
+    .await
+    .map_err(|e| e.to_string())?;
+    let _ = connection.close().await;
+    Ok(format!(
+        "connected to {}:{}",
+        config.rabbitmq_host, config.rabbitmq_port
+    ))
+}
+
+async fn check_s3_bucket(project: &Projects) -> Result<String, String> {
+    let s3_config = &project.s3_config;
+    s3_client(s3_config)
+        .head_bucket(rusoto_s3::HeadBucketRequest {
+            bucket: s3_config.bucket_name.clone(),
+            ..Default::default()
+        })
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(format!("{} is reachable", s3_config.bucket_name))
+}
+
+async fn check_s3_write(project: &Projects) -> CheckResult {
+    let s3_config = &project.s3_config;
+    let client = s3_client(s3_config);
+    let key = format!("{}/{}", CHECK_OBJECT_PREFIX, Uuid::new_v4());
+    let written = CheckResult::run("s3", async {
+        let mut put_req = rusoto_s3::PutObjectRequest {
+            bucket: s3_config.bucket_name.clone(),
+            key: key.clone(),
+            body: Some(b"ok".to_vec().into()),
+            content_type: Some("text/plain".to_string()),
+            ..Default::default()
+        };
+        ServerSideEncryptionHeaders::from_config(&s3_config.encryption)
+            .map_err(|e| e.to_string())?
+            .apply(&mut put_req);
+        client
+            .put_object(put_req)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok::<_, String>(format!("wrote a test object to {}", s3_config.bucket_name))
+    })
+    .await;
+    if !matches!(written.outcome, CheckOutcome::Ok(_)) {
+        return written;
+    }
+
+    let deleted = client
+        .delete_object(rusoto_s3::DeleteObjectRequest {
+            bucket: s3_config.bucket_name.clone(),
+            key: key.clone(),
+            ..Default::default()
+        })
+        .await;
+    let outcome = match deleted {
+        Ok(_) => written.outcome,
+        Err(e) => CheckOutcome::Warning(format!(
+            "wrote {} to {}, but could not delete it: {}",
+            key, s3_config.bucket_name, e
+        )),
+    };
+    CheckResult {
+        name: "s3",
+        outcome,
+    }
+}
+
+async fn check_work_dir(admission: &AdmissionController) -> Result<String, std::io::Error> {
+    let work_dir = admission.work_dir();
+    let path = work_dir.join(format!("{}-{}", CHECK_OBJECT_PREFIX, Uuid::new_v4()));
+    tokio::fs::write(&path, b"ok").await?;
+    tokio::fs::remove_file(&path).await?;
+    Ok(format!("{} is writable", work_dir.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(name: &'static str, outcome: CheckOutcome) -> CheckResult {
+        CheckResult { name, outcome }
+    }
+
+    #[test]
+    fn warnings_do_not_fail_the_report() {
+        let mut report = PreflightReport {
+            checks: vec![check("work_dir", CheckOutcome::Ok("writable".to_string()))],
+            projects: vec![ProjectReport {
+                project_id: "project".to_string(),
+                checks: vec![check(
+                    "s3",
+                    CheckOutcome::Warning("could not delete it".to_string()),
+                )],
+            }],
+        };
+        assert!(report.passed());
+        let printed = report.to_string();
+        assert!(printed.contains("[warn] s3"));
+        assert!(printed.ends_with("Preflight checks passed"));
+        assert!(report.failures().is_empty());
+
+        report.projects[0].checks.push(check(
+            "syncflow",
+            CheckOutcome::Failed("denied".to_string()),
+        ));
+        assert!(!report.passed());
+        assert!(report.to_string().contains("[FAIL] syncflow     denied"));
+        assert_eq!(
+            report.failures(),
+            HashMap::from([("project".to_string(), "syncflow: denied".to_string())])
+        );
+    }
+
+    #[test]
+    fn global_failures_fail_every_project() {
+        let project = |project_id: &str| ProjectReport {
+            project_id: project_id.to_string(),
+            checks: vec![check("s3", CheckOutcome::Ok("reachable".to_string()))],
+        };
+        let report = PreflightReport {
+            checks: vec![check(
+                "work_dir",
+                CheckOutcome::Failed("read-only".to_string()),
+            )],
+            projects: vec![project("a"), project("b")],
+        };
+        let failures = report.failures();
+        assert_eq!(failures.len(), 2);
+        assert_eq!(failures["b"], "work_dir: read-only");
+    }
+
+    #[actix_rt::test]
+    async fn reports_errors_as_failures() {
+        let failed = CheckResult::run("rabbitmq", async {
+            Err::<String, _>("refused".to_string())
+        })
+        .await;
+        assert_eq!(failed.outcome, CheckOutcome::Failed("refused".to_string()));
+    }
+}
@@ -0,0 +1,197 @@
+use serde::Serialize;
+use std::fmt;
+use std::path::Path;
+
+use crate::compression::Compression;
+use crate::error_messages::TextEgressError;
+
+/// A line of a participant file, see `timestamps::RECORD_FORMAT`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Record {
+    pub received_at_iso: String,
+    pub received_at_ns: i64,
+    pub offset_ns: i64,
+    pub sender_timestamp_ns: Option<i64>,
+    pub text: String,
+}
+
+impl Record {
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let mut columns = line.splitn(5, '|');
+        let mut column = |name: &str| {
+            columns
+                .next()
+                .ok_or_else(|| format!("missing column {}", name))
+        };
+        let parse_ns = |name: &str, value: &str| {
+            value
+                .parse::<i64>()
+                .map_err(|_| format!("{} is not a timestamp: {:?}", name, value))
+        };
+
+        let received_at_iso = column("received_at_iso")?.to_string();
+        let received_at_ns = parse_ns("received_at_ns", column("received_at_ns")?)?;
+        let offset_ns = parse_ns("offset_ns", column("offset_ns")?)?;
+        let sender_timestamp_ns = match column("sender_timestamp_ns")? {
+            "" => None,
+            value => Some(parse_ns("sender_timestamp_ns", value)?),
+        };
+        let text = column("text")?.to_string();
+
+        Ok(Record {
+            received_at_iso,
+            received_at_ns,
+            offset_ns,
+            sender_timestamp_ns,
+            text,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct InvalidLine {
+    pub line: usize,
+    pub error: String,
+}
+
+/// Message counts and problems found in a participant file.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RecordingSummary {
+    pub path: String,
+    pub message_count: usize,
+    pub first_message_at: Option<i64>,
+    pub last_message_at: Option<i64>,
+    /// Lines received earlier than the line before them
+    pub out_of_order: usize,
+    pub invalid_lines: Vec<InvalidLine>,
+}
+
+impl RecordingSummary {
+    pub fn is_valid(&self) -> bool {
+        self.invalid_lines.is_empty()
+    }
+}
+
+impl fmt::Display for RecordingSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let format_ns = |ns: Option<i64>| {
+            ns.map(|ns| chrono::DateTime::from_timestamp_nanos(ns).to_rfc3339())
+                .unwrap_or_else(|| "-".to_string())
+        };
+        writeln!(f, "{}", self.path)?;
+        writeln!(f, "  messages:      {}", self.message_count)?;
+        writeln!(f, "  first message: {}", format_ns(self.first_message_at))?;
+        writeln!(f, "  last message:  {}", format_ns(self.last_message_at))?;
+        writeln!(f, "  out of order:  {}", self.out_of_order)?;
+        write!(f, "  invalid lines: {}", self.invalid_lines.len())?;
+        for invalid in &self.invalid_lines {
+            write!(f, "\n    line {}: {}", invalid.line, invalid.error)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads a participant file, decompressing `.gz` and `.zst` objects downloaded from S3.
+#[allow(clippy::result_large_err)]
+pub fn read_recording(path: &Path) -> Result<(Vec<Record>, RecordingSummary), TextEgressError> {
+    let compression = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(Compression::from_extension)
+        .unwrap_or_default();
+    let content = compression.decompress(std::fs::read(path)?)?;
+    let content = String::from_utf8_lossy(&content);
+
+    let mut records = vec![];
+    let mut summary = RecordingSummary {
+        path: path.display().to_string(),
+        ..Default::default()
+    };
+    for (index, line) in content.lines().enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        match Record::parse(line) {
+            Ok(record) => {
+                if summary
+                    .last_message_at
+                    .is_some_and(|last| record.received_at_ns < last)
+                {
+                    summary.out_of_order += 1;
+                }
+                summary.message_count += 1;
+                summary
+                    .first_message_at
+                    .get_or_insert(record.received_at_ns);
+                summary.last_message_at = Some(record.received_at_ns);
+                records.push(record);
+            }
+            Err(error) => summary.invalid_lines.push(InvalidLine {
+                line: index + 1,
+                error,
+            }),
+        }
+    }
+    Ok((records, summary))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn parses_records() {
+        let record = Record::parse(
+            "2024-05-01T10:00:00UTC|1714557600000000000|1500|1714557599999000000|a|b",
+        )
+        .unwrap();
+        assert_eq!(record.received_at_iso, "2024-05-01T10:00:00UTC");
+        assert_eq!(record.received_at_ns, 1714557600000000000);
+        assert_eq!(record.offset_ns, 1500);
+        assert_eq!(record.sender_timestamp_ns, Some(1714557599999000000));
+        // Only the first columns are split, the text may contain the separator
+        assert_eq!(record.text, "a|b");
+
+        let without_sender = Record::parse("iso|1|0||hello").unwrap();
+        assert_eq!(without_sender.sender_timestamp_ns, None);
+        assert_eq!(without_sender.text, "hello");
+    }
+
+    #[test]
+    fn rejects_malformed_records() {
+        assert_eq!(
+            Record::parse("iso|1|0|").unwrap_err(),
+            "missing column text"
+        );
+        assert!(Record::parse("iso|yesterday|0||hello")
+            .unwrap_err()
+            .starts_with("received_at_ns is not a timestamp"));
+        assert!(Record::parse("iso|1|0|soon|hello")
+            .unwrap_err()
+            .starts_with("sender_timestamp_ns is not a timestamp"));
+    }
+
+    #[test]
+    fn summarizes_compressed_recordings() {
+        let dir = TempDir::new("recording").unwrap();
+        let path = dir.path().join("alice-all-topics.txt.gz");
+        let content = "iso|3|0||first\n\niso|2|0||out of order\nnot a record\niso|5|0||last\n";
+        let compressed = Compression::Gzip
+            .compress(content.as_bytes().to_vec())
+            .unwrap();
+        std::fs::write(&path, compressed).unwrap();
+
+        let (records, summary) = read_recording(&path).unwrap();
+
+        assert_eq!(records.len(), 3);
+        assert_eq!(summary.message_count, 3);
+        assert_eq!(summary.first_message_at, Some(3));
+        assert_eq!(summary.last_message_at, Some(5));
+        assert_eq!(summary.out_of_order, 1);
+        assert!(!summary.is_valid());
+        assert_eq!(summary.invalid_lines.len(), 1);
+        assert_eq!(summary.invalid_lines[0].line, 4);
+        assert!(summary.to_string().contains("line 4: missing column"));
+    }
+}
@@ -0,0 +1,461 @@
+use regex::{NoExpand, Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::LazyLock;
+
+use crate::config::RedactionConfig;
+use crate::error_messages::TextEgressError;
+
+const EMAIL_PATTERN: &str = r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}";
+const PHONE_PATTERN: &str = r"(?:\+?\d{1,3}[\s.-]?)?(?:\(\d{3}\)|\d{3})[\s.-]?\d{3}[\s.-]?\d{4}\b";
+
+/// A capitalized word, including hyphenated and apostrophized names like Mary-Jane or O'Brien
+static CAPITALIZED_WORD: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\b\p{Lu}(?:['’]\p{Lu})?\p{Ll}+(?:['’-]\p{L}+)*\b").unwrap());
+
+/// Capitalized words that are not names even in the middle of a sentence
+const COMMON_CAPITALIZED: &[&str] = &[
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+    "Sunday",
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+    "I'm",
+    "I'll",
+    "I've",
+    "I'd",
+];
+
+/// Counts of redacted matches, written to the egress metadata.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RedactionReport {
+    /// Number of messages that had at least one match
+    pub redacted_messages: usize,
+    /// Number of matches per placeholder label
+    pub matches: BTreeMap<String, usize>,
+}
+
+struct RedactionRule {
+    label: String,
+    regex: Regex,
+}
+
+/// A span of text recognized as a named entity, replaced with `[LABEL]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntityMatch {
+    pub start: usize,
+    pub end: usize,
+    pub label: String,
+}
+
+/// Finds named entities in captured text. Detectors run after the pattern rules,
+/// on text that already has their placeholders.
+pub trait EntityDetector: Send + Sync {
+    /// Non-overlapping matches, in any order.
+    fn detect(&self, text: &str) -> Vec<EntityMatch>;
+}
+
+/// Detects person names, from a list of known names and from capitalized words that
+/// do not start a sentence. This is a heuristic: lowercase names are missed and other
+/// proper nouns, such as places, are redacted as well.
+pub struct NameDetector {
+    names: Option<Regex>,
+    capitalized: bool,
+}
+
+impl NameDetector {
+    #[allow(clippy::result_large_err)]
+    pub fn new(names: &[String], capitalized: bool) -> Result<Self, TextEgressError> {
+        Ok(NameDetector {
+            names: whole_words(names)?,
+            capitalized,
+        })
+    }
+
+    /// Runs of capitalized words with at least one word that is likely a name.
+    fn capitalized_names(&self, text: &str) -> Vec<(usize, usize)> {
+        let mut spans = vec![];
+        // Start, end and whether a word of the run is likely a name
+        let mut run: Option<(usize, usize, bool)> = None;
+        for word in CAPITALIZED_WORD.find_iter(text) {
+            let likely_name = !starts_sentence(&text[..word.start()])
+                && !COMMON_CAPITALIZED.contains(&word.as_str());
+            match run {
+                Some((start, end, has_name)) if &text[end..word.start()] == " " => {
+                    run = Some((start, word.end(), has_name || likely_name));
+                }
+                _ => {
+                    if let Some((start, end, true)) = run {
+                        spans.push((start, end));
+                    }
+                    run = Some((word.start(), word.end(), likely_name));
+                }
+            }
+        }
+        if let Some((start, end, true)) = run {
+            spans.push((start, end));
+        }
+        spans
+    }
+}
+
+impl EntityDetector for NameDetector {
+    fn detect(&self, text: &str) -> Vec<EntityMatch> {
+        let mut spans: Vec<(usize, usize)> = self
+            .names
+            .iter()
+            .flat_map(|names| names.find_iter(text).map(|m| (m.start(), m.end())))
+            .collect();
+        if self.capitalized {
+            spans.extend(self.capitalized_names(text));
+        }
+        spans.sort();
+
+        let mut matches: Vec<EntityMatch> = vec![];
+        for (start, end) in spans {
+            match matches.last_mut() {
+                Some(last) if start <= last.end => last.end = last.end.max(end),
+                _ => matches.push(EntityMatch {
+                    start,
+                    end,
+                    label: "NAME".to_string(),
+                }),
+            }
+        }
+        matches
+    }
+}
+
+fn starts_sentence(before: &str) -> bool {
+    let before = before.trim_end();
+    before.is_empty() || before.ends_with(['.', '!', '?', ':'])
+}
+
+/// Matches any of the terms as whole words regardless of case, `None` without terms.
+#[allow(clippy::result_large_err)]
+fn whole_words(terms: &[String]) -> Result<Option<Regex>, TextEgressError> {
+    if terms.is_empty() {
+        return Ok(None);
+    }
+    let alternation = terms
+        .iter()
+        .map(|term| regex::escape(term))
+        .collect::<Vec<_>>()
+        .join("|");
+    let regex = RegexBuilder::new(&format!(r"\b(?:{})\b", alternation))
+        .case_insensitive(true)
+        .build()?;
+    Ok(Some(regex))
+}
+
+/// Reads one term per line, skipping blank lines.
+#[allow(clippy::result_large_err)]
+fn read_terms(path: &str) -> Result<Vec<String>, TextEgressError> {
+    Ok(std::fs::read_to_string(path)?
+        .lines()
+        .map(|line| line.trim().to_string())
+        .filter(|line| !line.is_empty())
+        .collect())
+}
+
+/// Replaces PII in captured text with `[LABEL]` placeholders.
+pub struct Redactor {
+    rules: Vec<RedactionRule>,
+    detectors: Vec<Box<dyn EntityDetector>>,
+}
+
+impl Redactor {
+    /// Compiles the configured rules, returns `None` when redaction is disabled.
+    #[allow(clippy::result_large_err)]
+    pub fn from_config(config: &RedactionConfig) -> Result<Option<Self>, TextEgressError> {
+        if !config.enabled {
+            return Ok(None);
+        }
+
+        let mut rules = vec![];
+        let mut add_rule = |label: &str, pattern: &str| -> Result<(), TextEgressError> {
+            rules.push(RedactionRule {
+                label: label.to_uppercase(),
+                regex: Regex::new(pattern)?,
+            });
+            Ok(())
+        };
+
+        if config.emails {
+            add_rule("EMAIL", EMAIL_PATTERN)?;
+        }
+        if config.phone_numbers {
+            add_rule("PHONE", PHONE_PATTERN)?;
+        }
+        if let Some(pattern) = &config.student_id_pattern {
+            add_rule("STUDENT_ID", pattern)?;
+        }
+        for rule in &config.rules {
+            add_rule(&rule.label, &rule.pattern)?;
+        }
+
+        let mut dictionary = config.dictionary.clone();
+        if let Some(path) = &config.dictionary_path {
+            dictionary.extend(read_terms(path)?);
+        }
+        if let Some(regex) = whole_words(&dictionary)? {
+            rules.push(RedactionRule {
+                label: "DICTIONARY".to_string(),
+                regex,
+            });
+        }
+
+        let mut redactor = Redactor {
+            rules,
+            detectors: vec![],
+        };
+        let mut names = config.names.clone();
+        if let Some(path) = &config.names_path {
+            names.extend(read_terms(path)?);
+        }
+        if !names.is_empty() || config.capitalized_names {
+            redactor = redactor.with_detector(NameDetector::new(&names, config.capitalized_names)?);
+        }
+        Ok(Some(redactor))
+    }
+
+    /// Adds a named entity detector, applied after the configured rules.
+    pub fn with_detector(mut self, detector: impl EntityDetector + 'static) -> Self {
+        self.detectors.push(Box::new(detector));
+        self
+    }
+
+    /// Redacts `text` and records the matches in `report`.
+    pub fn redact(&self, text: &str, report: &mut RedactionReport) -> String {
+        let mut redacted = text.to_string();
+        let mut matched = false;
+
+        for rule in &self.rules {
+            let count = rule.regex.find_iter(&redacted).count();
+            if count == 0 {
+                continue;
+            }
+            matched = true;
+            *report.matches.entry(rule.label.clone()).or_default() += count;
+            let placeholder = format!("[{}]", rule.label);
+            redacted = rule
+                .regex
+                .replace_all(&redacted, NoExpand(&placeholder))
+                .into_owned();
+        }
+
+        for detector in &self.detectors {
+            let mut entities = detector.detect(&redacted);
+            // Replaced from the end, so that earlier offsets stay valid
+            entities.sort_by_key(|entity| std::cmp::Reverse(entity.start));
+            for entity in entities {
+                matched = true;
+                *report.matches.entry(entity.label.clone()).or_default() += 1;
+                redacted.replace_range(entity.start..entity.end, &format!("[{}]", entity.label));
+            }
+        }
+
+        if matched {
+            report.redacted_messages += 1;
+        }
+        redacted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RedactionRuleConfig;
+
+    fn redactor(config: RedactionConfig) -> Redactor {
+        Redactor::from_config(&RedactionConfig {
+            enabled: true,
+            ..config
+        })
+        .unwrap()
+        .unwrap()
+    }
+
+    fn redact(redactor: &Redactor, text: &str) -> (String, RedactionReport) {
+        let mut report = RedactionReport::default();
+        let redacted = redactor.redact(text, &mut report);
+        (redacted, report)
+    }
+
+    #[test]
+    fn is_disabled_by_default() {
+        assert!(Redactor::from_config(&RedactionConfig::default())
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn redacts_emails() {
+        let redactor = redactor(RedactionConfig::default());
+        let (redacted, report) = redact(&redactor, "mail jane.doe+tag@example.edu or a@b.io");
+        assert_eq!(redacted, "mail [EMAIL] or [EMAIL]");
+        assert_eq!(report.matches["EMAIL"], 2);
+        assert_eq!(report.redacted_messages, 1);
+    }
+
+    #[test]
+    fn redacts_phone_numbers() {
+        let redactor = redactor(RedactionConfig::default());
+        for number in [
+            "555-123-4567",
+            "(555) 123-4567",
+            "+1 555.123.4567",
+            "5551234567",
+        ] {
+            let (redacted, _) = redact(&redactor, &format!("call {} now", number));
+            assert_eq!(redacted, "call [PHONE] now", "{}", number);
+        }
+        let (redacted, report) = redact(&redactor, "room 12345, 3 students");
+        assert_eq!(redacted, "room 12345, 3 students");
+        assert_eq!(report.redacted_messages, 0);
+    }
+
+    #[test]
+    fn skips_disabled_built_in_patterns() {
+        let redactor = redactor(RedactionConfig {
+            emails: false,
+            phone_numbers: false,
+            ..Default::default()
+        });
+        let text = "jane@example.edu 555-123-4567";
+        assert_eq!(redact(&redactor, text).0, text);
+    }
+
+    #[test]
+    fn redacts_student_ids_and_custom_rules() {
+        let redactor = redactor(RedactionConfig {
+            student_id_pattern: Some(r"\b[A-Z]\d{8}\b".to_string()),
+            rules: vec![RedactionRuleConfig {
+                label: "ssn".to_string(),
+                pattern: r"\d{3}-\d{2}-\d{4}".to_string(),
+            }],
+            ..Default::default()
+        });
+        let (redacted, report) = redact(&redactor, "A12345678 has ssn 123-45-6789");
+        assert_eq!(redacted, "[STUDENT_ID] has ssn [SSN]");
+        assert_eq!(report.matches["STUDENT_ID"], 1);
+        assert_eq!(report.matches["SSN"], 1);
+    }
+
+    #[test]
+    fn rejects_invalid_custom_rules() {
+        let config = RedactionConfig {
+            enabled: true,
+            rules: vec![RedactionRuleConfig {
+                label: "broken".to_string(),
+                pattern: "(".to_string(),
+            }],
+            ..Default::default()
+        };
+        assert!(Redactor::from_config(&config).is_err());
+    }
+
+    #[test]
+    fn redacts_whole_dictionary_terms() {
+        let redactor = redactor(RedactionConfig {
+            dictionary: vec!["Jane Doe".to_string(), "Bob".to_string()],
+            capitalized_names: false,
+            ..Default::default()
+        });
+        let (redacted, report) = redact(&redactor, "jane doe asked BOB, Bobby and Alice");
+        assert_eq!(redacted, "[DICTIONARY] asked [DICTIONARY], Bobby and Alice");
+        assert_eq!(report.matches["DICTIONARY"], 2);
+    }
+
+    #[test]
+    fn redacts_listed_names() {
+        let redactor = redactor(RedactionConfig {
+            names: vec!["jane".to_string()],
+            capitalized_names: false,
+            ..Default::default()
+        });
+        let (redacted, report) = redact(&redactor, "Jane said hi to jane and Janet");
+        assert_eq!(redacted, "[NAME] said hi to [NAME] and Janet");
+        assert_eq!(report.matches["NAME"], 2);
+    }
+
+    #[test]
+    fn redacts_capitalized_names_within_sentences() {
+        let redactor = redactor(RedactionConfig::default());
+        let (redacted, report) = redact(
+            &redactor,
+            "Thanks, I asked Mary-Jane O'Brien on Monday. Then we met Bob. Okay",
+        );
+        assert_eq!(
+            redacted,
+            "Thanks, I asked [NAME] on Monday. Then we met [NAME]. Okay"
+        );
+        assert_eq!(report.matches["NAME"], 2);
+    }
+
+    #[test]
+    fn keeps_other_placeholders() {
+        let redactor = redactor(RedactionConfig::default());
+        let (redacted, report) = redact(&redactor, "mail Jane Doe at jane@example.edu");
+        assert_eq!(redacted, "mail [NAME] at [EMAIL]");
+        assert_eq!(report.redacted_messages, 1);
+    }
+
+    #[test]
+    fn runs_pluggable_detectors() {
+        struct Course;
+        impl EntityDetector for Course {
+            fn detect(&self, text: &str) -> Vec<EntityMatch> {
+                text.match_indices("CS101")
+                    .map(|(start, term)| EntityMatch {
+                        start,
+                        end: start + term.len(),
+                        label: "COURSE".to_string(),
+                    })
+                    .collect()
+            }
+        }
+        let redactor = redactor(RedactionConfig::default()).with_detector(Course);
+        let (redacted, report) = redact(&redactor, "see you in CS101 and CS101");
+        assert_eq!(redacted, "see you in [COURSE] and [COURSE]");
+        assert_eq!(report.matches["COURSE"], 2);
+    }
+
+    #[test]
+    fn does_not_expand_placeholders() {
+        let redactor = redactor(RedactionConfig {
+            rules: vec![RedactionRuleConfig {
+                label: "$0".to_string(),
+                pattern: "secret".to_string(),
+            }],
+            ..Default::default()
+        });
+        assert_eq!(redact(&redactor, "a secret").0, "a [$0]");
+    }
+
+    #[test]
+    fn counts_messages_across_calls() {
+        let redactor = redactor(RedactionConfig::default());
+        let mut report = RedactionReport::default();
+        redactor.redact("a@b.io", &mut report);
+        redactor.redact("nothing here", &mut report);
+        redactor.redact("c@d.io 555-123-4567", &mut report);
+        assert_eq!(report.redacted_messages, 2);
+        assert_eq!(report.matches["EMAIL"], 2);
+        assert_eq!(report.matches["PHONE"], 1);
+    }
+}
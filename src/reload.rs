@@ -0,0 +1,444 @@
+use actix::{Actor, Addr};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::Semaphore;
+
+use crate::admin::{DegradedProject, DegradedProjects, ProjectListeners};
+use crate::admission::AdmissionController;
+use crate::config::{Projects, TextEgressConfig};
+use crate::error_messages::TextEgressError;
+use crate::session_listener_actor::{
+    ListenerResources, ProjectMessages, ReloadMessages, SessionListenerActor,
+};
+
+/// Delay before the first retry of a project that failed, doubled on every further failure
+const RETRY_INITIAL_DELAY: Duration = Duration::from_secs(5);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(300);
+
+/// A project waiting to be started again.
+#[derive(Debug)]
+struct Retry {
+    project: Projects,
+    attempts: u32,
+    next_attempt: Instant,
+}
+
+/// A registered project and its session listener.
+struct RunningProject {
+    project: Projects,
+    addr: Addr<SessionListenerActor>,
+    resources: ListenerResources,
+}
+
+/// The session listeners of the configured projects, reconciled with the configuration on reload.
+/// Projects that fail to register, or whose listener stops, are retried without affecting the others.
+pub struct ProjectRegistry {
+    config: TextEgressConfig,
+    global_egress_limit: Option<Arc<Semaphore>>,
+    projects: HashMap<String, RunningProject>,
+    listeners: ProjectListeners,
+    retries: HashMap<String, Retry>,
+    degraded: DegradedProjects,
+}
+
+impl ProjectRegistry {
+    pub fn new(config: TextEgressConfig) -> Self {
+        let global_egress_limit = config
+            .admission
+            .max_concurrent_egresses
+            .map(|limit| Arc::new(Semaphore::new(limit)));
+        ProjectRegistry {
+            config,
+            global_egress_limit,
+            projects: HashMap::new(),
+            listeners: ProjectListeners::default(),
+            retries: HashMap::new(),
+            degraded: DegradedProjects::default(),
+        }
+    }
+
+    /// Registers every configured project, scheduling a retry for those that fail.
+    pub async fn start(&mut self) {
+        for project in self.config.projects.clone() {
+            if self.retries.contains_key(&project.project_id) {
+                continue;
+            }
+            self.try_start_project(&project).await;
+        }
+    }
+
+    /// Keeps a project from starting until its first retry, such as after failed preflight checks.
+    pub fn defer(&mut self, project_id: &str, error: TextEgressError) {
+        let project = self
+            .config
+            .projects
+            .iter()
+            .find(|project| project.project_id == project_id)
+            .cloned();
+        if let Some(project) = project {
+            self.mark_degraded(&project, error);
+        }
+    }
+
+    /// Restarts projects whose session listener stopped, such as after a panic,
+    /// and retries degraded projects that are due.
+    pub async fn supervise(&mut self) {
+        let stopped: Vec<String> = self
+            .projects
+            .iter()
+            .filter(|(_, running)| !running.addr.connected())
+            .map(|(project_id, _)| project_id.clone())
+            .collect();
+        for project_id in stopped {
+            let Some(running) = self.projects.remove(&project_id) else {
+                continue;
+            };
+            self.listeners.write().unwrap().remove(&project_id);
+            log::error!(
+                "Session listener of project {} stopped unexpectedly, restarting it",
+                project_id
+            );
+            running.resources.release().await;
+            self.try_start_project(&running.project).await;
+        }
+
+        let now = Instant::now();
+        let due: Vec<Projects> = self
+            .retries
+            .values()
+            .filter(|retry| retry.next_attempt <= now)
+            .map(|retry| retry.project.clone())
+            .collect();
+        for project in due {
+            log::info!("Retrying project {}", project.project_id);
+            self.try_start_project(&project).await;
+        }
+    }
+
+    async fn try_start_project(&mut self, project: &Projects) {
+        match self.start_project(project).await {
+            Ok(()) => {
+                if self.retries.remove(&project.project_id).is_some() {
+                    log::info!("Project {} recovered", project.project_id);
+                }
+                self.degraded.write().unwrap().remove(&project.project_id);
+            }
+            Err(e) => self.mark_degraded(project, e),
+        }
+    }
+
+    fn mark_degraded(&mut self, project: &Projects, error: TextEgressError) {
+        let attempts = self
+            .retries
+            .get(&project.project_id)
+            .map_or(0, |retry| retry.attempts)
+            + 1;
+        let delay = retry_delay(attempts);
+        log::error!(
+            "Project {} is degraded, retrying in {:?}: {:?}",
+            project.project_id,
+            delay,
+            error
+        );
+        self.degraded.write().unwrap().insert(
+            project.project_id.clone(),
+            DegradedProject {
+                project_id: project.project_id.clone(),
+                error: error.to_string(),
+                attempts,
+                retry_at: (chrono::Utc::now() + delay).to_rfc3339(),
+            },
+        );
+        self.retries.insert(
+            project.project_id.clone(),
+            Retry {
+                project: project.clone(),
+                attempts,
+                next_attempt: Instant::now() + delay,
+            },
+        );
+    }
+
+    fn forget_degraded(&mut self, project_id: &str) {
+        self.retries.remove(project_id);
+        self.degraded.write().unwrap().remove(project_id);
+    }
+
+    async fn start_project(&mut self, project: &Projects) -> Result<(), TextEgressError> {
+        let config = &self.config;
+        // Resolved on every start, so that a retry picks up secrets fixed in the meantime
+        let configured = project;
+        let mut project = configured.clone();
+        project.resolve_secrets(&config.secrets.providers()).await?;
+        let session_listener_actor = SessionListenerActor::new(
+            &config.rabbitmq_host,
+            config.rabbitmq_port,
+            true,
+            &project.project_id,
+            &config.syncflow_server_url,
+            &project.key,
+            project.secret.expose(),
+            &project.s3_config,
+            &project.recording,
+            AdmissionController::new(
+                &config.admission,
+                project.max_concurrent_egresses,
+                self.global_egress_limit.clone(),
+            ),
+            project.retry_failed_sessions,
+            &config.coordination,
+            &config.status_reporting,
+        );
+        let resources = session_listener_actor.resources();
+        let session_listener_actor = session_listener_actor.start();
+
+        let register = session_listener_actor
+            .send(ProjectMessages::Register)
+            .await??;
+        log::info!(
+            "Registered to project {} as device {}",
+            project.project_id,
+            register.id
+        );
+
+        self.listeners
+            .write()
+            .unwrap()
+            .insert(project.project_id.clone(), session_listener_actor.clone());
+        self.projects.insert(
+            project.project_id.clone(),
+            RunningProject {
+                project: configured.clone(),
+                addr: session_listener_actor,
+                resources,
+            },
+        );
+        Ok(())
+    }
+
+    /// The running session listeners, kept up to date across reloads.
+    pub fn listeners(&self) -> ProjectListeners {
+        self.listeners.clone()
+    }
+
+    /// The projects waiting to be retried.
+    pub fn degraded(&self) -> DegradedProjects {
+        self.degraded.clone()
+    }
+
+    fn drain_timeout(&self) -> Duration {
+        Duration::from_secs(self.config.drain_timeout_secs)
+    }
+
+    fn remove_project(&mut self, project_id: &str) -> Option<Addr<SessionListenerActor>> {
+        self.listeners.write().unwrap().remove(project_id);
+        self.projects.remove(project_id).map(|running| running.addr)
+    }
+
+    /// Starts added projects, drains removed ones and applies changed settings.
+    /// Settings outside of `projects` only take effect after a restart.
+    pub async fn reload(&mut self, config: TextEgressConfig) {
+        if settings(&self.config) != settings(&config) {
+            log::warn!("Only project changes are applied on reload, restart to apply the rest");
+        }
+        self.config.projects = config.projects.clone();
+
+        let configured: HashMap<String, Projects> = config
+            .projects
+            .into_iter()
+            .map(|project| (project.project_id.clone(), project))
+            .collect();
+
+        let removed: Vec<String> = self
+            .projects
+            .keys()
+            .filter(|project_id| !configured.contains_key(*project_id))
+            .cloned()
+            .collect();
+        for project_id in removed {
+            log::info!("Project {} was removed, draining it", project_id);
+            if let Some(addr) = self.remove_project(&project_id) {
+                drain(project_id, addr, self.drain_timeout());
+            }
+        }
+
+        // Degraded projects keep their retry schedule unless their settings changed
+        let retried: Vec<String> = self.retries.keys().cloned().collect();
+        for project_id in retried {
+            let unchanged = configured
+                .get(&project_id)
+                .is_some_and(|project| *project == self.retries[&project_id].project);
+            if !unchanged {
+                self.forget_degraded(&project_id);
+            }
+        }
+
+        for (project_id, project) in configured {
+            if self.retries.contains_key(&project_id) {
+                continue;
+            }
+            let Some(RunningProject {
+                project: running,
+                addr,
+                ..
+            }) = self.projects.get(&project_id)
+            else {
+                log::info!("Project {} was added, starting it", project_id);
+                self.try_start_project(&project).await;
+                continue;
+            };
+            if *running == project {
+                continue;
+            }
+
+            let only_s3_changed = Projects {
+                s3_config: project.s3_config.clone(),
+                ..running.clone()
+            } == project;
+            if only_s3_changed {
+                log::info!("S3 settings of project {} changed", project_id);
+                let update = async {
+                    let mut resolved = project.clone();
+                    resolved
+                        .resolve_secrets(&self.config.secrets.providers())
+                        .await?;
+                    addr.send(ReloadMessages::UpdateS3Config(resolved.s3_config))
+                        .await?
+                };
+                match update.await {
+                    Ok(()) => {
+                        if let Some(running) = self.projects.get_mut(&project_id) {
+                            running.project = project;
+                        }
+                    }
+                    Err(e) => log::error!(
+                        "Keeping previous S3 settings of project {}: {:?}",
+                        project_id,
+                        e
+                    ),
+                }
+                continue;
+            }
+
+            // Running egresses finish with the previous settings
+            log::info!("Project {} changed, restarting it", project_id);
+            if let Some(addr) = self.remove_project(&project_id) {
+                drain(project_id.clone(), addr, self.drain_timeout());
+            }
+            self.try_start_project(&project).await;
+        }
+    }
+
+    /// Deregisters every project without waiting for running egresses. A project that
+    /// fails to deregister does not keep the others registered.
+    pub async fn shutdown(self) -> Result<(), TextEgressError> {
+        let mut failed = vec![];
+        for (project_id, RunningProject { addr, .. }) in self.projects {
+            let deregistered = match addr.send(ProjectMessages::Deregister).await {
+                Ok(result) => result,
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = deregistered {
+                log::error!("Failed to deregister project {}: {:?}", project_id, e);
+                failed.push(project_id);
+            }
+            tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+        }
+        if !failed.is_empty() {
+            return Err(TextEgressError::DeviceNotRegistered(format!(
+                "failed to deregister projects {}",
+                failed.join(", ")
+            )));
+        }
+        Ok(())
+    }
+}
+
+fn drain(project_id: String, addr: Addr<SessionListenerActor>, timeout: Duration) {
+    actix::spawn(async move {
+        match addr.send(ProjectMessages::Drain { timeout }).await {
+            Ok(Ok(_)) => log::info!("Project {} drained", project_id),
+            Ok(Err(e)) => log::error!("Failed to drain project {}: {:?}", project_id, e),
+            Err(e) => log::error!("Failed to drain project {}: {:?}", project_id, e),
+        }
+    });
+}
+
+fn retry_delay(attempts: u32) -> Duration {
+    RETRY_INITIAL_DELAY
+        .saturating_mul(2u32.saturating_pow(attempts.saturating_sub(1)))
+        .min(RETRY_MAX_DELAY)
+}
+
+fn settings(config: &TextEgressConfig) -> TextEgressConfig {
+    TextEgressConfig {
+        projects: vec![],
+        ..config.clone()
+    }
+}
+
+/// Detects changes to the config file by its modification time.
+#[derive(Debug)]
+pub struct ConfigFileWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+}
+
+impl ConfigFileWatcher {
+    pub fn new(path: PathBuf) -> Self {
+        let modified = modified_at(&path);
+        ConfigFileWatcher { path, modified }
+    }
+
+    /// Whether the file changed since the last call.
+    pub fn changed(&mut self) -> bool {
+        let modified = modified_at(&self.path);
+        if modified == self.modified {
+            return false;
+        }
+        self.modified = modified;
+        true
+    }
+}
+
+fn modified_at(path: &PathBuf) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use tempdir::TempDir;
+
+    #[test]
+    fn backs_off_retries_up_to_the_maximum() {
+        assert_eq!(retry_delay(1), RETRY_INITIAL_DELAY);
+        assert_eq!(retry_delay(2), RETRY_INITIAL_DELAY * 2);
+        assert_eq!(retry_delay(4), RETRY_INITIAL_DELAY * 8);
+        assert_eq!(retry_delay(10), RETRY_MAX_DELAY);
+        assert_eq!(retry_delay(u32::MAX), RETRY_MAX_DELAY);
+    }
+
+    #[test]
+    fn detects_config_file_changes() {
+        let dir = TempDir::new("reload").unwrap();
+        let path = dir.path().join("config.toml");
+        let mut watcher = ConfigFileWatcher::new(path.clone());
+        assert!(!watcher.changed());
+
+        let file = File::create(&path).unwrap();
+        assert!(watcher.changed());
+        assert!(!watcher.changed());
+
+        let later = SystemTime::now() + Duration::from_secs(10);
+        file.set_modified(later).unwrap();
+        assert!(watcher.changed());
+        assert!(!watcher.changed());
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(watcher.changed());
+    }
+}
@@ -1,18 +1,34 @@
+use crate::buffered_writer::{BufferedWriter, BufferingConfig, WriterReport};
+use crate::config::RecordingConfig;
+use crate::consent::{ConsentTracker, ConsentTransition};
 use crate::error_messages::TextEgressError;
-use crate::session_listener_actor::{RoomListenerUpdates, SessionListenerActor};
-use actix::{Actor, Addr, AsyncContext, Context, Handler, Message};
-use livekit::{Room, RoomEvent};
+use crate::participants::ParticipantPolicy;
+use crate::recording::read_recording;
+use crate::redaction::{RedactionReport, Redactor};
+use crate::room_source::{ConnectedRoom, RoomEvents, RoomSource, RoomSourceEvent};
+use crate::secrets::Secret;
+use crate::session_listener_actor::{JoinTokenRequest, RoomListenerUpdates, SessionListenerActor};
+use crate::supervision::ReconnectConfig;
+use crate::timestamps::{self, RECORD_FORMAT};
+use actix::{
+    Actor, ActorContext, ActorFutureExt, Addr, AsyncContext, Context, Handler, Message, Recipient,
+    WrapFuture,
+};
+use chrono::{DateTime, Utc};
+use livekit::DisconnectReason;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use tempdir::TempDir;
 use tokio::fs::File;
 use tokio::fs::OpenOptions;
 use tokio::io::AsyncWriteExt;
 use tokio::sync::{
-    mpsc,
     oneshot::{channel, Receiver as OneshotReceiver, Sender},
+    Mutex,
 };
+use tracing::{Instrument, Span};
 
 // FixMe: This needs a proper refactoring for various reasons:
 // 1. The listen to room events function is too long/complex
@@ -23,26 +39,16 @@ use tokio::sync::{
 #[rtype(result = "()")]
 pub enum RoomListenerMessages {
     StartListening {
-        join_token: String,
+        session_id: String,
+        join_token: Secret,
         server_url: String,
         room_name: String,
         topic: Option<String>,
+        span: Span,
     },
-    #[allow(dead_code)]
     StopListening,
 }
 
-pub(crate) async fn join_room(
-    server_url: &str,
-    access_token_str: &str,
-) -> Result<(Room, mpsc::UnboundedReceiver<RoomEvent>), TextEgressError> {
-    #[allow(unused_mut)]
-    let (room, mut room_events) =
-        Room::connect(server_url, access_token_str, Default::default()).await?;
-
-    Ok((room, room_events))
-}
-
 pub async fn create_file(identity: &str, root: &Path) -> Result<(File, String), TextEgressError> {
     let file_name = format!("{}.txt", identity);
     let handler = OpenOptions::new()
@@ -56,20 +62,264 @@ pub async fn create_file(identity: &str, root: &Path) -> Result<(File, String),
 
 pub struct RoomListenerActor {
     pub egress_id: String,
-    pub parent_addr: Addr<SessionListenerActor>,
+    parent: RoomListenerParent,
+    room_source: Arc<dyn RoomSource>,
     cancel_sender: Option<Sender<()>>,
+    recording_config: RecordingConfig,
+    work_dir: PathBuf,
+    /// The room to rejoin when the actor is restarted
+    room: Option<RoomConnection>,
+    recording: SharedRecording,
+    restarts: u32,
+    stop_requested: bool,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Where a room listener reports its progress and asks for join tokens.
+#[derive(Clone)]
+pub(crate) struct RoomListenerParent {
+    pub updates: Recipient<RoomListenerUpdates>,
+    pub join_tokens: Recipient<JoinTokenRequest>,
+}
+
+impl From<Addr<SessionListenerActor>> for RoomListenerParent {
+    fn from(addr: Addr<SessionListenerActor>) -> Self {
+        RoomListenerParent {
+            updates: addr.clone().recipient(),
+            join_tokens: addr.recipient(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl RoomListenerParent {
+    /// Starts a stand-in for the session listener that hands the updates to a test
+    /// and answers join token requests with a fixed token.
+    pub(crate) fn forwarding_to(
+        updates: tokio::sync::mpsc::UnboundedSender<RoomListenerUpdates>,
+    ) -> Self {
+        let parent = TestParent { updates }.start();
+        RoomListenerParent {
+            updates: parent.clone().recipient(),
+            join_tokens: parent.recipient(),
+        }
+    }
+}
+
+#[cfg(test)]
+struct TestParent {
+    updates: tokio::sync::mpsc::UnboundedSender<RoomListenerUpdates>,
+}
+
+#[cfg(test)]
+impl Actor for TestParent {
+    type Context = Context<Self>;
+}
+
+#[cfg(test)]
+impl Handler<RoomListenerUpdates> for TestParent {
+    type Result = Result<(), TextEgressError>;
+
+    fn handle(&mut self, msg: RoomListenerUpdates, _ctx: &mut Self::Context) -> Self::Result {
+        let _ = self.updates.send(msg);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl Handler<JoinTokenRequest> for TestParent {
+    type Result = Result<Secret, TextEgressError>;
+
+    fn handle(&mut self, _msg: JoinTokenRequest, _ctx: &mut Self::Context) -> Self::Result {
+        Ok(Secret::new("renewed-token"))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct RoomConnection {
+    session_id: String,
+    join_token: Secret,
+    server_url: String,
+    room_name: String,
+    topic: Option<String>,
+    span: Span,
+}
+
+/// Recording state that outlives a connection to the room, so that a restarted
+/// listener continues the same egress.
+#[derive(Debug)]
+pub(crate) struct RoomRecording {
+    temp_dir: PathBuf,
+    metadata: TextEgressMetadata,
+    /// Closed segments and files of previous connections
+    closed_files: Vec<DataEgressResultFiles>,
+    /// Files of the current connection, recovered if it ends without closing them
+    open_files: HashMap<String, DataEgressResultFiles>,
+    next_segments: HashMap<String, u32>,
+    redaction_report: Option<RedactionReport>,
+    consent: ConsentTracker,
+    /// When the connection was lost (ns), and why
+    interruption: Option<(i64, String)>,
+}
+
+/// Empty until the room is first joined.
+pub(crate) type SharedRecording = Arc<Mutex<Option<RoomRecording>>>;
+
+impl RoomRecording {
+    #[allow(clippy::too_many_arguments)]
+    async fn start(
+        room_name: &str,
+        topic: Option<String>,
+        joined_at: i64,
+        recording: &RecordingConfig,
+        pseudonymized: bool,
+        server_url: &str,
+        work_dir: &Path,
+    ) -> Result<Self, TextEgressError> {
+        let room_created_at =
+            match timestamps::room_created_at(&recording.timestamps, server_url, room_name)
+                .instrument(tracing::info_span!("room_created_at"))
+                .await
+            {
+                Ok(room_created_at) => room_created_at,
+                Err(e) => {
+                    log::warn!(
+                        "Failed to look up creation time of room {:?}: {:?}",
+                        room_name,
+                        e
+                    );
+                    None
+                }
+            };
+        let temp_dir = TempDir::new_in(work_dir, room_name)?.into_path();
+
+        Ok(RoomRecording {
+            temp_dir,
+            metadata: TextEgressMetadata {
+                room_name: room_name.to_string(),
+                topic,
+                started_at: chrono::Utc::now().timestamp(),
+                ended_at: None,
+                room_created_at,
+                joined_at,
+                offset_origin: room_created_at.unwrap_or(joined_at),
+                record_format: RECORD_FORMAT.to_string(),
+                redaction: None,
+                pseudonymized,
+                consent_transitions: None,
+                writers: vec![],
+                gaps: vec![],
+                error: None,
+            },
+            closed_files: vec![],
+            open_files: HashMap::new(),
+            next_segments: HashMap::new(),
+            redaction_report: None,
+            consent: ConsentTracker::new(&recording.consent),
+            interruption: None,
+        })
+    }
+
+    /// Notes when and why the connection was lost, recorded as a gap once the room is rejoined.
+    fn interrupt(&mut self, reason: &str) {
+        self.interruption
+            .get_or_insert((now_ns(), reason.to_string()));
+    }
+
+    /// Moves files that a connection left open, such as after a panic, to the closed files,
+    /// counting their messages from disk.
+    fn recover_open_files(&mut self) {
+        for (_, mut file) in self.open_files.drain() {
+            match read_recording(Path::new(&file.file_path)) {
+                Ok((_, summary)) => {
+                    file.message_count = summary.message_count;
+                    file.first_message_at = summary.first_message_at;
+                    file.last_message_at = summary.last_message_at;
+                }
+                Err(e) => log::warn!("Failed to read {}: {:?}", file.file_path, e),
+            }
+            self.closed_files.push(file);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct DataEgressResultFiles {
     pub participant: String,
     pub file_path: String,
+    pub topic: Option<String>,
+    pub message_count: usize,
+    pub first_message_at: Option<i64>,
+    pub last_message_at: Option<i64>,
+    /// Index of the file among the participant's segments
+    #[serde(default)]
+    pub segment: u32,
 }
 
 #[derive(Debug)]
 pub struct FileHandler {
-    pub file: File,
+    pub writer: BufferedWriter,
     pub path: String,
+    pub segment: u32,
+    pub opened_at: DateTime<Utc>,
+}
+
+impl FileHandler {
+    pub fn new(file: File, path: String, segment: u32, buffering: &BufferingConfig) -> Self {
+        FileHandler {
+            writer: BufferedWriter::spawn(file, &path, buffering),
+            path,
+            segment,
+            opened_at: Utc::now(),
+        }
+    }
+
+    /// The file as a result, counting only the messages the writer has written so far.
+    pub fn result_file(&self, participant: &str, topic: Option<String>) -> DataEgressResultFiles {
+        let metrics = self.writer.metrics();
+        DataEgressResultFiles {
+            participant: participant.to_string(),
+            file_path: self.path.clone(),
+            topic,
+            message_count: metrics.written as usize,
+            first_message_at: metrics.first_written_at,
+            last_message_at: metrics.last_written_at,
+            segment: self.segment,
+        }
+    }
+
+    /// Drains and closes the writer, returning the file and any spill sidecar as results.
+    pub async fn close(
+        self,
+        participant: &str,
+        topic: Option<String>,
+    ) -> (Vec<DataEgressResultFiles>, Option<WriterReport>) {
+        let result = self.result_file(participant, topic.clone());
+        match self.writer.close().await {
+            Ok(report) => {
+                let mut results = vec![DataEgressResultFiles {
+                    message_count: report.metrics.written as usize,
+                    first_message_at: report.metrics.first_written_at,
+                    last_message_at: report.metrics.last_written_at,
+                    ..result
+                }];
+                if let Some(spill_path) = &report.spill_path {
+                    results.push(DataEgressResultFiles {
+                        participant: participant.to_string(),
+                        file_path: spill_path.clone(),
+                        topic,
+                        message_count: report.metrics.spilled as usize,
+                        segment: self.segment,
+                        ..Default::default()
+                    });
+                }
+                (results, Some(report))
+            }
+            Err(e) => {
+                log::error!("Failed to close writer for {}: {:?}", self.path, e);
+                (vec![result], None)
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -78,6 +328,51 @@ pub struct TextEgressMetadata {
     pub topic: Option<String>,
     pub started_at: i64,
     pub ended_at: Option<i64>,
+    /// Room creation time (ns), when LiveKit API credentials are configured
+    pub room_created_at: Option<i64>,
+    /// Time the egress joined the room (ns)
+    pub joined_at: i64,
+    /// Origin of the offset column, the room creation time if known, else the join time (ns)
+    pub offset_origin: i64,
+    pub record_format: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redaction: Option<RedactionReport>,
+    #[serde(default)]
+    pub pseudonymized: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub consent_transitions: Option<Vec<ConsentTransition>>,
+    #[serde(default)]
+    pub writers: Vec<WriterReport>,
+    /// Times the listener lost its connection and rejoined the room
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub gaps: Vec<RecordingGap>,
+    /// Why recording ended early, when the room could not be rejoined
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Messages sent between these times were not recorded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordingGap {
+    /// When the connection was lost (ns)
+    pub disconnected_at: i64,
+    /// When the room was joined again (ns)
+    pub reconnected_at: i64,
+    #[serde(default)]
+    pub kind: GapKind,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GapKind {
+    /// The SDK resumed the connection, the files stayed open
+    Resumed,
+    /// The room was joined again within the grace period, the files stayed open
+    Rejoined,
+    /// The listener was restarted, the files continue as new segments
+    #[default]
+    Restarted,
 }
 
 impl Actor for RoomListenerActor {
@@ -91,13 +386,169 @@ impl Actor for RoomListenerActor {
     }
 }
 
+impl actix::Supervised for RoomListenerActor {
+    fn restarting(&mut self, ctx: &mut Self::Context) {
+        self.restarts += 1;
+        let delay = self
+            .recording_config
+            .restart
+            .delay(self.restarts)
+            .unwrap_or_default();
+        log::info!(
+            "Restarting room listener of egress {} in {:?} (restart {} of {})",
+            self.egress_id,
+            delay,
+            self.restarts,
+            self.recording_config.restart.max_restarts
+        );
+        ctx.run_later(delay, |act, ctx| act.listen(ctx));
+    }
+}
+
 impl RoomListenerActor {
-    pub fn new(egress_id: &str, parent_addr: Addr<SessionListenerActor>) -> Self {
+    pub(crate) fn new(
+        egress_id: &str,
+        parent: RoomListenerParent,
+        recording_config: &RecordingConfig,
+        work_dir: &Path,
+        room_source: Arc<dyn RoomSource>,
+    ) -> Self {
         RoomListenerActor {
             egress_id: egress_id.to_string(),
-            parent_addr,
+            parent,
+            room_source,
+            recording_config: recording_config.clone(),
+            work_dir: work_dir.to_path_buf(),
             cancel_sender: None,
+            room: None,
+            recording: SharedRecording::default(),
+            restarts: 0,
+            stop_requested: false,
+        }
+    }
+
+    /// Joins the room, running the connection in its own task so that a panic ends
+    /// the connection rather than the actor.
+    fn listen(&mut self, ctx: &mut Context<Self>) {
+        let Some(room) = self.room.clone() else {
+            return;
+        };
+        if self.stop_requested {
+            self.finish(ctx, None);
+            return;
+        }
+        let egress_id = self.egress_id.clone();
+        let parent = self.parent.clone();
+        let room_source = self.room_source.clone();
+        let recording_config = self.recording_config.clone();
+        let work_dir = self.work_dir.clone();
+        let recording = self.recording.clone();
+        let (tx, mut rx) = channel::<()>();
+        self.cancel_sender = Some(tx);
+
+        let span = room.span.clone();
+        let connection = actix::spawn(
+            async move {
+                listen_to_room_data_channels(
+                    &room,
+                    &egress_id,
+                    &mut rx,
+                    parent,
+                    &recording_config,
+                    &work_dir,
+                    &recording,
+                    room_source.as_ref(),
+                )
+                .instrument(tracing::info_span!("recording"))
+                .await
+            }
+            .instrument(span),
+        );
+        let recording = self.recording.clone();
+        let outcome = async move {
+            match connection.await {
+                Ok(outcome) => outcome,
+                Err(e) => {
+                    let reason = format!("room listener task failed: {}", e);
+                    if let Some(state) = recording.lock().await.as_mut() {
+                        state.interrupt(&reason);
+                    }
+                    ListenOutcome::Interrupted(reason)
+                }
+            }
+        };
+        ctx.spawn(
+            outcome
+                .into_actor(self)
+                .map(|outcome, act, ctx| act.connection_ended(outcome, ctx)),
+        );
+    }
+
+    fn connection_ended(&mut self, outcome: ListenOutcome, ctx: &mut Context<Self>) {
+        self.cancel_sender = None;
+        let reason = match outcome {
+            ListenOutcome::Finished => {
+                self.room = None;
+                return;
+            }
+            ListenOutcome::Interrupted(reason) => reason,
+        };
+        if self.stop_requested {
+            self.finish(ctx, None);
+        } else if self
+            .recording_config
+            .restart
+            .delay(self.restarts + 1)
+            .is_some()
+        {
+            log::warn!(
+                "Room listener of egress {} was interrupted: {}",
+                self.egress_id,
+                reason
+            );
+            // The supervisor restarts the actor, which rejoins the room
+            ctx.stop();
+        } else {
+            log::error!(
+                "Room listener of egress {} was interrupted {} times, giving up: {}",
+                self.egress_id,
+                self.restarts + 1,
+                reason
+            );
+            self.finish(ctx, Some(reason));
+        }
+    }
+
+    /// Hands over what was recorded while no connection is running.
+    fn finish(&mut self, ctx: &mut Context<Self>, error: Option<String>) {
+        let Some(room) = self.room.take() else {
+            return;
+        };
+        let egress_id = self.egress_id.clone();
+        let parent = self.parent.clone();
+        let recording = self.recording.clone();
+        let fut = async move {
+            match recording.lock().await.as_mut() {
+                Some(state) => finish_recording(state, &egress_id, &parent, error).await,
+                None => match error {
+                    Some(error) => parent.updates.do_send(RoomListenerUpdates::Failed {
+                        egress_id,
+                        error: TextEgressError::RoomListenerError(error),
+                        stopped: true,
+                        span: Span::current(),
+                    }),
+                    None => parent.updates.do_send(RoomListenerUpdates::Stopped {
+                        egress_id,
+                        files: vec![],
+                        room_name: room.room_name,
+                        topic: room.topic,
+                        span: Span::current(),
+                    }),
+                },
+            }
         }
+        .instrument(room.span);
+        ctx.spawn(actix::fut::wrap_future(fut));
     }
 }
 
@@ -108,127 +559,271 @@ impl Handler<RoomListenerMessages> for RoomListenerActor {
         log::info!("Received message: {:?}", &msg);
         match msg {
             RoomListenerMessages::StartListening {
+                session_id,
                 server_url,
                 join_token,
                 room_name,
                 topic,
+                span,
             } => {
                 log::info!(
                     "Starting to listen to room data channels for room: {:?}",
                     room_name
                 );
-                let rname = room_name.clone();
-                let topic = topic.clone();
-                let egress_id = self.egress_id.clone();
-                let parent_addr = self.parent_addr.clone();
-                let (tx, mut rx) = channel::<()>();
-                self.cancel_sender = Some(tx);
-
-                let fut = async move {
-                    listen_to_room_data_channels(
-                        &join_token,
-                        &server_url,
-                        &rname,
-                        &egress_id,
-                        topic,
-                        &mut rx,
-                        parent_addr,
-                    )
-                    .await;
-                };
-                ctx.spawn(actix::fut::wrap_future(fut));
+                let span = tracing::info_span!(
+                    parent: &span,
+                    "room_egress",
+                    egress_id = %self.egress_id,
+                    room_name = %room_name
+                );
+                self.room = Some(RoomConnection {
+                    session_id,
+                    join_token,
+                    server_url,
+                    room_name,
+                    topic,
+                    span,
+                });
+                self.listen(ctx);
             }
             RoomListenerMessages::StopListening => {
                 log::info!(
                     "Stopping listening to room data channels {:?}",
                     &self.cancel_sender
                 );
+                self.stop_requested = true;
                 if let Some(sender) = self.cancel_sender.take() {
                     log::info!("Stopping listening to room data channels");
                     let _ = sender.send(());
+                } else {
+                    // Waiting to rejoin the room
+                    self.finish(ctx, None);
                 }
             }
         }
     }
 }
 
+/// How a connection to the room ended.
+#[derive(Debug)]
+pub(crate) enum ListenOutcome {
+    /// The egress was stopped, the room ended or the recording could not be set up
+    Finished,
+    /// The connection was lost, and the room can be joined again
+    Interrupted(String),
+}
+
+enum Rejoin {
+    Joined(Box<dyn ConnectedRoom>, RoomEvents),
+    Cancelled,
+    TimedOut,
+}
+
+/// Asks the session listener for a token that is not about to expire, keeping the
+/// current one if that fails.
+async fn refresh_join_token(
+    connection: &RoomConnection,
+    join_token: &mut Secret,
+    parent: &RoomListenerParent,
+    renew: bool,
+) {
+    let token_request = JoinTokenRequest {
+        session_id: connection.session_id.clone(),
+        room_name: connection.room_name.clone(),
+        renew,
+    };
+    match parent.join_tokens.send(token_request).await {
+        Ok(Ok(token)) => *join_token = token,
+        Ok(Err(e)) => log::warn!("Failed to get a new join token: {:?}", e),
+        Err(e) => log::warn!("Failed to get a new join token: {:?}", e),
+    }
+}
+
+/// Joins the room again while the files stay open, until the grace period ends.
+/// Attempts after the first renew the token, in case it was refused.
+async fn rejoin_room(
+    connection: &RoomConnection,
+    join_token: &mut Secret,
+    parent: &RoomListenerParent,
+    reconnect: &ReconnectConfig,
+    cancel_receiver: &mut OneshotReceiver<()>,
+    room_source: &dyn RoomSource,
+) -> Rejoin {
+    let deadline = tokio::time::Instant::now() + reconnect.grace_period();
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        refresh_join_token(connection, join_token, parent, attempt > 1).await;
+        log::info!(
+            "Rejoining room {:?} (attempt {})",
+            connection.room_name,
+            attempt
+        );
+
+        tokio::select! {
+            _ = &mut *cancel_receiver => return Rejoin::Cancelled,
+            _ = tokio::time::sleep_until(deadline) => return Rejoin::TimedOut,
+            joined = room_source.join(&connection.server_url, join_token.expose()) => match joined {
+                Ok((room, room_events)) => return Rejoin::Joined(room, room_events),
+                Err(e) => log::warn!("Failed to rejoin room {:?}: {:?}", connection.room_name, e),
+            },
+        }
+        tokio::select! {
+            _ = &mut *cancel_receiver => return Rejoin::Cancelled,
+            _ = tokio::time::sleep_until(deadline) => return Rejoin::TimedOut,
+            _ = tokio::time::sleep(reconnect.retry_interval()) => {}
+        }
+    }
+}
+
+fn now_ns() -> i64 {
+    chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default()
+}
+
+/// Disconnects after which the room is not joined again.
+fn room_ended(reason: DisconnectReason) -> bool {
+    matches!(
+        reason,
+        DisconnectReason::ClientInitiated
+            | DisconnectReason::DuplicateIdentity
+            | DisconnectReason::ParticipantRemoved
+            | DisconnectReason::RoomDeleted
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn listen_to_room_data_channels(
-    join_token: &str,
-    server_url: &str,
-    room_name: &str,
+    connection: &RoomConnection,
     egress_id: &str,
-    topic: Option<String>,
     cancel_receiver: &mut OneshotReceiver<()>,
-    parent_addr: Addr<SessionListenerActor>,
-) {
+    parent: RoomListenerParent,
+    recording: &RecordingConfig,
+    work_dir: &Path,
+    shared_recording: &SharedRecording,
+    room_source: &dyn RoomSource,
+) -> ListenOutcome {
+    let server_url = connection.server_url.as_str();
+    let room_name = connection.room_name.as_str();
+    let topic = connection.topic.clone();
+    let mut join_token = connection.join_token.clone();
     log::info!("Listening to room data channels for room: {:?}", room_name);
 
-    parent_addr.do_send(RoomListenerUpdates::Started {
-        egress_id: egress_id.to_string(),
-        room_name: room_name.to_string(),
-        files: vec![],
-        topic: topic.clone(),
-    });
-
-    let room_join_result = join_room(server_url, join_token).await;
-
-    let (room, mut room_events) = match room_join_result {
-        Ok((room, room_events)) => (room, room_events),
+    let (redactor, participant_policy) = match recording_policies(recording) {
+        Ok(policies) => policies,
         Err(e) => {
-            log::error!("Failed to join room: {:?}", e);
-            parent_addr.do_send(RoomListenerUpdates::Failed {
+            log::error!("Failed to build recording policies: {:?}", e);
+            parent.updates.do_send(RoomListenerUpdates::Failed {
                 egress_id: egress_id.to_string(),
                 error: e,
+                stopped: true,
+                span: Span::current(),
             });
-            return;
+            return ListenOutcome::Finished;
         }
     };
 
-    let mut per_participant_files: HashMap<String, FileHandler> = HashMap::new();
-    let temp_dir = match TempDir::new(room_name) {
-        Ok(temp_dir) => temp_dir.into_path(),
+    // Held while connected, and released by the unwinding if the connection panics
+    let mut recording_state = shared_recording.lock().await;
+    if recording_state.is_none() {
+        parent.updates.do_send(RoomListenerUpdates::Started {
+            egress_id: egress_id.to_string(),
+            room_name: room_name.to_string(),
+            files: vec![],
+            topic: topic.clone(),
+            span: Span::current(),
+        });
+    }
+
+    // The token of a restarted listener may have expired
+    refresh_join_token(connection, &mut join_token, &parent, false).await;
+    let room_join_result = room_source
+        .join(server_url, join_token.expose())
+        .instrument(tracing::info_span!("join_room"))
+        .await;
+
+    let (mut room, mut room_events) = match room_join_result {
+        Ok((room, room_events)) => (room, room_events),
         Err(e) => {
-            parent_addr.do_send(RoomListenerUpdates::Failed {
-                egress_id: egress_id.to_string(),
-                error: e.into(),
-            });
-            return;
+            log::error!("Failed to join room: {:?}", e);
+            if let Some(state) = recording_state.as_mut() {
+                state.interrupt(&e.to_string());
+            }
+            return ListenOutcome::Interrupted(e.to_string());
         }
     };
+    let joined_at = chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default();
 
-    let mut to_listen = topic;
-    let mut metadata = TextEgressMetadata {
-        room_name: room_name.to_string(),
-        topic: to_listen.clone(),
-        started_at: chrono::Utc::now().timestamp(),
-        ended_at: None,
+    if recording_state.is_none() {
+        let started = RoomRecording::start(
+            room_name,
+            topic.clone(),
+            joined_at,
+            recording,
+            participant_policy.is_pseudonymized(),
+            server_url,
+            work_dir,
+        )
+        .await;
+        match started {
+            Ok(state) => *recording_state = Some(state),
+            Err(e) => {
+                parent.updates.do_send(RoomListenerUpdates::Failed {
+                    egress_id: egress_id.to_string(),
+                    error: e,
+                    stopped: true,
+                    span: Span::current(),
+                });
+                return ListenOutcome::Finished;
+            }
+        }
+    }
+    let Some(state) = recording_state.as_mut() else {
+        return ListenOutcome::Finished;
     };
-    let mut participant_disconnected_files = vec![];
+    state.recover_open_files();
+    if let Some((disconnected_at, reason)) = state.interruption.take() {
+        log::info!(
+            "Rejoined room {:?}, files continue as new segments",
+            room_name
+        );
+        state.metadata.gaps.push(RecordingGap {
+            disconnected_at,
+            reconnected_at: joined_at,
+            kind: GapKind::Restarted,
+            reason,
+        });
+    }
+    let offset_origin = state.metadata.offset_origin;
 
-    println!("Listening to room data channels for room: {:?}", room_name);
+    let mut per_participant_files: HashMap<String, FileHandler> = HashMap::new();
+    let to_listen = topic;
+    // When the connection dropped (ns), while the SDK resumes it
+    let mut reconnecting_since: Option<i64> = None;
 
-    loop {
+    log::info!("Listening to room data channels for room: {:?}", room_name);
+
+    let outcome = loop {
         tokio::select! {
             _ = &mut *cancel_receiver => {
                 log::info!("Cancelling listening to room data channels");
                 // leave the room
-                let _ = room.close().await;
-                break;
+                room.close().await;
+                break ListenOutcome::Finished;
             }
-            Some(event) = room_events.recv() => {
+            event = room_events.recv() => {
+                let Some(event) = event else {
+                    break ListenOutcome::Interrupted("room events ended".to_string());
+                };
                 match event {
-                    RoomEvent::DataReceived {
+                    RoomSourceEvent::DataReceived {
                         payload,
                         participant,
-                        kind: _,
                         topic,
                     } => {
-                        println!("Data received from participant: {:?}, payload: {:?}", participant, payload);
                         let timestamp = chrono::Utc::now();
                         let timestamp_str_iso = timestamp.format("%Y-%m-%dT%H:%M:%S%Z");
                         // let timestamp_iso =
                         let timestamp_ns = timestamp.timestamp_nanos_opt().unwrap_or_default();
-                        let payload_str = format!("{}|{}|{}\n", timestamp_str_iso, timestamp_ns, String::from_utf8_lossy(&payload));
                         if let Some(participant) = participant {
                             if let (Some(topic), Some(to_listen)) = (&topic, &to_listen) {
                                 log::info!("Comparing topics: {:?} and {:?}", topic, to_listen);
@@ -237,147 +832,607 @@ pub(crate) async fn listen_to_room_data_channels(
                                 }
                             }
 
-                            let topic_prefix = to_listen.take().unwrap_or_else(|| "all-topics".to_string());
-                            let filepath = format!(
-                                "{}-{}-{}",
-                                participant.identity().as_str(),
-                                topic_prefix,
-                                chrono::Utc::now().format("%Y-%m-%dT%H:%M:%S%Z")
+                            if !participant_policy.should_record(&participant.identity, participant.kind) {
+                                continue;
+                            }
+                            let participant_alias = match participant_policy.alias(&participant.identity) {
+                                Ok(alias) => alias,
+                                Err(e) => {
+                                    log::error!("Failed to pseudonymize participant identity: {:?}", e);
+                                    parent.updates.do_send(RoomListenerUpdates::Failed {
+                                        egress_id: egress_id.to_string(),
+                                        error: e,
+                                        stopped: false,
+                                        span: Span::current(),
+                                    });
+                                    continue;
+                                }
+                            };
+                            if !state.consent.update(&participant_alias, &participant.attributes, &participant.metadata) {
+                                continue;
+                            }
+
+                            let text = String::from_utf8_lossy(&payload);
+                            let sender_timestamp_ns = timestamps::sender_timestamp_ns(&text, &recording.timestamps.sender_timestamp_key)
+                                .map(|ns| ns.to_string())
+                                .unwrap_or_default();
+                            let text = match &redactor {
+                                Some(redactor) => redactor.redact(&text, state.redaction_report.get_or_insert_with(Default::default)),
+                                None => text.into_owned(),
+                            };
+                            let payload_str = format!(
+                                "{}|{}|{}|{}|{}\n",
+                                timestamp_str_iso,
+                                timestamp_ns,
+                                timestamp_ns - offset_origin,
+                                sender_timestamp_ns,
+                                text
                             );
 
-                            if !per_participant_files.contains_key(participant.identity().as_str()) {
-                                match create_file(&filepath, &temp_dir).await {
+                            let should_rotate = per_participant_files.get(&participant_alias).is_some_and(|handle| {
+                                recording.rotation.should_rotate(handle.writer.pending_size(), handle.opened_at, timestamp, payload_str.len() as u64)
+                            });
+                            if should_rotate {
+                                if let Some(closed) = per_participant_files.remove(&participant_alias) {
+                                    log::info!("Rotating segment {} for participant: {:?}", closed.segment, participant_alias);
+                                    state.open_files.remove(&participant_alias);
+                                    let (segment_files, report) = closed.close(&participant_alias, to_listen.clone()).await;
+                                    for segment_file in segment_files {
+                                        if recording.rotation.upload_segments {
+                                            parent.updates.do_send(RoomListenerUpdates::SegmentCompleted {
+                                                egress_id: egress_id.to_string(),
+                                                file: segment_file.clone(),
+                                                span: Span::current(),
+                                            });
+                                        }
+                                        state.closed_files.push(segment_file);
+                                    }
+                                    state.metadata.writers.extend(report);
+                                }
+                            }
+
+                            if !per_participant_files.contains_key(&participant_alias) {
+                                let segment = state.next_segments.entry(participant_alias.clone()).or_default();
+                                let topic_prefix = to_listen.clone().unwrap_or_else(|| "all-topics".to_string());
+                                let mut filepath = format!(
+                                    "{}-{}-{}",
+                                    participant_alias,
+                                    topic_prefix,
+                                    chrono::Utc::now().format("%Y-%m-%dT%H:%M:%S%Z")
+                                );
+                                if *segment > 0 {
+                                    filepath = format!("{}-{}", filepath, segment);
+                                }
+                                match create_file(&filepath, &state.temp_dir).await {
                                     Ok((fh, fname)) => {
-                                        per_participant_files.insert(
-                                            participant.identity().into(),
-                                            FileHandler {
-                                                file: fh,
-                                                path: fname,
-                                            },
+                                        let file_handler = FileHandler::new(fh, fname, *segment, &recording.buffering);
+                                        *segment += 1;
+                                        state.open_files.insert(
+                                            participant_alias.clone(),
+                                            file_handler.result_file(&participant_alias, to_listen.clone()),
                                         );
-                                        parent_addr.do_send(RoomListenerUpdates::Updated {
+                                        per_participant_files.insert(participant_alias.clone(), file_handler);
+                                        parent.updates.do_send(RoomListenerUpdates::Updated {
                                             egress_id: egress_id.to_string(),
                                             room_name: room_name.to_string(),
                                             files: per_participant_files
                                             .iter()
-                                            .map(|(participant, file_handler)| DataEgressResultFiles {
-                                                participant: participant.to_string(),
-                                                file_path: file_handler.path.clone(),
-                                            })
+                                            .map(|(participant, file_handler)| file_handler.result_file(participant, to_listen.clone()))
                                             .collect(),
                                             topic: to_listen.clone(),
+                                            span: Span::current(),
                                         });
                                     },
                                     Err(e) => {
                                         log::error!("Failed to create file: {:?}", e);
-                                        parent_addr.do_send(RoomListenerUpdates::Failed {
+                                        parent.updates.do_send(RoomListenerUpdates::Failed {
                                             egress_id: egress_id.to_string(),
-                                            error: e
+                                            error: e,
+                                            stopped: false,
+                                            span: Span::current(),
                                         });
+                                        // The message is lost, creating the file is tried again on the next one
+                                        continue;
                                     }
                                 };
                             }
 
                             let handle = per_participant_files
-                                .get_mut(&participant.identity().to_string())
+                                .get_mut(&participant_alias)
                                 .unwrap();
-                            match handle.file.write_all(payload_str.as_ref()).await {
+                            match handle.writer.write(payload_str.as_bytes().to_vec(), timestamp_ns).await {
                                 Ok(_) => {
                                     log::debug!(
                                         "Data received from participant: {:?}, payload: {:?}",
-                                        participant.identity(),
+                                        participant_alias,
                                         payload_str
                                     );
                                 },
                                 Err(e) => {
                                     log::error!("Failed to write to file: {:?}", e);
-                                    parent_addr.do_send(RoomListenerUpdates::Failed {
+                                    parent.updates.do_send(RoomListenerUpdates::Failed {
                                         egress_id: egress_id.to_string(),
-                                        error: e.into()
+                                        error: e.into(),
+                                        stopped: false,
+                                        span: Span::current(),
                                     });
                                 }
                             }
                         }
                     },
-                    RoomEvent::ParticipantDisconnected(participant) => {
-                        let participant_id = participant.identity().to_string();
+                    RoomSourceEvent::ParticipantConnected(participant)
+                    | RoomSourceEvent::ParticipantUpdated(participant)
+                        if participant_policy.should_record(&participant.identity, participant.kind) =>
+                    {
+                        match participant_policy.alias(&participant.identity) {
+                            Ok(participant_alias) => {
+                                state.consent.update(&participant_alias, &participant.attributes, &participant.metadata);
+                            }
+                            Err(e) => log::error!("Failed to pseudonymize participant identity: {:?}", e),
+                        }
+                    },
+                    RoomSourceEvent::ParticipantDisconnected(participant) => {
+                        let Ok(participant_id) = participant_policy.alias(&participant.identity) else {
+                            continue;
+                        };
                         if let Some(file_handler) = per_participant_files.remove(&participant_id) {
                             log::info!("Participant disconnected: {:?}", participant_id);
-                            let _ = file_handler.file.sync_all().await;
-                            participant_disconnected_files.push(DataEgressResultFiles {
-                                participant: participant_id,
-                                file_path: file_handler.path,
-                            });
+                            state.open_files.remove(&participant_id);
+                            let (files, report) = file_handler.close(&participant_id, to_listen.clone()).await;
+                            state.closed_files.extend(files);
+                            state.metadata.writers.extend(report);
                         }
                     },
-                    RoomEvent::Disconnected { reason } => {
+                    RoomSourceEvent::Reconnecting => {
+                        log::warn!("Connection to room {:?} dropped, reconnecting", room_name);
+                        reconnecting_since.get_or_insert(now_ns());
+                    }
+                    RoomSourceEvent::Reconnected => {
+                        log::info!("Reconnected to room {:?}", room_name);
+                        if let Some(disconnected_at) = reconnecting_since.take() {
+                            state.metadata.gaps.push(RecordingGap {
+                                disconnected_at,
+                                reconnected_at: now_ns(),
+                                kind: GapKind::Resumed,
+                                reason: "connection resumed".to_string(),
+                            });
+                        }
+                    }
+                    RoomSourceEvent::Disconnected(reason) => {
                         log::info!("Disconnected from room {:?}", reason);
-                        break;
+                        if room_ended(reason) {
+                            break ListenOutcome::Finished;
+                        }
+                        let disconnected_at = reconnecting_since.take().unwrap_or_else(now_ns);
+                        let reason = format!("disconnected from room: {:?}", reason);
+                        match rejoin_room(connection, &mut join_token, &parent, &recording.reconnect, cancel_receiver, room_source).await {
+                            Rejoin::Joined(new_room, new_room_events) => {
+                                room = new_room;
+                                room_events = new_room_events;
+                                state.metadata.gaps.push(RecordingGap {
+                                    disconnected_at,
+                                    reconnected_at: now_ns(),
+                                    kind: GapKind::Rejoined,
+                                    reason,
+                                });
+                            }
+                            Rejoin::Cancelled => break ListenOutcome::Finished,
+                            Rejoin::TimedOut => break ListenOutcome::Interrupted(reason),
+                        }
                     }
                     _ => {}
                 }
             },
         }
+    };
+
+    for (participant, file_handler) in per_participant_files {
+        state.open_files.remove(&participant);
+        let (files, report) = file_handler.close(&participant, to_listen.clone()).await;
+        state.closed_files.extend(files);
+        state.metadata.writers.extend(report);
     }
 
-    let mut results: Vec<DataEgressResultFiles> = per_participant_files
-        .iter()
-        .map(|(participant, file_handler)| DataEgressResultFiles {
-            participant: participant.to_string(),
-            file_path: file_handler.path.clone(),
-        })
-        .collect();
+    if let ListenOutcome::Interrupted(reason) = &outcome {
+        log::warn!("Lost the connection to room {:?}: {}", room_name, reason);
+        state.interrupt(reason);
+        return outcome;
+    }
+    finish_recording(state, egress_id, &parent, None).await;
+    outcome
+}
 
-    results.extend(participant_disconnected_files);
+/// Writes the metadata and hands the recorded files over for upload.
+async fn finish_recording(
+    state: &mut RoomRecording,
+    egress_id: &str,
+    parent: &RoomListenerParent,
+    error: Option<String>,
+) {
+    state.recover_open_files();
+    let mut results = std::mem::take(&mut state.closed_files);
 
+    let metadata = &mut state.metadata;
     metadata.ended_at = Some(chrono::Utc::now().timestamp());
+    metadata.error = error;
+    metadata.redaction = state.redaction_report.take();
+    metadata.consent_transitions = state
+        .consent
+        .is_enabled()
+        .then(|| state.consent.transitions().to_vec());
 
-    let metadata_file = temp_dir.join("metadata.json");
-    match File::create(&metadata_file).await {
-        Ok(mut fh) => {
-            // Serialize metadata to file
-            let _ = match serde_json::to_string(&metadata) {
-                Ok(s) => fh
-                    .write_all(s.as_bytes())
-                    .await
-                    .map_err(|e| {
-                        log::error!("Failed to write metadata to file: {:?}", e);
-                        parent_addr.do_send(RoomListenerUpdates::Failed {
-                            egress_id: egress_id.to_string(),
-                            error: e.into(),
-                        });
-                    })
-                    .map(|_| {
-                        log::info!("Metadata written to file: {:?}", metadata_file);
-                        results.push(DataEgressResultFiles {
-                            participant: "metadata".to_string(),
-                            file_path: metadata_file.to_str().unwrap().to_string(),
-                        });
-                    }),
-                Err(e) => {
-                    log::error!("Failed to serialize metadata: {:?}", e);
-                    parent_addr.do_send(RoomListenerUpdates::Failed {
-                        egress_id: egress_id.to_string(),
-                        error: e.into(),
-                    });
-                    return;
-                }
-            };
-        }
+    match write_metadata(metadata, &state.temp_dir)
+        .instrument(tracing::info_span!("finalize"))
+        .await
+    {
+        Ok(metadata_file) => results.push(metadata_file),
         Err(e) => {
-            log::error!("Failed to create metadata file: {:?}", e);
-            parent_addr.do_send(RoomListenerUpdates::Failed {
+            parent.updates.do_send(RoomListenerUpdates::Failed {
                 egress_id: egress_id.to_string(),
-                error: e.into(),
+                error: e,
+                stopped: false,
+                span: Span::current(),
             });
         }
     };
 
     log::info!("Stopped listening to room data channels");
-    parent_addr.do_send(RoomListenerUpdates::Stopped {
+    parent.updates.do_send(RoomListenerUpdates::Stopped {
         egress_id: egress_id.to_string(),
         files: results,
-        room_name: room_name.to_string(),
-        topic: to_listen,
+        room_name: metadata.room_name.clone(),
+        topic: metadata.topic.clone(),
+        span: Span::current(),
     });
 }
+
+#[allow(clippy::result_large_err)]
+fn recording_policies(
+    recording: &RecordingConfig,
+) -> Result<(Option<Redactor>, ParticipantPolicy), TextEgressError> {
+    Ok((
+        Redactor::from_config(&recording.redaction)?,
+        ParticipantPolicy::from_config(recording)?,
+    ))
+}
+
+async fn write_metadata(
+    metadata: &TextEgressMetadata,
+    root: &Path,
+) -> Result<DataEgressResultFiles, TextEgressError> {
+    let metadata_file = root.join("metadata.json");
+    let serialized = serde_json::to_string(metadata).map_err(|e| {
+        log::error!("Failed to serialize metadata: {:?}", e);
+        e
+    })?;
+    let mut fh = File::create(&metadata_file).await.map_err(|e| {
+        log::error!("Failed to create metadata file: {:?}", e);
+        e
+    })?;
+    // Flushed, otherwise the write may still be in flight when the file is uploaded
+    fh.write_all(serialized.as_bytes()).await.map_err(|e| {
+        log::error!("Failed to write metadata to file: {:?}", e);
+        e
+    })?;
+    fh.flush().await.map_err(|e| {
+        log::error!("Failed to flush metadata file: {:?}", e);
+        e
+    })?;
+    log::info!("Metadata written to file: {:?}", metadata_file);
+
+    Ok(DataEgressResultFiles {
+        participant: "metadata".to_string(),
+        file_path: metadata_file.to_str().unwrap().to_string(),
+        topic: metadata.topic.clone(),
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recording::Record;
+    use crate::room_source::{RoomParticipant, ScriptedRoomSource};
+    use crate::supervision::RestartPolicy;
+    use actix::Supervisor;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    fn data(identity: &str, topic: Option<&str>, text: &str) -> RoomSourceEvent {
+        RoomSourceEvent::DataReceived {
+            participant: Some(RoomParticipant::new(identity)),
+            topic: topic.map(str::to_string),
+            payload: text.as_bytes().to_vec(),
+        }
+    }
+
+    fn room_deleted() -> RoomSourceEvent {
+        RoomSourceEvent::Disconnected(DisconnectReason::RoomDeleted)
+    }
+
+    /// Records the scripted room until the listener stops, returning the files it handed over.
+    async fn record(
+        source: &ScriptedRoomSource,
+        topic: Option<&str>,
+        recording: RecordingConfig,
+        work_dir: &Path,
+    ) -> Vec<DataEgressResultFiles> {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let parent = RoomListenerParent::forwarding_to(sender);
+        let room_source = Arc::new(source.clone());
+        let work_dir = work_dir.to_path_buf();
+        let listener = Supervisor::start(move |_| {
+            RoomListenerActor::new("egress", parent, &recording, &work_dir, room_source)
+        });
+        listener.do_send(RoomListenerMessages::StartListening {
+            session_id: "session".to_string(),
+            join_token: Secret::new("token"),
+            server_url: "ws://localhost:7880".to_string(),
+            room_name: "room".to_string(),
+            topic: topic.map(str::to_string),
+            span: Span::none(),
+        });
+
+        loop {
+            let update = tokio::time::timeout(Duration::from_secs(10), receiver.recv())
+                .await
+                .expect("the listener did not stop")
+                .expect("the listener dropped its parent");
+            match update {
+                RoomListenerUpdates::Stopped { files, .. } => return files,
+                RoomListenerUpdates::Failed { error, .. } => panic!("recording failed: {}", error),
+                _ => {}
+            }
+        }
+    }
+
+    fn participant_files<'a>(
+        files: &'a [DataEgressResultFiles],
+        participant: &str,
+    ) -> Vec<&'a DataEgressResultFiles> {
+        let mut files: Vec<_> = files
+            .iter()
+            .filter(|file| file.participant == participant)
+            .collect();
+        files.sort_by_key(|file| file.segment);
+        files
+    }
+
+    fn records(file: &DataEgressResultFiles) -> Vec<Record> {
+        let (records, summary) = read_recording(Path::new(&file.file_path)).unwrap();
+        assert!(summary.is_valid(), "{}", summary);
+        records
+    }
+
+    fn texts(file: &DataEgressResultFiles) -> Vec<String> {
+        records(file)
+            .into_iter()
+            .map(|record| record.text)
+            .collect()
+    }
+
+    fn metadata(files: &[DataEgressResultFiles]) -> TextEgressMetadata {
+        let [file] = participant_files(files, "metadata")[..] else {
+            panic!("expected a single metadata file");
+        };
+        serde_json::from_slice(&std::fs::read(&file.file_path).unwrap()).unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn records_only_the_configured_topic() {
+        let work_dir = TempDir::new("room-listener").unwrap();
+        let source = ScriptedRoomSource::new().connection(vec![
+            data("alice", Some("transcript"), "hello"),
+            data("alice", Some("chat"), "not recorded"),
+            data("bob", Some("chat"), "not recorded either"),
+            room_deleted(),
+        ]);
+
+        let files = record(
+            &source,
+            Some("transcript"),
+            RecordingConfig::default(),
+            work_dir.path(),
+        )
+        .await;
+
+        let alice = participant_files(&files, "alice");
+        assert_eq!(alice.len(), 1);
+        assert_eq!(texts(alice[0]), vec!["hello"]);
+        assert_eq!(alice[0].topic.as_deref(), Some("transcript"));
+        assert!(alice[0].file_path.contains("alice-transcript-"));
+        assert!(participant_files(&files, "bob").is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn records_every_topic_without_a_filter() {
+        let work_dir = TempDir::new("room-listener").unwrap();
+        let source = ScriptedRoomSource::new().connection(vec![
+            data("alice", Some("transcript"), "hello"),
+            data("alice", Some("chat"), "hi"),
+            data("alice", None, "no topic"),
+            room_deleted(),
+        ]);
+
+        let files = record(&source, None, RecordingConfig::default(), work_dir.path()).await;
+
+        let alice = participant_files(&files, "alice");
+        assert_eq!(alice.len(), 1);
+        assert_eq!(texts(alice[0]), vec!["hello", "hi", "no topic"]);
+        assert!(alice[0].file_path.contains("alice-all-topics-"));
+    }
+
+    #[actix_rt::test]
+    async fn creates_a_file_per_participant() {
+        let work_dir = TempDir::new("room-listener").unwrap();
+        let source = ScriptedRoomSource::new().connection(vec![
+            data("alice", None, "one"),
+            data("bob", None, "two"),
+            data("alice", None, "three"),
+            RoomSourceEvent::DataReceived {
+                participant: None,
+                topic: None,
+                payload: b"from the server".to_vec(),
+            },
+            room_deleted(),
+        ]);
+
+        let files = record(&source, None, RecordingConfig::default(), work_dir.path()).await;
+        let offset_origin = metadata(&files).offset_origin;
+
+        let alice = participant_files(&files, "alice");
+        let bob = participant_files(&files, "bob");
+        assert_eq!(files.len(), 3);
+        assert_eq!((alice.len(), bob.len()), (1, 1));
+        assert_eq!(alice[0].message_count, 2);
+        assert_eq!(bob[0].message_count, 1);
+        assert_eq!(texts(alice[0]), vec!["one", "three"]);
+        assert_eq!(texts(bob[0]), vec!["two"]);
+
+        let alice_records = records(alice[0]);
+        assert_eq!(
+            alice[0].first_message_at,
+            Some(alice_records[0].received_at_ns)
+        );
+        assert_eq!(
+            alice[0].last_message_at,
+            Some(alice_records[1].received_at_ns)
+        );
+        for record in alice_records {
+            assert_eq!(record.offset_ns, record.received_at_ns - offset_origin);
+            assert_eq!(record.sender_timestamp_ns, None);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn participant_disconnect_closes_their_file() {
+        let work_dir = TempDir::new("room-listener").unwrap();
+        let source = ScriptedRoomSource::new().connection(vec![
+            data("alice", None, "before"),
+            RoomSourceEvent::ParticipantDisconnected(RoomParticipant::new("alice")),
+            RoomSourceEvent::ParticipantDisconnected(RoomParticipant::new("bob")),
+            RoomSourceEvent::ParticipantConnected(RoomParticipant::new("alice")),
+            data("alice", None, "after"),
+            room_deleted(),
+        ]);
+
+        let files = record(&source, None, RecordingConfig::default(), work_dir.path()).await;
+
+        let alice = participant_files(&files, "alice");
+        assert_eq!(alice.len(), 2);
+        assert_eq!((alice[0].segment, alice[1].segment), (0, 1));
+        assert_eq!(texts(alice[0]), vec!["before"]);
+        assert_eq!(texts(alice[1]), vec!["after"]);
+        assert!(alice[1].file_path.ends_with("-1.txt"));
+        assert!(participant_files(&files, "bob").is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn writes_metadata() {
+        let work_dir = TempDir::new("room-listener").unwrap();
+        let source = ScriptedRoomSource::new().connection(vec![
+            data("alice", Some("transcript"), "hello"),
+            RoomSourceEvent::Reconnecting,
+            RoomSourceEvent::Reconnected,
+            data("bob", Some("transcript"), "hi"),
+            room_deleted(),
+        ]);
+
+        let files = record(
+            &source,
+            Some("transcript"),
+            RecordingConfig::default(),
+            work_dir.path(),
+        )
+        .await;
+        let metadata = metadata(&files);
+
+        assert_eq!(metadata.room_name, "room");
+        assert_eq!(metadata.topic.as_deref(), Some("transcript"));
+        assert_eq!(metadata.record_format, RECORD_FORMAT);
+        assert!(metadata
+            .ended_at
+            .is_some_and(|ended_at| ended_at >= metadata.started_at));
+        // Without LiveKit API credentials offsets are relative to the join
+        assert_eq!(metadata.room_created_at, None);
+        assert_eq!(metadata.offset_origin, metadata.joined_at);
+        assert!(!metadata.pseudonymized);
+        assert_eq!(metadata.writers.len(), 2);
+        assert_eq!(metadata.gaps.len(), 1);
+        assert_eq!(metadata.gaps[0].kind, GapKind::Resumed);
+        assert!(metadata.gaps[0].reconnected_at >= metadata.gaps[0].disconnected_at);
+        assert_eq!(metadata.error, None);
+    }
+
+    #[actix_rt::test]
+    async fn rejoins_the_room_with_the_files_open() {
+        let work_dir = TempDir::new("room-listener").unwrap();
+        let source = ScriptedRoomSource::new()
+            .connection(vec![
+                data("alice", None, "before"),
+                RoomSourceEvent::Disconnected(DisconnectReason::SignalClose),
+            ])
+            .connection(vec![data("alice", None, "after"), room_deleted()]);
+
+        let files = record(&source, None, RecordingConfig::default(), work_dir.path()).await;
+
+        assert_eq!(source.joins(), 2);
+        let alice = participant_files(&files, "alice");
+        assert_eq!(alice.len(), 1);
+        assert_eq!(texts(alice[0]), vec!["before", "after"]);
+        let gaps = metadata(&files).gaps;
+        assert_eq!(gaps.len(), 1);
+        assert_eq!(gaps[0].kind, GapKind::Rejoined);
+    }
+
+    fn restarting(max_restarts: u32) -> RecordingConfig {
+        RecordingConfig {
+            restart: RestartPolicy {
+                max_restarts,
+                initial_delay_ms: 10,
+                max_delay_ms: 10,
+            },
+            ..Default::default()
+        }
+    }
+
+    #[actix_rt::test]
+    async fn restarts_with_the_files_continued_as_new_segments() {
+        let work_dir = TempDir::new("room-listener").unwrap();
+        let source = ScriptedRoomSource::new()
+            .lost_connection(vec![data("alice", None, "before")])
+            .connection(vec![data("alice", None, "after"), room_deleted()]);
+
+        let files = record(&source, None, restarting(1), work_dir.path()).await;
+
+        assert_eq!(source.joins(), 2);
+        let alice = participant_files(&files, "alice");
+        assert_eq!(alice.len(), 2);
+        assert_eq!(texts(alice[0]), vec!["before"]);
+        assert_eq!(texts(alice[1]), vec!["after"]);
+        assert_eq!(alice[1].segment, 1);
+        let metadata = metadata(&files);
+        assert_eq!(metadata.gaps.len(), 1);
+        assert_eq!(metadata.gaps[0].kind, GapKind::Restarted);
+        assert_eq!(metadata.error, None);
+    }
+
+    #[actix_rt::test]
+    async fn gives_up_after_the_restarts_are_used_up() {
+        let work_dir = TempDir::new("room-listener").unwrap();
+        let source = ScriptedRoomSource::new()
+            .lost_connection(vec![data("alice", None, "before")])
+            .lost_connection(vec![]);
+
+        let files = record(&source, None, restarting(1), work_dir.path()).await;
+
+        assert_eq!(source.joins(), 2);
+        let alice = participant_files(&files, "alice");
+        assert_eq!(texts(alice[0]), vec!["before"]);
+        let metadata = metadata(&files);
+        assert_eq!(metadata.gaps.len(), 1);
+        assert!(metadata
+            .error
+            .is_some_and(|error| error.contains("room events ended")));
+    }
+}
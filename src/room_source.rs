@@ -0,0 +1,240 @@
+use livekit::{DisconnectReason, Participant, ParticipantKind, RemoteParticipant, Room, RoomEvent};
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+use crate::error_messages::TextEgressError;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Events of a joined room, in the order they happened.
+pub type RoomEvents = mpsc::UnboundedReceiver<RoomSourceEvent>;
+
+pub type JoinedRoom = (Box<dyn ConnectedRoom>, RoomEvents);
+
+/// A participant as the room listener sees it.
+#[derive(Debug, Clone)]
+pub struct RoomParticipant {
+    pub identity: String,
+    pub kind: ParticipantKind,
+    pub metadata: String,
+    pub attributes: HashMap<String, String>,
+}
+
+impl RoomParticipant {
+    /// A standard participant without metadata or attributes.
+    pub fn new(identity: &str) -> Self {
+        RoomParticipant {
+            identity: identity.to_string(),
+            kind: ParticipantKind::Standard,
+            metadata: String::new(),
+            attributes: HashMap::new(),
+        }
+    }
+}
+
+impl From<&RemoteParticipant> for RoomParticipant {
+    fn from(participant: &RemoteParticipant) -> Self {
+        RoomParticipant {
+            identity: participant.identity().to_string(),
+            kind: participant.kind(),
+            metadata: participant.metadata(),
+            attributes: participant.attributes(),
+        }
+    }
+}
+
+/// The room events the room listener acts on.
+#[derive(Debug, Clone)]
+pub enum RoomSourceEvent {
+    DataReceived {
+        participant: Option<RoomParticipant>,
+        topic: Option<String>,
+        payload: Vec<u8>,
+    },
+    ParticipantConnected(RoomParticipant),
+    /// The participant's metadata or attributes changed
+    ParticipantUpdated(RoomParticipant),
+    ParticipantDisconnected(RoomParticipant),
+    /// The connection dropped and is being resumed
+    Reconnecting,
+    Reconnected,
+    Disconnected(DisconnectReason),
+}
+
+impl RoomSourceEvent {
+    /// Converts a LiveKit event, `None` for the events the room listener ignores.
+    pub fn from_livekit(event: RoomEvent) -> Option<Self> {
+        let event = match event {
+            RoomEvent::DataReceived {
+                payload,
+                participant,
+                topic,
+                ..
+            } => RoomSourceEvent::DataReceived {
+                participant: participant.as_ref().map(RoomParticipant::from),
+                topic,
+                payload: Arc::unwrap_or_clone(payload),
+            },
+            RoomEvent::ParticipantConnected(participant) => {
+                RoomSourceEvent::ParticipantConnected(RoomParticipant::from(&participant))
+            }
+            RoomEvent::ParticipantMetadataChanged {
+                participant: Participant::Remote(participant),
+                ..
+            }
+            | RoomEvent::ParticipantAttributesChanged {
+                participant: Participant::Remote(participant),
+                ..
+            } => RoomSourceEvent::ParticipantUpdated(RoomParticipant::from(&participant)),
+            RoomEvent::ParticipantDisconnected(participant) => {
+                RoomSourceEvent::ParticipantDisconnected(RoomParticipant::from(&participant))
+            }
+            RoomEvent::Reconnecting => RoomSourceEvent::Reconnecting,
+            RoomEvent::Reconnected => RoomSourceEvent::Reconnected,
+            RoomEvent::Disconnected { reason } => RoomSourceEvent::Disconnected(reason),
+            _ => return None,
+        };
+        Some(event)
+    }
+}
+
+/// A joined room, left with `close`.
+pub trait ConnectedRoom: Send + Sync {
+    fn close(&self) -> BoxFuture<'_, ()>;
+}
+
+/// Joins rooms for the room listener.
+pub trait RoomSource: Send + Sync {
+    fn join<'a>(
+        &'a self,
+        server_url: &'a str,
+        token: &'a str,
+    ) -> BoxFuture<'a, Result<JoinedRoom, TextEgressError>>;
+}
+
+/// Joins LiveKit rooms.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LiveKitRoomSource;
+
+struct LiveKitRoom(Room);
+
+impl ConnectedRoom for LiveKitRoom {
+    fn close(&self) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            if let Err(e) = self.0.close().await {
+                log::warn!("Failed to leave the room: {:?}", e);
+            }
+        })
+    }
+}
+
+impl RoomSource for LiveKitRoomSource {
+    fn join<'a>(
+        &'a self,
+        server_url: &'a str,
+        token: &'a str,
+    ) -> BoxFuture<'a, Result<JoinedRoom, TextEgressError>> {
+        Box::pin(async move {
+            let (room, mut livekit_events) =
+                Room::connect(server_url, token, Default::default()).await?;
+            let (sender, events) = mpsc::unbounded_channel();
+            tokio::spawn(async move {
+                while let Some(event) = livekit_events.recv().await {
+                    let Some(event) = RoomSourceEvent::from_livekit(event) else {
+                        continue;
+                    };
+                    if sender.send(event).is_err() {
+                        break;
+                    }
+                }
+            });
+            Ok((
+                Box::new(LiveKitRoom(room)) as Box<dyn ConnectedRoom>,
+                events,
+            ))
+        })
+    }
+}
+
+/// An in-memory stand-in for LiveKit, for tests and local development. Every join
+/// plays back the next scripted connection; joins fail once the script is used up.
+#[derive(Debug, Clone, Default)]
+pub struct ScriptedRoomSource {
+    connections: Arc<Mutex<VecDeque<ScriptedConnection>>>,
+    joins: Arc<AtomicUsize>,
+}
+
+#[derive(Debug, Clone)]
+struct ScriptedConnection {
+    events: Vec<RoomSourceEvent>,
+    /// Whether the event stream stays open after the events were delivered
+    stays_open: bool,
+}
+
+/// Keeps the event stream of a scripted connection open until it is closed.
+struct ScriptedRoom(Mutex<Option<mpsc::UnboundedSender<RoomSourceEvent>>>);
+
+impl ConnectedRoom for ScriptedRoom {
+    fn close(&self) -> BoxFuture<'_, ()> {
+        self.0.lock().unwrap().take();
+        Box::pin(async {})
+    }
+}
+
+impl ScriptedRoomSource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a connection that delivers `events`, then stays open until the room is left.
+    pub fn connection(self, events: Vec<RoomSourceEvent>) -> Self {
+        self.script(events, true)
+    }
+
+    /// Adds a connection that delivers `events`, then ends its event stream as if
+    /// the connection task died.
+    pub fn lost_connection(self, events: Vec<RoomSourceEvent>) -> Self {
+        self.script(events, false)
+    }
+
+    fn script(self, events: Vec<RoomSourceEvent>, stays_open: bool) -> Self {
+        let connection = ScriptedConnection { events, stays_open };
+        self.connections.lock().unwrap().push_back(connection);
+        self
+    }
+
+    /// How many times a room was joined, including failed attempts.
+    pub fn joins(&self) -> usize {
+        self.joins.load(Ordering::Acquire)
+    }
+}
+
+impl RoomSource for ScriptedRoomSource {
+    fn join<'a>(
+        &'a self,
+        _server_url: &'a str,
+        _token: &'a str,
+    ) -> BoxFuture<'a, Result<JoinedRoom, TextEgressError>> {
+        Box::pin(async move {
+            self.joins.fetch_add(1, Ordering::AcqRel);
+            let script = self
+                .connections
+                .lock()
+                .unwrap()
+                .pop_front()
+                .ok_or_else(|| {
+                    TextEgressError::RoomListenerError("no scripted connection left".to_string())
+                })?;
+            let (sender, events) = mpsc::unbounded_channel();
+            for event in script.events {
+                let _ = sender.send(event);
+            }
+            let room = ScriptedRoom(Mutex::new(script.stays_open.then_some(sender)));
+            Ok((Box::new(room) as Box<dyn ConnectedRoom>, events))
+        })
+    }
+}
@@ -0,0 +1,117 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// When to close a participant file and start a new segment. No rotation by default.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RotationConfig {
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+    #[serde(default)]
+    pub max_duration_secs: Option<u64>,
+    /// Rotate on wall clock boundaries, e.g. 3600 rotates at the top of every hour
+    #[serde(default)]
+    pub wall_clock_secs: Option<u64>,
+    /// Upload closed segments right away instead of when the egress stops
+    #[serde(default)]
+    pub upload_segments: bool,
+}
+
+impl RotationConfig {
+    /// Whether a file opened at `opened_at` with `bytes_written` bytes should be rotated
+    /// before writing `incoming` more bytes at `now`.
+    pub fn should_rotate(
+        &self,
+        bytes_written: u64,
+        opened_at: DateTime<Utc>,
+        now: DateTime<Utc>,
+        incoming: u64,
+    ) -> bool {
+        if bytes_written == 0 {
+            return false;
+        }
+
+        if let Some(max_bytes) = self.max_bytes {
+            if bytes_written + incoming > max_bytes {
+                return true;
+            }
+        }
+
+        if let Some(max_duration_secs) = self.max_duration_secs {
+            if (now - opened_at).num_seconds() >= max_duration_secs as i64 {
+                return true;
+            }
+        }
+
+        if let Some(wall_clock_secs) = self.wall_clock_secs.filter(|secs| *secs > 0) {
+            let boundary = wall_clock_secs as i64;
+            if opened_at.timestamp().div_euclid(boundary) != now.timestamp().div_euclid(boundary) {
+                return true;
+            }
+        }
+
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(hour: u32, minute: u32, second: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 1, 1, hour, minute, second)
+            .unwrap()
+    }
+
+    #[test]
+    fn never_rotates_by_default() {
+        let config = RotationConfig::default();
+        assert!(!config.should_rotate(u64::MAX / 2, at(0, 0, 0), at(23, 0, 0), 1024));
+    }
+
+    #[test]
+    fn rotates_by_size() {
+        let config = RotationConfig {
+            max_bytes: Some(100),
+            ..Default::default()
+        };
+        assert!(!config.should_rotate(90, at(0, 0, 0), at(0, 0, 0), 10));
+        assert!(config.should_rotate(90, at(0, 0, 0), at(0, 0, 0), 11));
+    }
+
+    #[test]
+    fn rotates_by_duration() {
+        let config = RotationConfig {
+            max_duration_secs: Some(60),
+            ..Default::default()
+        };
+        assert!(!config.should_rotate(1, at(0, 0, 0), at(0, 0, 59), 1));
+        assert!(config.should_rotate(1, at(0, 0, 0), at(0, 1, 0), 1));
+    }
+
+    #[test]
+    fn rotates_on_wall_clock_boundaries() {
+        let config = RotationConfig {
+            wall_clock_secs: Some(3600),
+            ..Default::default()
+        };
+        assert!(!config.should_rotate(1, at(10, 0, 0), at(10, 59, 59), 1));
+        assert!(config.should_rotate(1, at(10, 59, 59), at(11, 0, 0), 1));
+
+        let disabled = RotationConfig {
+            wall_clock_secs: Some(0),
+            ..Default::default()
+        };
+        assert!(!disabled.should_rotate(1, at(10, 0, 0), at(12, 0, 0), 1));
+    }
+
+    #[test]
+    fn keeps_the_first_message_in_an_empty_file() {
+        let config = RotationConfig {
+            max_bytes: Some(10),
+            max_duration_secs: Some(1),
+            ..Default::default()
+        };
+        assert!(!config.should_rotate(0, at(0, 0, 0), at(1, 0, 0), 100));
+    }
+}
@@ -1,8 +1,18 @@
 use actix::{Actor, Addr, Handler, Message};
 use rusoto_s3::S3;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use tokio::io::AsyncReadExt;
+use tracing::{Instrument, Span};
 
+use crate::compression::Compression;
+use crate::config::S3Config;
+use crate::encryption::{
+    DataKey, EnvelopeEncryptor, ServerSideEncryptionHeaders, ENCRYPTED_FILE_EXTENSION,
+    ENVELOPE_ALGORITHM,
+};
+use crate::error_messages::TextEgressError;
+use crate::manifest::{EgressManifest, ManifestObject, MANIFEST_FILE_NAME};
+use crate::room_listener_actor::DataEgressResultFiles;
 use crate::session_listener_actor::{S3UploaderUpdates, SessionListenerActor};
 
 #[derive(Debug, Clone, Message)]
@@ -11,13 +21,25 @@ pub enum S3UploaderMessages {
     Start {
         prefix: String,
         egress_id: String,
-        files: Vec<String>,
+        files: Vec<DataEgressResultFiles>,
+        manifest: EgressManifest,
+        span: Span,
+    },
+    /// Uploads a rotated segment while the egress is still recording.
+    UploadSegment {
+        prefix: String,
+        egress_id: String,
+        file: DataEgressResultFiles,
+        span: Span,
     },
 }
 
 pub(crate) struct S3UploaderActor {
     bucket: String,
     s3_client: rusoto_s3::S3Client,
+    compression: Compression,
+    envelope_encryptor: Option<EnvelopeEncryptor>,
+    server_side_encryption: ServerSideEncryptionHeaders,
     parent_addr: Addr<SessionListenerActor>,
 }
 
@@ -30,30 +52,38 @@ impl Actor for S3UploaderActor {
 }
 
 impl S3UploaderActor {
+    #[allow(clippy::result_large_err)]
     pub fn new(
-        bucket: &str,
-        access_key: &str,
-        secret_key: &str,
-        region: &str,
-        endpoint: &str,
+        s3_config: &S3Config,
         parent_addr: Addr<SessionListenerActor>,
-    ) -> Self {
-        let client = rusoto_core::HttpClient::new().expect("Failed to create request dispatcher");
-        let region = rusoto_core::Region::Custom {
-            name: region.to_string(),
-            endpoint: endpoint.to_string(),
-        };
-        let credentials_provider = rusoto_credential::StaticProvider::new_minimal(
-            access_key.to_string(),
-            secret_key.to_string(),
-        );
+    ) -> Result<Self, TextEgressError> {
+        let s3_client = s3_client(s3_config);
 
-        let s3_client = rusoto_s3::S3Client::new_with(client, credentials_provider, region);
+        let envelope_encryptor = s3_config
+            .encryption
+            .public_key_path
+            .as_deref()
+            .map(EnvelopeEncryptor::from_pem_file)
+            .transpose()?;
 
-        S3UploaderActor {
-            bucket: bucket.to_string(),
+        Ok(S3UploaderActor {
+            bucket: s3_config.bucket_name.clone(),
             s3_client,
+            compression: s3_config.compression,
+            envelope_encryptor,
+            server_side_encryption: ServerSideEncryptionHeaders::from_config(
+                &s3_config.encryption,
+            )?,
             parent_addr,
+        })
+    }
+
+    fn upload_target(&self) -> UploadTarget {
+        UploadTarget {
+            bucket: self.bucket.clone(),
+            s3_client: self.s3_client.clone(),
+            compression: self.compression,
+            server_side_encryption: self.server_side_encryption.clone(),
         }
     }
 }
@@ -67,70 +97,302 @@ impl Handler<S3UploaderMessages> for S3UploaderActor {
                 prefix,
                 egress_id,
                 files,
+                mut manifest,
+                span,
             } => {
                 log::info!("Uploading files to S3 bucket: {}", self.bucket);
+                let span = tracing::info_span!(
+                    parent: &span,
+                    "s3_upload",
+                    egress_id = %egress_id,
+                    bucket = %self.bucket
+                );
                 let parent_addr = self.parent_addr.clone();
-                let bucket = self.bucket.clone();
-                let s3_client = self.s3_client.clone();
-                let mut uploaded_files: Vec<String> = vec![];
-
-                actix::spawn(async move {
-                    for file in files {
-                        log::info!("Uploading file: {}", &file);
-                        let mut fh = match tokio::fs::File::open(&file).await {
-                            Ok(f) => f,
+                let target = self.upload_target();
+                let envelope_encryptor = self.envelope_encryptor.clone();
+                // Segments uploaded while recording are already listed in the manifest
+                let mut uploaded_files: Vec<String> =
+                    manifest.objects.iter().map(|o| o.key.clone()).collect();
+
+                actix::spawn(
+                    async move {
+                        parent_addr.do_send(S3UploaderUpdates::Started {
+                            egress_id: egress_id.clone(),
+                            files: files.iter().map(|f| f.file_path.clone()).collect(),
+                            bucket: target.bucket.clone(),
+                            span: Span::current(),
+                        });
+                        let data_key = match envelope_encryptor
+                            .as_ref()
+                            .map(EnvelopeEncryptor::data_key)
+                            .transpose()
+                        {
+                            Ok(data_key) => data_key,
                             Err(e) => {
-                                log::error!("Failed to open file: {:?}", e);
+                                log::error!("Failed to generate data key: {:?}", e);
                                 parent_addr.do_send(S3UploaderUpdates::Failed {
                                     egress_id: egress_id.clone(),
-                                    error: e.into(),
+                                    error: e,
+                                    span: Span::current(),
                                 });
                                 return;
                             }
                         };
-                        let file_path = PathBuf::from(&file);
-                        let file_name = file_path.file_name().unwrap().to_str().unwrap();
-                        let mut buffer = Vec::new();
-                        match fh.read_to_end(&mut buffer).await {
-                            Ok(_) => {}
-                            Err(e) => {
-                                parent_addr.do_send(S3UploaderUpdates::Failed {
-                                    egress_id: egress_id.clone(),
-                                    error: e.into(),
-                                });
-                                return;
+                        for file in files {
+                            match target.upload_file(&prefix, &file, data_key.as_ref()).await {
+                                Ok(object) => {
+                                    uploaded_files.push(object.key.clone());
+                                    manifest.objects.push(object);
+                                }
+                                Err(e) => {
+                                    log::error!("Failed to upload file: {:?}", e);
+                                    parent_addr.do_send(S3UploaderUpdates::Failed {
+                                        egress_id: egress_id.clone(),
+                                        error: e,
+                                        span: Span::current(),
+                                    });
+                                    return;
+                                }
                             }
                         }
-                        let key = format!("{}/{}", prefix, file_name);
-                        let put_req = rusoto_s3::PutObjectRequest {
-                            bucket: bucket.clone(),
-                            key: key.clone(),
-                            body: Some(buffer.into()),
-                            content_type: Some("text/plain".to_string()),
-                            ..Default::default()
+
+                        let manifest_key =
+                            match target.upload_manifest(&prefix, &mut manifest).await {
+                                Ok(manifest_key) => manifest_key,
+                                Err(e) => {
+                                    log::error!("Failed to upload manifest: {:?}", e);
+                                    parent_addr.do_send(S3UploaderUpdates::Failed {
+                                        egress_id: egress_id.clone(),
+                                        error: e,
+                                        span: Span::current(),
+                                    });
+                                    return;
+                                }
+                            };
+                        uploaded_files.push(manifest_key);
+
+                        parent_addr.do_send(S3UploaderUpdates::Completed {
+                            egress_id,
+                            bucket: target.bucket.clone(),
+                            files: uploaded_files,
+                            span: Span::current(),
+                        });
+                    }
+                    .instrument(span),
+                );
+            }
+            S3UploaderMessages::UploadSegment {
+                prefix,
+                egress_id,
+                file,
+                span,
+            } => {
+                log::info!("Uploading segment: {}", &file.file_path);
+                let span = tracing::info_span!(
+                    parent: &span,
+                    "s3_upload_segment",
+                    egress_id = %egress_id,
+                    bucket = %self.bucket
+                );
+                let parent_addr = self.parent_addr.clone();
+                let target = self.upload_target();
+                let envelope_encryptor = self.envelope_encryptor.clone();
+
+                actix::spawn(
+                    async move {
+                        let upload = async {
+                            let data_key = envelope_encryptor
+                                .as_ref()
+                                .map(EnvelopeEncryptor::data_key)
+                                .transpose()?;
+                            target.upload_file(&prefix, &file, data_key.as_ref()).await
                         };
-                        let res = s3_client.put_object(put_req).await;
-                        match res {
-                            Ok(_) => {
-                                uploaded_files.push(key.clone());
+                        match upload.await {
+                            Ok(object) => {
+                                parent_addr.do_send(S3UploaderUpdates::SegmentUploaded {
+                                    egress_id,
+                                    file_path: file.file_path,
+                                    object,
+                                    span: Span::current(),
+                                });
                             }
                             Err(e) => {
-                                log::error!("Failed to upload file: {:?}", e);
-                                parent_addr.do_send(S3UploaderUpdates::Failed {
-                                    egress_id: egress_id.clone(),
-                                    error: e.into(),
-                                });
-                                return;
+                                // The segment is uploaded again when the egress stops
+                                log::warn!(
+                                    "Failed to upload segment {}, retrying on stop: {:?}",
+                                    &file.file_path,
+                                    e
+                                );
                             }
                         }
                     }
-                    parent_addr.do_send(S3UploaderUpdates::Completed {
-                        egress_id,
-                        bucket: bucket.clone(),
-                        files: uploaded_files,
-                    });
-                });
+                    .instrument(span),
+                );
             }
         }
     }
 }
+
+/// Bucket and object options shared by every upload.
+#[derive(Clone)]
+struct UploadTarget {
+    bucket: String,
+    s3_client: rusoto_s3::S3Client,
+    compression: Compression,
+    server_side_encryption: ServerSideEncryptionHeaders,
+}
+
+impl UploadTarget {
+    /// Lists the bucket in the manifest and uploads it, returning its key. The manifest
+    /// holds no message content and is never envelope encrypted, only server side.
+    async fn upload_manifest(
+        &self,
+        prefix: &str,
+        manifest: &mut EgressManifest,
+    ) -> Result<String, TextEgressError> {
+        manifest.bucket = Some(self.bucket.clone());
+        let manifest_key = format!("{}/{}", prefix, MANIFEST_FILE_NAME);
+        put_object(
+            &self.s3_client,
+            &self.bucket,
+            &manifest_key,
+            serde_json::to_vec_pretty(manifest)?,
+            "application/json",
+            None,
+            &self.server_side_encryption,
+        )
+        .await?;
+        Ok(manifest_key)
+    }
+
+    /// Reads, compresses, encrypts and uploads a recording, returning its manifest entry.
+    async fn upload_file(
+        &self,
+        prefix: &str,
+        file: &DataEgressResultFiles,
+        data_key: Option<&DataKey>,
+    ) -> Result<ManifestObject, TextEgressError> {
+        log::info!("Uploading file: {}", &file.file_path);
+        let mut fh = tokio::fs::File::open(&file.file_path).await?;
+        let file_path = PathBuf::from(&file.file_path);
+        let file_name = file_path.file_name().unwrap().to_str().unwrap();
+        let mut buffer = Vec::new();
+        fh.read_to_end(&mut buffer).await?;
+
+        let uncompressed_size = buffer.len() as u64;
+        let buffer = self.compression.compress_blocking(buffer).await?;
+        let (buffer, encryption) = match data_key {
+            Some(data_key) => (data_key.encrypt(&buffer)?, Some(ENVELOPE_ALGORITHM)),
+            None => (buffer, None),
+        };
+
+        let mut key = format!("{}/{}", prefix, file_name);
+        if let Some(extension) = self.compression.extension() {
+            key = format!("{}.{}", key, extension);
+        }
+        if encryption.is_some() {
+            key = format!("{}.{}", key, ENCRYPTED_FILE_EXTENSION);
+        }
+        let content_type = content_type_for(&file_path);
+        let content_encoding = self.compression.content_encoding();
+        let object = ManifestObject::new(
+            &key,
+            &buffer,
+            content_type,
+            content_encoding,
+            encryption,
+            uncompressed_size,
+            file,
+        );
+        // Encrypted objects are opaque to S3 and HTTP clients
+        let (object_content_type, object_content_encoding) = match encryption {
+            Some(_) => ("application/octet-stream", None),
+            None => (content_type, content_encoding),
+        };
+        put_object(
+            &self.s3_client,
+            &self.bucket,
+            &key,
+            buffer,
+            object_content_type,
+            object_content_encoding,
+            &self.server_side_encryption,
+        )
+        .await?;
+
+        Ok(object)
+    }
+}
+
+/// Uploads the files of an egress outside of the actor, for egresses left behind by a stopped
+/// instance. Files are compressed and encrypted like any other egress, followed by the manifest.
+pub async fn upload_egress(
+    s3_config: &S3Config,
+    prefix: &str,
+    files: &[DataEgressResultFiles],
+    mut manifest: EgressManifest,
+) -> Result<EgressManifest, TextEgressError> {
+    let target = UploadTarget {
+        bucket: s3_config.bucket_name.clone(),
+        s3_client: s3_client(s3_config),
+        compression: s3_config.compression,
+        server_side_encryption: ServerSideEncryptionHeaders::from_config(&s3_config.encryption)?,
+    };
+    let data_key = match &s3_config.encryption.public_key_path {
+        Some(path) => Some(EnvelopeEncryptor::from_pem_file(path)?.data_key()?),
+        None => None,
+    };
+
+    for file in files {
+        let object = target.upload_file(prefix, file, data_key.as_ref()).await?;
+        manifest.objects.push(object);
+    }
+    target.upload_manifest(prefix, &mut manifest).await?;
+    Ok(manifest)
+}
+
+pub fn s3_client(s3_config: &S3Config) -> rusoto_s3::S3Client {
+    let client = rusoto_core::HttpClient::new().expect("Failed to create request dispatcher");
+    let region = rusoto_core::Region::Custom {
+        name: s3_config.region.clone(),
+        endpoint: s3_config.endpoint.clone(),
+    };
+    let credentials_provider = rusoto_credential::StaticProvider::new_minimal(
+        s3_config.access_key.clone(),
+        s3_config.secret_key.expose().to_string(),
+    );
+
+    rusoto_s3::S3Client::new_with(client, credentials_provider, region)
+}
+
+fn content_type_for(file_path: &Path) -> &'static str {
+    match file_path.extension().and_then(|ext| ext.to_str()) {
+        Some("json") => "application/json",
+        _ => "text/plain",
+    }
+}
+
+async fn put_object(
+    s3_client: &rusoto_s3::S3Client,
+    bucket: &str,
+    key: &str,
+    body: Vec<u8>,
+    content_type: &str,
+    content_encoding: Option<&str>,
+    server_side_encryption: &ServerSideEncryptionHeaders,
+) -> Result<(), TextEgressError> {
+    let mut put_req = rusoto_s3::PutObjectRequest {
+        bucket: bucket.to_string(),
+        key: key.to_string(),
+        body: Some(body.into()),
+        content_type: Some(content_type.to_string()),
+        content_encoding: content_encoding.map(|e| e.to_string()),
+        ..Default::default()
+    };
+    server_side_encryption.apply(&mut put_req);
+    s3_client
+        .put_object(put_req)
+        .instrument(tracing::info_span!("put_object", key = %key))
+        .await?;
+    Ok(())
+}
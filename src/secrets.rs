@@ -0,0 +1,341 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::pin::Pin;
+
+use crate::error_messages::TextEgressError;
+
+const REDACTED: &str = "[REDACTED]";
+
+/// A configuration value that is never printed by `Debug` or serialized.
+#[derive(Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: impl Into<String>) -> Self {
+        Secret(value.into())
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Secret(value)
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(REDACTED)
+    }
+}
+
+/// Fields that may be read from the file named by a `<FIELD>_FILE` variable
+const FILE_SECRET_FIELDS: &[&str] = &[
+    "KEY",
+    "SECRET",
+    "ACCESS_KEY",
+    "SECRET_KEY",
+    "CUSTOMER_KEY",
+    "LIVEKIT_API_KEY",
+    "LIVEKIT_API_SECRET",
+    "TOKEN",
+];
+
+/// Replaces nested `<FIELD>_FILE` variables, such as `PROJECTS__0__SECRET_FILE`, with the
+/// contents of the file they point to, as with Docker and Kubernetes secrets.
+#[allow(clippy::result_large_err)]
+pub fn resolve_secret_files(vars: &mut BTreeMap<String, String>) -> Result<(), TextEgressError> {
+    let file_vars: Vec<(String, String)> = vars
+        .iter()
+        .filter_map(|(key, path)| {
+            let field_key = key.strip_suffix("_FILE")?;
+            let (_, field) = field_key.rsplit_once("__")?;
+            FILE_SECRET_FIELDS
+                .contains(&field)
+                .then(|| (field_key.to_string(), path.clone()))
+        })
+        .collect();
+
+    for (field_key, path) in file_vars {
+        if vars.contains_key(&field_key) {
+            return Err(TextEgressError::ConfigFileError(format!(
+                "both {} and {}_FILE are set",
+                field_key, field_key
+            )));
+        }
+        let content = std::fs::read_to_string(&path).map_err(|e| {
+            TextEgressError::ConfigFileError(format!("{}_FILE ({}): {}", field_key, path, e))
+        })?;
+        let value = content.trim_end_matches(['\r', '\n']).to_string();
+        vars.remove(&format!("{}_FILE", field_key));
+        vars.insert(field_key, value);
+    }
+    Ok(())
+}
+
+pub type SecretFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<String, TextEgressError>> + Send + 'a>>;
+
+/// Looks up secrets referenced in the configuration as `<scheme>:<reference>`.
+pub trait SecretProvider: Send + Sync {
+    fn scheme(&self) -> &str;
+
+    fn fetch<'a>(&'a self, reference: &'a str) -> SecretFuture<'a>;
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SecretsConfig {
+    #[serde(default)]
+    pub vault: Option<VaultConfig>,
+}
+
+impl SecretsConfig {
+    pub fn providers(&self) -> Vec<Box<dyn SecretProvider>> {
+        let mut providers: Vec<Box<dyn SecretProvider>> = vec![];
+        if let Some(vault) = &self.vault {
+            providers.push(Box::new(VaultProvider::new(vault)));
+        }
+        providers
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VaultConfig {
+    /// Base URL of the server, such as `https://vault.example.org:8200`
+    pub address: String,
+    pub token: Secret,
+    #[serde(default)]
+    pub namespace: Option<String>,
+}
+
+/// Reads secrets from the Vault HTTP API, referenced as `vault:<path>#<field>`,
+/// for example `vault:secret/data/syncflow#api_secret`. KV version 1 and 2 are supported.
+#[derive(Debug, Clone)]
+pub struct VaultProvider {
+    client: reqwest::Client,
+    address: String,
+    token: Secret,
+    namespace: Option<String>,
+}
+
+impl VaultProvider {
+    pub fn new(config: &VaultConfig) -> Self {
+        VaultProvider {
+            client: reqwest::Client::new(),
+            address: config.address.trim_end_matches('/').to_string(),
+            token: config.token.clone(),
+            namespace: config.namespace.clone(),
+        }
+    }
+
+    async fn read(&self, reference: &str) -> Result<String, TextEgressError> {
+        let (path, field) = reference.split_once('#').ok_or_else(|| {
+            TextEgressError::SecretError(format!("expected <path>#<field>, got {}", reference))
+        })?;
+        let mut request = self
+            .client
+            .get(format!(
+                "{}/v1/{}",
+                self.address,
+                path.trim_start_matches('/')
+            ))
+            .header("X-Vault-Token", self.token.expose());
+        if let Some(namespace) = &self.namespace {
+            request = request.header("X-Vault-Namespace", namespace);
+        }
+        let body: serde_json::Value = request.send().await?.error_for_status()?.json().await?;
+
+        // KV version 2 nests the secret's fields in another `data` object
+        let data = &body["data"];
+        let value = data["data"][field]
+            .as_str()
+            .or_else(|| data[field].as_str());
+        value
+            .map(|value| value.to_string())
+            .ok_or_else(|| TextEgressError::SecretError(format!("{} has no field {}", path, field)))
+    }
+}
+
+impl SecretProvider for VaultProvider {
+    fn scheme(&self) -> &str {
+        "vault"
+    }
+
+    fn fetch<'a>(&'a self, reference: &'a str) -> SecretFuture<'a> {
+        Box::pin(self.read(reference))
+    }
+}
+
+/// Replaces a `<scheme>:<reference>` value with the secret fetched from the matching provider.
+/// Values without a known scheme are used as they are.
+pub async fn resolve(
+    secret: &mut Secret,
+    providers: &[Box<dyn SecretProvider>],
+) -> Result<(), TextEgressError> {
+    let Some((scheme, reference)) = secret.expose().split_once(':') else {
+        return Ok(());
+    };
+    if let Some(provider) = providers.iter().find(|p| p.scheme() == scheme) {
+        let value = provider.fetch(reference).await?;
+        *secret = Secret::new(value);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+    use tempdir::TempDir;
+
+    fn vars(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn never_prints_or_serializes_secrets() {
+        let secret = Secret::new("hunter2");
+        assert_eq!(format!("{:?}", secret), "[REDACTED]");
+        assert_eq!(serde_json::to_string(&secret).unwrap(), "\"[REDACTED]\"");
+        let parsed: Secret = serde_json::from_str("\"hunter2\"").unwrap();
+        assert_eq!(parsed, secret);
+    }
+
+    #[test]
+    fn reads_secret_files_without_trailing_newlines() {
+        let dir = TempDir::new("secrets").unwrap();
+        let path = dir.path().join("secret");
+        std::fs::write(&path, "s3cr3t\r\n").unwrap();
+        let path = path.to_string_lossy().to_string();
+
+        let mut vars = vars(&[
+            ("PROJECTS__0__SECRET_FILE", &path),
+            ("PROJECTS__0__BUCKET_NAME_FILE", &path),
+        ]);
+        resolve_secret_files(&mut vars).unwrap();
+
+        assert_eq!(vars["PROJECTS__0__SECRET"], "s3cr3t");
+        assert!(!vars.contains_key("PROJECTS__0__SECRET_FILE"));
+        // Only secret fields are read from files
+        assert!(vars.contains_key("PROJECTS__0__BUCKET_NAME_FILE"));
+        assert!(!vars.contains_key("PROJECTS__0__BUCKET_NAME"));
+    }
+
+    #[test]
+    fn rejects_a_secret_set_both_ways() {
+        let mut vars = vars(&[
+            ("PROJECTS__0__SECRET", "inline"),
+            ("PROJECTS__0__SECRET_FILE", "/run/secrets/secret"),
+        ]);
+        let error = resolve_secret_files(&mut vars).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("both PROJECTS__0__SECRET and PROJECTS__0__SECRET_FILE are set"));
+    }
+
+    #[test]
+    fn fails_on_missing_secret_files() {
+        let mut vars = vars(&[("PROJECTS__0__SECRET_FILE", "/nonexistent/secret")]);
+        assert!(matches!(
+            resolve_secret_files(&mut vars),
+            Err(TextEgressError::ConfigFileError(_))
+        ));
+    }
+
+    /// Serves KV version 1 secrets under `kv/` and version 2 secrets under `secret/data/`.
+    async fn vault_stub(request: HttpRequest, path: web::Path<String>) -> HttpResponse {
+        let token = request.headers().get("X-Vault-Token");
+        if token.and_then(|token| token.to_str().ok()) != Some("root-token") {
+            return HttpResponse::Forbidden().finish();
+        }
+        match path.as_str() {
+            "kv/syncflow" => HttpResponse::Ok().json(serde_json::json!({
+                "data": { "api_secret": "v1-secret" }
+            })),
+            "secret/data/syncflow" => HttpResponse::Ok().json(serde_json::json!({
+                "data": { "data": { "api_secret": "v2-secret" }, "metadata": { "version": 3 } }
+            })),
+            _ => HttpResponse::NotFound().finish(),
+        }
+    }
+
+    fn start_vault_stub() -> String {
+        let server =
+            HttpServer::new(|| App::new().route("/v1/{path:.*}", web::get().to(vault_stub)))
+                .workers(1)
+                .bind(("127.0.0.1", 0))
+                .unwrap();
+        let address = format!("http://{}", server.addrs()[0]);
+        actix_rt::spawn(server.run());
+        address
+    }
+
+    fn vault(address: &str, token: &str) -> Vec<Box<dyn SecretProvider>> {
+        SecretsConfig {
+            vault: Some(VaultConfig {
+                address: format!("{}/", address),
+                token: Secret::new(token),
+                namespace: None,
+            }),
+        }
+        .providers()
+    }
+
+    #[actix_rt::test]
+    async fn reads_kv_v1_and_v2_secrets_from_vault() {
+        let providers = vault(&start_vault_stub(), "root-token");
+
+        let mut v1 = Secret::new("vault:kv/syncflow#api_secret");
+        resolve(&mut v1, &providers).await.unwrap();
+        assert_eq!(v1.expose(), "v1-secret");
+
+        let mut v2 = Secret::new("vault:secret/data/syncflow#api_secret");
+        resolve(&mut v2, &providers).await.unwrap();
+        assert_eq!(v2.expose(), "v2-secret");
+    }
+
+    #[actix_rt::test]
+    async fn fails_on_missing_vault_secrets() {
+        let address = start_vault_stub();
+        let providers = vault(&address, "root-token");
+
+        let mut missing_field = Secret::new("vault:kv/syncflow#other");
+        assert!(matches!(
+            resolve(&mut missing_field, &providers).await,
+            Err(TextEgressError::SecretError(_))
+        ));
+        let mut missing_path = Secret::new("vault:kv/other#api_secret");
+        assert!(resolve(&mut missing_path, &providers).await.is_err());
+        let mut no_field = Secret::new("vault:kv/syncflow");
+        assert!(resolve(&mut no_field, &providers).await.is_err());
+
+        let mut forbidden = Secret::new("vault:kv/syncflow#api_secret");
+        assert!(resolve(&mut forbidden, &vault(&address, "wrong-token"))
+            .await
+            .is_err());
+    }
+
+    #[actix_rt::test]
+    async fn keeps_values_without_a_known_scheme() {
+        let providers = vault("http://127.0.0.1:9", "root-token");
+        for value in ["plain-secret", "other:reference#field"] {
+            let mut secret = Secret::new(value);
+            resolve(&mut secret, &providers).await.unwrap();
+            assert_eq!(secret.expose(), value);
+        }
+    }
+}
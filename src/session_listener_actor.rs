@@ -1,23 +1,38 @@
 use self::room_listener_actor::DataEgressResultFiles;
-use crate::config::S3Config;
+use crate::admission::{AdmissionController, AdmissionPermit};
+use crate::config::{RecordingConfig, S3Config};
+use crate::coordination::{
+    AmqpSessionClaim, ConnectionLease, CoordinationConfig, CoordinationMode, SessionClaim,
+};
 use crate::error_messages::TextEgressError;
+use crate::join_tokens::JoinTokens;
+use crate::manifest::{EgressManifest, ManifestObject};
 use crate::room_listener_actor::{self, RoomListenerActor, RoomListenerMessages};
+use crate::room_source::LiveKitRoomSource;
 use crate::s3_uploader_actor::{S3UploaderActor, S3UploaderMessages};
+use crate::secrets::Secret;
+use crate::status_reporter::{StatusReporter, StatusReportingConfig};
+use crate::telemetry::record_error;
 use actix::prelude::*;
 use actix::{Actor, Addr, Handler};
-use amqprs::channel::{BasicConsumeArguments, QueueBindArguments, QueueDeclareArguments};
+use amqprs::channel::{
+    BasicCancelArguments, BasicConsumeArguments, BasicNackArguments, BasicQosArguments, Channel,
+    QueueBindArguments, QueueDeclareArguments,
+};
 use amqprs::connection::{Connection, OpenConnectionArguments};
 use amqprs::tls::TlsAdaptor;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use syncflow_client::ProjectClient;
 use syncflow_shared::device_models::{DeviceRegisterRequest, DeviceResponse, NewSessionMessage};
-use syncflow_shared::livekit_models::{TokenRequest, VideoGrantsWrapper};
 use tokio::sync::Mutex;
+use tracing::{Instrument, Span};
 use uuid::Uuid;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum TextEgressStatus {
     Started,
     Stopped,
@@ -25,11 +40,61 @@ pub enum TextEgressStatus {
     Starting,
     Stopping,
     Complete,
+    Uploading,
+    Uploaded,
+}
+
+impl TextEgressStatus {
+    /// Whether the egress has finished, successfully or not
+    pub fn is_final(&self) -> bool {
+        matches!(self, TextEgressStatus::Failed | TextEgressStatus::Uploaded)
+    }
+}
+
+/// Virtual host of the session notification exchanges
+pub(crate) const RABBITMQ_VHOST_NAME: &str = "syncflow";
+
+/// Group the egress device is registered in, also its RabbitMQ user name
+pub(crate) const EGRESS_DEVICE_GROUP: &str = "text-egress";
+
+/// How often a draining project checks for unfinished egresses
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Waits until every egress of a draining project has been uploaded or has failed.
+async fn wait_for_egresses(project_id: &str, egress_leases: &Mutex<HashMap<String, EgressLease>>) {
+    loop {
+        let (recording, uploading) =
+            egress_leases
+                .lock()
+                .await
+                .values()
+                .fold((0, 0), |(recording, uploading), lease| {
+                    match lease.listener_stopped {
+                        false => (recording + 1, uploading),
+                        true => (recording, uploading + 1),
+                    }
+                });
+        if recording + uploading == 0 {
+            return;
+        }
+        log::info!(
+            "Draining project {}, waiting for {} recording and {} uploading egresses",
+            project_id,
+            recording,
+            uploading
+        );
+        tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+    }
 }
 
+/// How long a refreshed connection overlaps the previous one at most, while copies of
+/// the same notification delivered on both are dropped
+const DUPLICATE_NOTIFICATION_WINDOW: Duration = Duration::from_secs(60);
+
 #[derive(Debug, Clone)]
 pub struct TextEgressInfo {
     pub egress_id: String,
+    pub session_id: String,
     pub room_name: String,
     pub topic: Option<String>,
     pub started_at: Option<usize>,
@@ -39,18 +104,53 @@ pub struct TextEgressInfo {
     pub status: TextEgressStatus,
     pub paths: Vec<String>,
     pub s3_bucket_name: Option<String>,
+    /// Rotated segments already uploaded while recording, by local file path
+    pub uploaded_segments: HashMap<String, ManifestObject>,
 }
 
 pub struct SessionListenerActor {
     pub rabbitmq_host: String,
     pub port: u16,
     pub use_ssl: bool,
+    project_id: String,
     project_client: Arc<Mutex<ProjectClient>>,
     registered_egress_group: Arc<Mutex<Option<DeviceResponse>>>,
     rabbitmq_listener: Arc<Mutex<Option<Addr<RabbitMQListenerActor>>>>,
     s3_uploader: Arc<Mutex<Option<Addr<S3UploaderActor>>>>,
     s3_config: S3Config,
+    recording_config: RecordingConfig,
     session_egresses: Arc<Mutex<HashMap<String, TextEgressInfo>>>,
+    status_reporter: Arc<StatusReporter>,
+    admission: Arc<AdmissionController>,
+    egress_leases: Arc<Mutex<HashMap<String, EgressLease>>>,
+    retry_failed_sessions: bool,
+    coordination: CoordinationConfig,
+    /// Set once the project is removed, new sessions are no longer accepted
+    draining: bool,
+    join_tokens: Arc<JoinTokens>,
+}
+
+/// Resources held by an egress until it reaches a final state.
+#[derive(Debug, Default)]
+struct EgressLease {
+    permit: Option<AdmissionPermit>,
+    claim: Option<Arc<dyn SessionClaim>>,
+    room_listener: Option<Addr<RoomListenerActor>>,
+    /// Set once the room listener stopped and the recording is being uploaded
+    listener_stopped: bool,
+}
+
+/// Releases the admission slots and the session claim of a finished egress.
+async fn release_egress(
+    egress_leases: &Mutex<HashMap<String, EgressLease>>,
+    egress_id: &str,
+    requeue: bool,
+) {
+    if let Some(lease) = egress_leases.lock().await.remove(egress_id) {
+        if let Some(claim) = lease.claim {
+            claim.release(requeue);
+        }
+    }
 }
 
 impl SessionListenerActor {
@@ -64,11 +164,17 @@ impl SessionListenerActor {
         api_key: &str,
         api_secret: &str,
         s3_config: &S3Config,
+        recording_config: &RecordingConfig,
+        admission: AdmissionController,
+        retry_failed_sessions: bool,
+        coordination: &CoordinationConfig,
+        status_reporting: &StatusReportingConfig,
     ) -> Self {
         SessionListenerActor {
             rabbitmq_host: rabbitmq_host.to_string(),
             port,
             use_ssl,
+            project_id: project_id.to_string(),
             project_client: Arc::new(Mutex::new(ProjectClient::new(
                 base_url, project_id, api_key, api_secret,
             ))),
@@ -76,11 +182,107 @@ impl SessionListenerActor {
             rabbitmq_listener: Arc::new(Mutex::new(None)),
             s3_uploader: Arc::new(Mutex::new(None)),
             s3_config: s3_config.clone(),
+            recording_config: recording_config.clone(),
             session_egresses: Arc::new(Mutex::new(HashMap::new())),
+            status_reporter: Arc::new(StatusReporter::new(status_reporting)),
+            admission: Arc::new(admission),
+            egress_leases: Arc::new(Mutex::new(HashMap::new())),
+            retry_failed_sessions,
+            coordination: coordination.clone(),
+            draining: false,
+            join_tokens: Arc::new(JoinTokens::new(&recording_config.join_token)),
         }
     }
 }
 
+/// What a session listener shares with its project registry, to clean up after it
+/// if it stops unexpectedly.
+#[derive(Clone)]
+pub struct ListenerResources {
+    project_id: String,
+    project_client: Arc<Mutex<ProjectClient>>,
+    registered_egress_group: Arc<Mutex<Option<DeviceResponse>>>,
+    rabbitmq_listener: Arc<Mutex<Option<Addr<RabbitMQListenerActor>>>>,
+    egress_leases: Arc<Mutex<HashMap<String, EgressLease>>>,
+}
+
+impl SessionListenerActor {
+    pub fn resources(&self) -> ListenerResources {
+        ListenerResources {
+            project_id: self.project_id.clone(),
+            project_client: self.project_client.clone(),
+            registered_egress_group: self.registered_egress_group.clone(),
+            rabbitmq_listener: self.rabbitmq_listener.clone(),
+            egress_leases: self.egress_leases.clone(),
+        }
+    }
+}
+
+impl ListenerResources {
+    /// Stops the room listeners and the RabbitMQ listener of a stopped session listener
+    /// and deregisters its device. What the room listeners recorded stays in the work directory.
+    pub async fn release(&self) {
+        for (egress_id, lease) in self.egress_leases.lock().await.drain() {
+            if let (Some(room_listener), false) = (&lease.room_listener, lease.listener_stopped) {
+                log::warn!(
+                    "Stopping room listener of egress {}, its recording is left in the work directory",
+                    egress_id
+                );
+                room_listener.do_send(RoomListenerMessages::StopListening);
+            }
+            if let Some(claim) = lease.claim {
+                claim.release(true);
+            }
+        }
+
+        if let Some(rmq_addr) = self.rabbitmq_listener.lock().await.as_ref() {
+            rmq_addr.do_send(RabbitMQListenerActorMessages::StopListening {
+                project_id: self.project_id.clone(),
+            });
+        }
+        if self.registered_egress_group.lock().await.is_none() {
+            return;
+        }
+        let deregistered = deregister(
+            self.project_client.clone(),
+            self.registered_egress_group.clone(),
+            self.rabbitmq_listener.clone(),
+        )
+        .await;
+        if let Err(e) = deregistered {
+            log::error!(
+                "Failed to deregister the device of project {}: {:?}",
+                self.project_id,
+                e
+            );
+        }
+    }
+}
+
+/// Reports a copy of the egress, so that no lock is held during the request.
+async fn report_egress_status(
+    reporter: &StatusReporter,
+    project_client: &Mutex<ProjectClient>,
+    project_id: &str,
+    info: TextEgressInfo,
+) {
+    if !reporter.is_enabled() {
+        return;
+    }
+    let api_token = project_client.lock().await.generate_api_token();
+    let reported = match api_token {
+        Ok(api_token) => reporter.report(&api_token, project_id, &info).await,
+        Err(e) => Err(e.into()),
+    };
+    if let Err(e) = reported {
+        log::error!(
+            "Failed to report status for egress_id: {:#?} to SyncFlow: {:#?}",
+            info.egress_id,
+            e
+        );
+    }
+}
+
 #[derive(Message, Debug)]
 #[rtype(result = "Result<(), TextEgressError>")]
 pub(crate) enum RoomListenerUpdates {
@@ -89,50 +291,162 @@ pub(crate) enum RoomListenerUpdates {
         room_name: String,
         topic: Option<String>,
         files: Vec<DataEgressResultFiles>,
+        span: Span,
     },
     Updated {
         egress_id: String,
         room_name: String,
         topic: Option<String>,
         files: Vec<DataEgressResultFiles>,
+        span: Span,
     },
     Failed {
         egress_id: String,
         error: TextEgressError,
+        /// The listener gave up on the room, no `Stopped` update follows
+        stopped: bool,
+        span: Span,
     },
     Stopped {
         egress_id: String,
         room_name: String,
         topic: Option<String>,
         files: Vec<DataEgressResultFiles>,
+        span: Span,
+    },
+    SegmentCompleted {
+        egress_id: String,
+        file: DataEgressResultFiles,
+        span: Span,
     },
 }
 
+impl RoomListenerUpdates {
+    fn span(&self) -> &Span {
+        match self {
+            RoomListenerUpdates::Started { span, .. }
+            | RoomListenerUpdates::Updated { span, .. }
+            | RoomListenerUpdates::Failed { span, .. }
+            | RoomListenerUpdates::Stopped { span, .. }
+            | RoomListenerUpdates::SegmentCompleted { span, .. } => span,
+        }
+    }
+}
+
 #[derive(Message, Debug)]
 #[rtype(result = "Result<(), TextEgressError>")]
-#[allow(dead_code)]
 pub(crate) enum S3UploaderUpdates {
     Started {
         egress_id: String,
         files: Vec<String>,
         bucket: String,
+        span: Span,
     },
     Completed {
         egress_id: String,
         files: Vec<String>,
         bucket: String,
+        span: Span,
     },
     Failed {
         egress_id: String,
         error: TextEgressError,
+        span: Span,
+    },
+    SegmentUploaded {
+        egress_id: String,
+        file_path: String,
+        object: ManifestObject,
+        span: Span,
     },
 }
 
+impl S3UploaderUpdates {
+    fn span(&self) -> &Span {
+        match self {
+            S3UploaderUpdates::Started { span, .. }
+            | S3UploaderUpdates::Completed { span, .. }
+            | S3UploaderUpdates::Failed { span, .. }
+            | S3UploaderUpdates::SegmentUploaded { span, .. } => span,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Message)]
 #[rtype(result = "Result<DeviceResponse, TextEgressError>")]
 pub enum ProjectMessages {
     Register,
     Deregister,
+    /// Stops accepting sessions and deregisters once running egresses have finished.
+    /// Room listeners still running after `timeout` are stopped and their recordings uploaded.
+    Drain {
+        timeout: Duration,
+    },
+}
+
+/// An egress as reported by the admin API.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EgressSummary {
+    pub egress_id: String,
+    pub session_id: String,
+    pub room_name: String,
+    pub topic: Option<String>,
+    pub status: TextEgressStatus,
+    pub started_at: Option<usize>,
+    pub stopped_at: Option<usize>,
+    pub error: Option<String>,
+    pub files: usize,
+}
+
+impl From<&TextEgressInfo> for EgressSummary {
+    fn from(info: &TextEgressInfo) -> Self {
+        EgressSummary {
+            egress_id: info.egress_id.clone(),
+            session_id: info.session_id.clone(),
+            room_name: info.room_name.clone(),
+            topic: info.topic.clone(),
+            status: info.status.clone(),
+            started_at: info.started_at,
+            stopped_at: info.stopped_at,
+            error: info.error.clone(),
+            files: info.files.len(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProjectStatus {
+    pub project_id: String,
+    pub registered: bool,
+    pub draining: bool,
+    pub egresses: Vec<EgressSummary>,
+}
+
+#[derive(Debug, Clone, Message)]
+#[rtype(result = "Result<ProjectStatus, TextEgressError>")]
+pub enum AdminMessages {
+    Status,
+    /// Stops recording an egress, which is then uploaded as usual.
+    StopEgress {
+        egress_id: String,
+    },
+}
+
+/// Asks for a new LiveKit token for a room listener that has to join its room again.
+#[derive(Debug, Clone, Message)]
+#[rtype(result = "Result<Secret, TextEgressError>")]
+pub(crate) struct JoinTokenRequest {
+    pub session_id: String,
+    pub room_name: String,
+    /// Replace the current token even if it has not expired, such as after it was refused
+    pub renew: bool,
+}
+
+#[derive(Debug, Clone, Message)]
+#[rtype(result = "Result<(), TextEgressError>")]
+pub enum ReloadMessages {
+    /// Replaces the S3 uploader, uploads already in progress finish with the old settings.
+    UpdateS3Config(S3Config),
 }
 
 #[derive(Debug, Clone, Message)]
@@ -156,6 +470,7 @@ impl Actor for SessionListenerActor {
 impl Handler<ProjectMessages> for SessionListenerActor {
     type Result = ResponseActFuture<Self, Result<DeviceResponse, TextEgressError>>;
 
+    #[allow(clippy::result_large_err)]
     fn handle(&mut self, msg: ProjectMessages, _ctx: &mut Self::Context) -> Self::Result {
         match msg {
             ProjectMessages::Register => {
@@ -168,52 +483,77 @@ impl Handler<ProjectMessages> for SessionListenerActor {
                 let actor_addr_arc = self.rabbitmq_listener.clone();
                 let s3_uploader_arc = self.s3_uploader.clone();
                 let s3_config = self.s3_config.clone();
+                let coordination = self.coordination.clone();
 
                 let fut = async move {
                     let client = client.lock().await;
                     let project = client.get_project_details().await?;
+                    let api_token = Secret::new(client.generate_api_token()?);
+                    // Built before registering, so that invalid S3 settings leave no device behind
+                    let s3_uploader_actor = S3UploaderActor::new(&s3_config, addr.clone())?;
+
                     let registration_request = DeviceRegisterRequest {
                         name: "text-egress".to_string(),
-                        group: "text-egress".to_string(),
+                        group: EGRESS_DEVICE_GROUP.to_string(),
                         comments: Some("Text Egress Actor".to_string()),
                     };
-
-                    let api_token = client.generate_api_token()?;
-
                     let egress_actor_response =
                         client.register_device(&registration_request).await?;
 
                     log::info!(
-                        "Registered session listener actor to {:#?} : {:#?}",
+                        "Registered session listener actor to {:#?} as device {} in group {}",
                         &project.name,
-                        egress_actor_response
+                        egress_actor_response.id,
+                        egress_actor_response.group
                     );
 
-                    let rmq_listener_actor = RabbitMQListenerActor::new(
+                    let rmq_listener_addr = RabbitMQListenerActor::new(
                         project.id.clone(),
                         egress_actor_response.id.clone(),
                         addr.clone(),
-                    );
-
-                    let rmq_listener_addr = rmq_listener_actor.start();
-
-                    rmq_listener_addr.do_send(RabbitMQListenerActorMessages::StartListening {
-                        project_id: project.id.clone(),
-                        group_name: egress_actor_response.group.clone(),
-                        api_token,
-                        rabbitmq_host,
-                        rabbitmq_port: port,
-                        rabbitmq_vhost_name: "syncflow".to_string(),
-                        use_ssl,
-                        exchange_name: egress_actor_response
-                            .session_notification_exchange_name
-                            .clone()
-                            .unwrap(),
-                        binding_key: egress_actor_response
-                            .session_notification_binding_key
-                            .clone()
-                            .unwrap(),
-                    });
+                    )
+                    .start();
+                    let listening = async {
+                        let missing = |field: &str| {
+                            TextEgressError::DeviceNotRegistered(format!(
+                                "device {} has no {}",
+                                egress_actor_response.id, field
+                            ))
+                        };
+                        let start_listening = RabbitMQListenerActorMessages::StartListening {
+                            project_id: project.id.clone(),
+                            group_name: egress_actor_response.group.clone(),
+                            api_token,
+                            rabbitmq_host,
+                            rabbitmq_port: port,
+                            rabbitmq_vhost_name: RABBITMQ_VHOST_NAME.to_string(),
+                            use_ssl,
+                            exchange_name: egress_actor_response
+                                .session_notification_exchange_name
+                                .clone()
+                                .ok_or_else(|| missing("session notification exchange"))?,
+                            binding_key: egress_actor_response
+                                .session_notification_binding_key
+                                .clone()
+                                .ok_or_else(|| missing("session notification binding key"))?,
+                            coordination,
+                        };
+                        rmq_listener_addr.send(start_listening).await?
+                    };
+                    if let Err(e) = listening.await {
+                        // Nobody would receive the notifications sent to this device
+                        if let Err(delete_error) =
+                            client.delete_device(&egress_actor_response.id).await
+                        {
+                            log::error!(
+                                "Failed to deregister device {} of project {}: {:?}",
+                                egress_actor_response.id,
+                                project.id,
+                                delete_error
+                            );
+                        }
+                        return Err(e);
+                    }
                     *actor_addr_arc.lock().await = Some(rmq_listener_addr);
 
                     let mut device_details = device_details_arc.lock().await;
@@ -235,53 +575,180 @@ impl Handler<ProjectMessages> for SessionListenerActor {
 
                     *device_details = Some(response);
 
-                    let s3_uploader_actor = S3UploaderActor::new(
-                        &s3_config.bucket_name,
-                        &s3_config.access_key,
-                        &s3_config.secret_key,
-                        &s3_config.region,
-                        &s3_config.endpoint,
-                        addr.clone(),
-                    );
-
-                    let s3_uploader_addr = s3_uploader_actor.start();
-
-                    *s3_uploader_arc.lock().await = Some(s3_uploader_addr);
+                    *s3_uploader_arc.lock().await = Some(s3_uploader_actor.start());
 
                     Ok(egress_actor_response)
                 };
 
-                Box::pin(fut.into_actor(self))
+                // A project that failed to register is started again by the registry
+                Box::pin(fut.into_actor(self).map(|result, _act, ctx| {
+                    if result.is_err() {
+                        ctx.stop();
+                    }
+                    result
+                }))
             }
             ProjectMessages::Deregister => {
+                let fut = deregister(
+                    self.project_client.clone(),
+                    self.registered_egress_group.clone(),
+                    self.rabbitmq_listener.clone(),
+                );
+
+                Box::pin(fut.into_actor(self))
+            }
+            ProjectMessages::Drain { timeout } => {
+                self.draining = true;
+                let project_id = self.project_id.clone();
                 let project_client = self.project_client.clone();
                 let device_details_arc = self.registered_egress_group.clone();
                 let rmq_actor_addr_arc = self.rabbitmq_listener.clone();
+                let egress_leases = self.egress_leases.clone();
 
                 let fut = async move {
-                    let device_details = device_details_arc.lock().await;
-                    let client = project_client.lock().await;
-                    let project = client.get_project_details().await?;
-                    let device = device_details.as_ref().ok_or_else(|| {
-                        TextEgressError::DeviceNotRegistered("Device not registered".to_string())
-                    })?;
-                    let deregistered_device = client.delete_device(&device.id).await?;
-                    log::info!(
-                        "Deregistered session listener actor from {:#?} : {:#?}",
-                        &project.name,
-                        deregistered_device
-                    );
-
-                    let rmq_addr = rmq_actor_addr_arc.lock().await;
-                    if rmq_addr.is_some() {
-                        rmq_addr.as_ref().unwrap().do_send(
-                            RabbitMQListenerActorMessages::StopListening {
-                                project_id: project.id.clone(),
-                            },
+                    if let Some(rmq_addr) = rmq_actor_addr_arc.lock().await.as_ref() {
+                        rmq_addr.do_send(RabbitMQListenerActorMessages::StopListening {
+                            project_id: project_id.clone(),
+                        });
+                    }
+                    let drained = wait_for_egresses(&project_id, &egress_leases);
+                    if tokio::time::timeout(timeout, drained).await.is_err() {
+                        log::warn!(
+                            "Project {} did not drain within {:?}, stopping its room listeners",
+                            project_id,
+                            timeout
                         );
+                        for lease in egress_leases.lock().await.values() {
+                            if let (Some(room_listener), false) =
+                                (&lease.room_listener, lease.listener_stopped)
+                            {
+                                room_listener.do_send(RoomListenerMessages::StopListening);
+                            }
+                        }
+                        // Stopped listeners upload what they recorded
+                        let uploaded = wait_for_egresses(&project_id, &egress_leases);
+                        if tokio::time::timeout(timeout, uploaded).await.is_err() {
+                            log::error!(
+                                "Project {} still has unfinished egresses, deregistering anyway",
+                                project_id
+                            );
+                        }
                     }
+                    deregister(project_client, device_details_arc, rmq_actor_addr_arc).await
+                };
+
+                Box::pin(fut.into_actor(self))
+            }
+        }
+    }
+}
+
+async fn deregister(
+    project_client: Arc<Mutex<ProjectClient>>,
+    device_details_arc: Arc<Mutex<Option<DeviceResponse>>>,
+    rmq_actor_addr_arc: Arc<Mutex<Option<Addr<RabbitMQListenerActor>>>>,
+) -> Result<DeviceResponse, TextEgressError> {
+    let device_details = device_details_arc.lock().await;
+    let client = project_client.lock().await;
+    let project = client.get_project_details().await?;
+    let device = device_details
+        .as_ref()
+        .ok_or_else(|| TextEgressError::DeviceNotRegistered("Device not registered".to_string()))?;
+    let deregistered_device = client.delete_device(&device.id).await?;
+    log::info!(
+        "Deregistered session listener actor from {:#?}, device {}",
+        &project.name,
+        deregistered_device.id
+    );
+
+    let rmq_addr = rmq_actor_addr_arc.lock().await;
+    if rmq_addr.is_some() {
+        rmq_addr
+            .as_ref()
+            .unwrap()
+            .do_send(RabbitMQListenerActorMessages::StopListening {
+                project_id: project.id.clone(),
+            });
+    }
+
+    Ok(deregistered_device)
+}
+
+impl Handler<AdminMessages> for SessionListenerActor {
+    type Result = ResponseActFuture<Self, Result<ProjectStatus, TextEgressError>>;
+
+    fn handle(&mut self, msg: AdminMessages, _ctx: &mut Self::Context) -> Self::Result {
+        let project_id = self.project_id.clone();
+        let draining = self.draining;
+        let registered_egress_group = self.registered_egress_group.clone();
+        let session_egresses = self.session_egresses.clone();
+        let egress_leases = self.egress_leases.clone();
+
+        let fut = async move {
+            if let AdminMessages::StopEgress { egress_id } = &msg {
+                let egress_leases = egress_leases.lock().await;
+                let room_listener = egress_leases
+                    .get(egress_id)
+                    .and_then(|lease| lease.room_listener.as_ref())
+                    .ok_or_else(|| TextEgressError::EgressNotFound(egress_id.clone()))?;
+                log::info!("Stopping egress {} on request", egress_id);
+                room_listener.do_send(RoomListenerMessages::StopListening);
+            }
+
+            let mut egresses: Vec<EgressSummary> = session_egresses
+                .lock()
+                .await
+                .values()
+                .map(EgressSummary::from)
+                .collect();
+            egresses.sort_by_key(|egress| egress.started_at);
+            Ok(ProjectStatus {
+                project_id,
+                registered: registered_egress_group.lock().await.is_some(),
+                draining,
+                egresses,
+            })
+        };
+
+        Box::pin(fut.into_actor(self))
+    }
+}
+
+impl Handler<JoinTokenRequest> for SessionListenerActor {
+    type Result = ResponseActFuture<Self, Result<Secret, TextEgressError>>;
+
+    fn handle(&mut self, msg: JoinTokenRequest, _ctx: &mut Self::Context) -> Self::Result {
+        let client = self.project_client.clone();
+        let join_tokens = self.join_tokens.clone();
+        let fut = async move {
+            let join_token = join_tokens
+                .get(&client, &msg.session_id, &msg.room_name, msg.renew)
+                .await?;
+            Ok(join_token.token)
+        };
+
+        Box::pin(fut.into_actor(self))
+    }
+}
 
-                    Ok(deregistered_device)
+impl Handler<ReloadMessages> for SessionListenerActor {
+    type Result = ResponseActFuture<Self, Result<(), TextEgressError>>;
+
+    fn handle(&mut self, msg: ReloadMessages, ctx: &mut Self::Context) -> Self::Result {
+        match msg {
+            ReloadMessages::UpdateS3Config(s3_config) => {
+                let s3_uploader = S3UploaderActor::new(&s3_config, ctx.address());
+                if s3_uploader.is_ok() {
+                    self.s3_config = s3_config;
+                }
+                let s3_uploader_arc = self.s3_uploader.clone();
+                let project_id = self.project_id.clone();
+
+                let fut = async move {
+                    let s3_uploader_addr = s3_uploader?.start();
+                    *s3_uploader_arc.lock().await = Some(s3_uploader_addr);
+                    log::info!("Rebuilt S3 uploader for project {}", project_id);
+                    Ok(())
                 };
 
                 Box::pin(fut.into_actor(self))
@@ -295,8 +762,14 @@ impl Handler<RoomListenerUpdates> for SessionListenerActor {
 
     fn handle(&mut self, msg: RoomListenerUpdates, _ctx: &mut Self::Context) -> Self::Result {
         let session_egresses = self.session_egresses.clone();
+        let egress_leases = self.egress_leases.clone();
+        let retry_failed_sessions = self.retry_failed_sessions;
         let s3_uploader_arc = self.s3_uploader.clone();
         let project_client_arc = self.project_client.clone();
+        let status_reporter = self.status_reporter.clone();
+        let project_id = self.project_id.clone();
+        let join_tokens = self.join_tokens.clone();
+        let span = tracing::info_span!(parent: msg.span(), "room_listener_update");
 
         let fut = async move {
             match msg {
@@ -305,32 +778,28 @@ impl Handler<RoomListenerUpdates> for SessionListenerActor {
                     room_name,
                     topic,
                     files,
+                    ..
                 } => {
                     let mut session_egresses = session_egresses.lock().await;
+                    let existing = session_egresses.get_mut(&egress_id);
 
-                    session_egresses.insert(
-                        egress_id.clone(),
-                        TextEgressInfo {
-                            egress_id,
-                            room_name,
-                            topic,
-                            started_at: Some(chrono::Utc::now().timestamp() as usize),
-                            stopped_at: None,
-                            files,
-                            error: None,
-                            status: TextEgressStatus::Started,
-                            paths: vec![],
-                            s3_bucket_name: None,
-                        },
-                    );
-
-                    Ok(())
+                    if let Some(active_egress) = existing {
+                        active_egress.room_name = room_name;
+                        active_egress.topic = topic;
+                        active_egress.files = files;
+                        active_egress.started_at = Some(chrono::Utc::now().timestamp() as usize);
+                        active_egress.status = TextEgressStatus::Started;
+                        Ok(())
+                    } else {
+                        Err(TextEgressError::EgressNotFound(egress_id))
+                    }
                 }
                 RoomListenerUpdates::Updated {
                     egress_id,
                     room_name,
                     topic,
                     files,
+                    ..
                 } => {
                     let mut session_egresses = session_egresses.lock().await;
                     let existing = session_egresses.get_mut(&egress_id);
@@ -342,13 +811,39 @@ impl Handler<RoomListenerUpdates> for SessionListenerActor {
                     }
                     Ok(())
                 }
-                RoomListenerUpdates::Failed { egress_id, error } => {
+                RoomListenerUpdates::Failed {
+                    egress_id,
+                    error,
+                    stopped,
+                    span,
+                } => {
+                    record_error(&span, &error);
+                    if stopped {
+                        release_egress(&egress_leases, &egress_id, retry_failed_sessions).await;
+                    }
                     let mut session_egresses = session_egresses.lock().await;
                     let existing = session_egresses.get_mut(&egress_id);
 
+                    let mut report = None;
                     if let Some(active_egress) = existing {
+                        // Only a stopped listener makes the egress final and retryable,
+                        // other errors are kept while it goes on recording
+                        if stopped {
+                            join_tokens.forget(&active_egress.session_id).await;
+                            active_egress.status = TextEgressStatus::Failed;
+                        }
                         active_egress.error = Some(error.to_string());
-                        active_egress.status = TextEgressStatus::Failed;
+                        report = Some(active_egress.clone());
+                    }
+                    drop(session_egresses);
+                    if let Some(report) = report {
+                        report_egress_status(
+                            &status_reporter,
+                            &project_client_arc,
+                            &project_id,
+                            report,
+                        )
+                        .await;
                     }
                     Ok(())
                 }
@@ -357,80 +852,172 @@ impl Handler<RoomListenerUpdates> for SessionListenerActor {
                     room_name,
                     topic,
                     files,
+                    ..
                 } => {
-                    let mut session_egresses = session_egresses.lock().await;
-                    let existing = session_egresses.get_mut(&egress_id);
-
-                    if let Some(active_egress) = existing {
-                        active_egress.files = files;
-                        active_egress.topic = topic;
-                        active_egress.room_name = room_name.clone();
-                        active_egress.stopped_at = Some(chrono::Utc::now().timestamp() as usize);
-                        active_egress.status = TextEgressStatus::Complete;
-
-                        let s3_uploader_addr = s3_uploader_arc.lock().await;
-                        let project_details = project_client_arc
-                            .lock()
-                            .await
-                            .get_project_details()
-                            .await?;
+                    if let Some(lease) = egress_leases.lock().await.get_mut(&egress_id) {
+                        lease.listener_stopped = true;
+                    }
+                    // Look everything up before touching the egress, without holding its lock
+                    let project_details =
+                        project_client_arc.lock().await.get_project_details().await;
+                    let s3_uploader_addr = s3_uploader_arc.lock().await.clone();
+
+                    let mut egresses = session_egresses.lock().await;
+                    let Some(active_egress) = egresses.get_mut(&egress_id) else {
+                        drop(egresses);
+                        release_egress(&egress_leases, &egress_id, false).await;
+                        return Err(TextEgressError::EgressNotFound(egress_id));
+                    };
+                    active_egress.files = files;
+                    active_egress.topic = topic;
+                    active_egress.room_name = room_name;
+                    active_egress.stopped_at = Some(chrono::Utc::now().timestamp() as usize);
 
-                        if s3_uploader_addr.is_some() {
-                            let uploader_addr = s3_uploader_addr.as_ref().unwrap();
+                    let upload = match (project_details, s3_uploader_addr) {
+                        (Ok(project_details), Some(uploader_addr)) => {
                             let files = active_egress
                                 .files
                                 .iter()
-                                .map(|f| f.file_path.clone())
+                                .filter(|file| {
+                                    !active_egress
+                                        .uploaded_segments
+                                        .contains_key(&file.file_path)
+                                })
+                                .cloned()
                                 .collect();
-
-                            let prefix = format!(
-                                "{}-{}/{}/{}/{}/{}",
-                                project_details.name,
-                                project_details.id,
-                                room_name,
-                                "text-egress",
-                                active_egress
-                                    .topic
-                                    .clone()
-                                    .unwrap_or_else(|| "all-topics".to_string()),
-                                &egress_id
+                            let mut manifest = EgressManifest::from(&*active_egress);
+                            manifest.objects =
+                                active_egress.uploaded_segments.values().cloned().collect();
+                            let prefix = egress_prefix(
+                                &project_details.name,
+                                &project_details.id,
+                                active_egress,
                             );
+                            Ok((uploader_addr, prefix, files, manifest))
+                        }
+                        (Err(e), _) => Err(e.into()),
+                        (Ok(_), None) => Err(TextEgressError::S3UploaderError(
+                            "S3 Uploader not available".to_string(),
+                        )),
+                    };
+                    active_egress.status = TextEgressStatus::Complete;
+                    let session_id = active_egress.session_id.clone();
+                    drop(egresses);
+                    join_tokens.forget(&session_id).await;
+
+                    let result = match upload {
+                        Ok((uploader_addr, prefix, files, manifest)) => uploader_addr
+                            .send(S3UploaderMessages::Start {
+                                prefix,
+                                egress_id: egress_id.clone(),
+                                files,
+                                manifest,
+                                span: Span::current(),
+                            })
+                            .await
+                            .map_err(TextEgressError::from),
+                        Err(e) => Err(e),
+                    };
 
-                            let _ = uploader_addr
-                                .send(S3UploaderMessages::Start {
-                                    prefix,
-                                    egress_id,
-                                    files,
-                                })
-                                .await;
-
-                            Ok(())
-                        } else {
-                            Err(TextEgressError::S3UploaderError(
-                                "S3 Uploader not available".to_string(),
-                            ))
+                    if let Err(e) = &result {
+                        release_egress(&egress_leases, &egress_id, retry_failed_sessions).await;
+                        let report = session_egresses.lock().await.get_mut(&egress_id).map(
+                            |active_egress| {
+                                active_egress.error = Some(e.to_string());
+                                active_egress.status = TextEgressStatus::Failed;
+                                active_egress.clone()
+                            },
+                        );
+                        if let Some(report) = report {
+                            report_egress_status(
+                                &status_reporter,
+                                &project_client_arc,
+                                &project_id,
+                                report,
+                            )
+                            .await;
                         }
-                    } else {
-                        Err(TextEgressError::EgressNotFound(egress_id))
                     }
+                    result
+                }
+                RoomListenerUpdates::SegmentCompleted {
+                    egress_id, file, ..
+                } => {
+                    // Look the project up before touching the egress, without holding its lock
+                    let project_details = project_client_arc
+                        .lock()
+                        .await
+                        .get_project_details()
+                        .await?;
+                    let egresses = session_egresses.lock().await;
+                    let active_egress = egresses
+                        .get(&egress_id)
+                        .ok_or_else(|| TextEgressError::EgressNotFound(egress_id.clone()))?;
+                    let prefix =
+                        egress_prefix(&project_details.name, &project_details.id, active_egress);
+                    drop(egresses);
+
+                    let s3_uploader_addr = s3_uploader_arc.lock().await;
+                    let uploader_addr = s3_uploader_addr.as_ref().ok_or_else(|| {
+                        TextEgressError::S3UploaderError("S3 Uploader not available".to_string())
+                    })?;
+                    uploader_addr.do_send(S3UploaderMessages::UploadSegment {
+                        prefix,
+                        egress_id,
+                        file,
+                        span: Span::current(),
+                    });
+                    Ok(())
                 }
             }
-        };
+        }
+        .instrument(span);
 
         Box::pin(fut.into_actor(self))
     }
 }
 
+/// The S3 key prefix of every object uploaded for an egress.
+pub(crate) fn egress_prefix(
+    project_name: &str,
+    project_id: &str,
+    egress: &TextEgressInfo,
+) -> String {
+    format!(
+        "{}-{}/{}/{}/{}/{}",
+        project_name,
+        project_id,
+        egress.room_name,
+        "text-egress",
+        egress
+            .topic
+            .clone()
+            .unwrap_or_else(|| "all-topics".to_string()),
+        &egress.egress_id
+    )
+}
+
 impl Handler<S3UploaderUpdates> for SessionListenerActor {
     type Result = ResponseActFuture<Self, Result<(), TextEgressError>>;
 
     fn handle(&mut self, msg: S3UploaderUpdates, _ctx: &mut Self::Context) -> Self::Result {
+        let session_egresses = self.session_egresses.clone();
+        let egress_leases = self.egress_leases.clone();
+        let retry_failed_sessions = self.retry_failed_sessions;
+        let project_client_arc = self.project_client.clone();
+        let status_reporter = self.status_reporter.clone();
+        let project_id = self.project_id.clone();
+        let span = tracing::info_span!(parent: msg.span(), "s3_uploader_update");
+
         let fut = async move {
+            let mut session_egresses = session_egresses.lock().await;
+            let mut report = None;
             match msg {
                 S3UploaderUpdates::Started {
                     egress_id,
                     files,
                     bucket,
+                    ..
                 } => {
                     log::info!(
                         "S3 Upload started for egress_id: {:#?} to bucket: {:#?} with files: {:#?}",
@@ -438,11 +1025,16 @@ impl Handler<S3UploaderUpdates> for SessionListenerActor {
                         bucket,
                         files
                     );
+                    if let Some(active_egress) = session_egresses.get_mut(&egress_id) {
+                        active_egress.status = TextEgressStatus::Uploading;
+                        active_egress.s3_bucket_name = Some(bucket);
+                    }
                 }
                 S3UploaderUpdates::Completed {
                     egress_id,
                     files,
                     bucket,
+                    ..
                 } => {
                     log::info!(
                         "S3 Upload completed for egress_id: {:#?} to bucket: {:#?}. Files : {:#?}",
@@ -450,17 +1042,57 @@ impl Handler<S3UploaderUpdates> for SessionListenerActor {
                         bucket,
                         files
                     );
+                    release_egress(&egress_leases, &egress_id, false).await;
+                    if let Some(active_egress) = session_egresses.get_mut(&egress_id) {
+                        active_egress.status = TextEgressStatus::Uploaded;
+                        active_egress.s3_bucket_name = Some(bucket);
+                        active_egress.paths = files;
+                        report = Some(active_egress.clone());
+                    }
                 }
-                S3UploaderUpdates::Failed { egress_id, error } => {
+                S3UploaderUpdates::Failed {
+                    egress_id,
+                    error,
+                    span,
+                } => {
+                    record_error(&span, &error);
                     log::error!(
                         "S3 Upload failed for egress_id: {:#?} with error: {:#?}",
                         egress_id,
                         error
                     );
+                    release_egress(&egress_leases, &egress_id, retry_failed_sessions).await;
+                    if let Some(active_egress) = session_egresses.get_mut(&egress_id) {
+                        active_egress.status = TextEgressStatus::Failed;
+                        active_egress.error = Some(error.to_string());
+                        report = Some(active_egress.clone());
+                    }
+                }
+                S3UploaderUpdates::SegmentUploaded {
+                    egress_id,
+                    file_path,
+                    object,
+                    ..
+                } => {
+                    log::info!(
+                        "Segment {:#?} uploaded for egress_id: {:#?} as {:#?}",
+                        file_path,
+                        egress_id,
+                        object.key
+                    );
+                    if let Some(active_egress) = session_egresses.get_mut(&egress_id) {
+                        active_egress.uploaded_segments.insert(file_path, object);
+                    }
                 }
             }
+            drop(session_egresses);
+            if let Some(report) = report {
+                report_egress_status(&status_reporter, &project_client_arc, &project_id, report)
+                    .await;
+            }
             Ok(())
-        };
+        }
+        .instrument(span);
         Box::pin(fut.into_actor(self))
     }
 }
@@ -470,39 +1102,144 @@ impl Handler<SessionCreatedMessage> for SessionListenerActor {
 
     fn handle(&mut self, msg: SessionCreatedMessage, _ctx: &mut Self::Context) -> Self::Result {
         log::info!("Received new session message: {:#?}", msg);
+        if self.draining {
+            log::info!(
+                "Project {} is draining, ignoring session {}",
+                self.project_id,
+                msg.session_id
+            );
+            if let Some(claim) = &msg.claim {
+                claim.release(true);
+            }
+            return Box::pin(async { Ok(()) }.into_actor(self));
+        }
         let client = self.project_client.clone();
+        let join_tokens = self.join_tokens.clone();
+        let session_egresses = self.session_egresses.clone();
         let parent_addr = _ctx.address();
+        let recording_config = self.recording_config.clone();
+        let admission = self.admission.clone();
+        let egress_leases = self.egress_leases.clone();
+        let retry_failed_sessions = self.retry_failed_sessions;
+        let status_reporter = self.status_reporter.clone();
+        let project_id = self.project_id.clone();
+        let span = tracing::info_span!(
+            parent: &msg.span,
+            "generate_session_token",
+            session_id = %msg.session_id
+        );
 
         let fut = async move {
-            let project_client = client.lock().await;
-
-            let session_token = project_client
-                .generate_session_token(
-                    &msg.session_id,
-                    &TokenRequest {
-                        identity: "text-egress-actor".to_string(),
-                        name: Some("Text Egress Actor".to_string()),
-                        video_grants: VideoGrantsWrapper {
-                            room: msg.session_name.clone(),
-                            room_join: true,
-                            room_create: false,
-                            can_subscribe: true,
-                            ..Default::default()
-                        },
+            let egress_id = Uuid::new_v4().to_string();
+            {
+                // Check and reserve under one lock, notifications can be delivered more than once
+                let mut session_egresses = session_egresses.lock().await;
+                let existing = session_egresses.values().find(|egress| {
+                    egress.session_id == msg.session_id
+                        && !(retry_failed_sessions && egress.status == TextEgressStatus::Failed)
+                });
+                if let Some(existing) = existing {
+                    log::info!(
+                        "Ignoring duplicate notification for session {}, egress {} is {:?}",
+                        msg.session_id,
+                        existing.egress_id,
+                        existing.status
+                    );
+                    let mut egress_leases = egress_leases.lock().await;
+                    match (egress_leases.get_mut(&existing.egress_id), msg.claim) {
+                        // A redelivery after reconnecting replaces the claim of the running egress
+                        (Some(lease), Some(claim)) => lease.claim = Some(claim),
+                        (None, Some(claim)) => claim.release(false),
+                        (_, None) => {}
+                    }
+                    return Ok(());
+                }
+                egress_leases.lock().await.insert(
+                    egress_id.clone(),
+                    EgressLease {
+                        claim: msg.claim.clone(),
+                        ..Default::default()
                     },
-                )
-                .await?;
-            let room_listener_actor =
-                RoomListenerActor::new(&Uuid::new_v4().to_string(), parent_addr);
-            let room_listener_addr = room_listener_actor.start();
-            room_listener_addr.do_send(RoomListenerMessages::StartListening {
-                join_token: session_token.token.clone(),
-                server_url: session_token.livekit_server_url.clone().unwrap(),
-                room_name: msg.session_name.clone(),
-                topic: None,
-            });
-            Ok(())
-        };
+                );
+                session_egresses.insert(
+                    egress_id.clone(),
+                    TextEgressInfo {
+                        egress_id: egress_id.clone(),
+                        session_id: msg.session_id.clone(),
+                        room_name: msg.session_name.clone(),
+                        topic: None,
+                        started_at: None,
+                        stopped_at: None,
+                        files: vec![],
+                        error: None,
+                        status: TextEgressStatus::Starting,
+                        paths: vec![],
+                        s3_bucket_name: None,
+                        uploaded_segments: HashMap::new(),
+                    },
+                );
+            }
+
+            let started = async {
+                let permit = admission
+                    .admit(&msg.session_id)
+                    .instrument(tracing::info_span!("admission"))
+                    .await?;
+
+                let join_token = join_tokens
+                    .get(&client, &msg.session_id, &msg.session_name, false)
+                    .await?;
+                let server_url = join_token.server_url.ok_or_else(|| {
+                    TextEgressError::JoinTokenError(format!(
+                        "no LiveKit server URL for session {}",
+                        msg.session_id
+                    ))
+                })?;
+                if let Some(lease) = egress_leases.lock().await.get_mut(&egress_id) {
+                    lease.permit = Some(permit);
+                }
+                let room_listener_actor = RoomListenerActor::new(
+                    &egress_id,
+                    parent_addr.into(),
+                    &recording_config,
+                    admission.work_dir(),
+                    Arc::new(LiveKitRoomSource),
+                );
+                let room_listener_addr = Supervisor::start(|_| room_listener_actor);
+                room_listener_addr.do_send(RoomListenerMessages::StartListening {
+                    session_id: msg.session_id.clone(),
+                    join_token: join_token.token,
+                    server_url,
+                    room_name: msg.session_name.clone(),
+                    topic: None,
+                    span: Span::current(),
+                });
+                if let Some(lease) = egress_leases.lock().await.get_mut(&egress_id) {
+                    lease.room_listener = Some(room_listener_addr);
+                }
+                Ok(())
+            }
+            .await;
+
+            if let Err(e) = &started {
+                record_error(&Span::current(), e);
+                release_egress(&egress_leases, &egress_id, retry_failed_sessions).await;
+                let report = session_egresses
+                    .lock()
+                    .await
+                    .get_mut(&egress_id)
+                    .map(|egress| {
+                        egress.status = TextEgressStatus::Failed;
+                        egress.error = Some(e.to_string());
+                        egress.clone()
+                    });
+                if let Some(report) = report {
+                    report_egress_status(&status_reporter, &client, &project_id, report).await;
+                }
+            }
+            started
+        }
+        .instrument(span);
 
         Box::pin(fut.into_actor(self))
     }
@@ -513,6 +1250,9 @@ impl Handler<ConnectionMessages> for SessionListenerActor {
 
     fn handle(&mut self, msg: ConnectionMessages, _ctx: &mut Self::Context) -> Self::Result {
         match msg {
+            ConnectionMessages::RefreshConnection if self.draining => {
+                Box::pin(async { Ok(()) }.into_actor(self))
+            }
             ConnectionMessages::RefreshConnection => {
                 let client = self.project_client.clone();
                 let rmq_listener_addr_arc = self.rabbitmq_listener.clone();
@@ -520,6 +1260,7 @@ impl Handler<ConnectionMessages> for SessionListenerActor {
                 let rmq_port = self.port;
                 let rmq_use_ssl = self.use_ssl;
                 let registered_egress_group = self.registered_egress_group.clone();
+                let coordination = self.coordination.clone();
 
                 let fut = async move {
                     log::info!("Refreshing connection for SessionListenerActor");
@@ -532,10 +1273,7 @@ impl Handler<ConnectionMessages> for SessionListenerActor {
                         (rmq_listener.as_ref(), device_details.as_ref())
                     {
                         let project_details = client.get_project_details().await?;
-                        addr.do_send(RabbitMQListenerActorMessages::StopListening {
-                            project_id: project_details.id.clone(),
-                        });
-                        log::info!("Stopped RabbitMQ listener actor");
+                        // The listener closes the previous connection once the new queue is bound
                         let exchange_name = device
                             .session_notification_exchange_name
                             .clone()
@@ -547,14 +1285,15 @@ impl Handler<ConnectionMessages> for SessionListenerActor {
 
                         addr.do_send(RabbitMQListenerActorMessages::StartListening {
                             project_id: project_details.id.clone(),
-                            group_name: "text-egress".into(),
-                            api_token: client.generate_api_token()?,
+                            group_name: EGRESS_DEVICE_GROUP.into(),
+                            api_token: Secret::new(client.generate_api_token()?),
                             rabbitmq_host: rmq_host,
                             rabbitmq_port: rmq_port,
-                            rabbitmq_vhost_name: "syncflow".into(),
+                            rabbitmq_vhost_name: RABBITMQ_VHOST_NAME.into(),
                             use_ssl: rmq_use_ssl,
                             exchange_name,
                             binding_key,
+                            coordination,
                         });
                     }
                     Ok(())
@@ -571,6 +1310,9 @@ pub struct SessionCreatedMessage {
     pub session_id: String,
     pub session_name: String,
     pub project_id: String,
+    /// Held until the egress finishes when instances share a queue
+    pub claim: Option<Arc<dyn SessionClaim>>,
+    pub span: Span,
 }
 
 #[derive(Debug, Clone, Message)]
@@ -579,13 +1321,14 @@ pub enum RabbitMQListenerActorMessages {
     StartListening {
         project_id: String,
         group_name: String,
-        api_token: String,
+        api_token: Secret,
         rabbitmq_host: String,
         rabbitmq_port: u16,
         rabbitmq_vhost_name: String,
         use_ssl: bool,
         exchange_name: String,
         binding_key: String,
+        coordination: CoordinationConfig,
     },
 
     StopListening {
@@ -597,7 +1340,89 @@ pub struct RabbitMQListenerActor {
     pub project_id: String,
     pub device_id: String,
     parent_addr: Addr<SessionListenerActor>,
-    connection: Arc<Mutex<Option<Connection>>>,
+    consumer: Arc<Mutex<Option<NotificationConsumer>>>,
+    recent_notifications: Arc<Mutex<RecentNotifications>>,
+}
+
+/// The connection and channel session notifications are currently consumed on.
+struct NotificationConsumer {
+    connection: Arc<ConnectionLease>,
+    channel: Channel,
+    consumer_tag: String,
+}
+
+impl NotificationConsumer {
+    /// Stops consuming, the connection closes once the claims taken on it are released.
+    async fn retire(self) {
+        let cancel = BasicCancelArguments::new(&self.consumer_tag);
+        if let Err(e) = self.channel.basic_cancel(cancel).await {
+            log::warn!(
+                "Failed to cancel the session notification consumer: {:?}",
+                e
+            );
+        }
+        drop(self.connection);
+    }
+}
+
+/// Notifications delivered while a refresh overlaps two connections, when both of their
+/// queues receive every published notification. Outside the overlap nothing is dropped,
+/// so repeated notifications for a session, such as to retry it, are always delivered.
+#[derive(Debug, Default)]
+struct RecentNotifications {
+    /// The latest connection, counted from 1
+    connection: u64,
+    /// The previous connection and until when it may still deliver copies
+    overlap: Option<(u64, Instant)>,
+    /// Messages delivered during the overlap, by connection
+    seen: Vec<(u64, String)>,
+}
+
+impl RecentNotifications {
+    /// Counts a new connection, overlapping the previous one if it is still consuming.
+    fn connected(&mut self, overlaps_previous: bool) -> u64 {
+        self.connection += 1;
+        self.seen.clear();
+        self.overlap = overlaps_previous.then(|| {
+            (
+                self.connection - 1,
+                Instant::now() + DUPLICATE_NOTIFICATION_WINDOW,
+            )
+        });
+        self.connection
+    }
+
+    /// Ends the overlap once the previous connection delivered its last notification.
+    fn closed(&mut self, connection: u64) {
+        if self
+            .overlap
+            .is_some_and(|(previous, _)| previous == connection)
+        {
+            self.overlap = None;
+            self.seen.clear();
+        }
+    }
+
+    /// Whether the message was not already delivered on the other connection of the overlap.
+    fn first_delivery(&mut self, connection: u64, message: &str) -> bool {
+        match self.overlap {
+            Some((_, until)) if Instant::now() <= until => {}
+            _ => {
+                self.overlap = None;
+                self.seen.clear();
+                return true;
+            }
+        }
+        if self
+            .seen
+            .iter()
+            .any(|(seen_on, seen)| *seen_on != connection && seen == message)
+        {
+            return false;
+        }
+        self.seen.push((connection, message.to_string()));
+        true
+    }
 }
 
 impl RabbitMQListenerActor {
@@ -610,7 +1435,8 @@ impl RabbitMQListenerActor {
             project_id,
             device_id,
             parent_addr,
-            connection: Arc::new(Mutex::new(None)),
+            consumer: Arc::new(Mutex::new(None)),
+            recent_notifications: Arc::new(Mutex::new(RecentNotifications::default())),
         }
     }
 }
@@ -626,6 +1452,24 @@ impl Actor for RabbitMQListenerActor {
     }
 }
 
+/// Opens a connection to the SyncFlow RabbitMQ broker, authenticating with a project API token.
+pub async fn open_rabbitmq_connection(
+    rabbitmq_host: &str,
+    rabbitmq_port: u16,
+    rabbitmq_vhost_name: &str,
+    group_name: &str,
+    api_token: &Secret,
+    use_ssl: bool,
+) -> Result<Connection, TextEgressError> {
+    let mut args =
+        OpenConnectionArguments::new(rabbitmq_host, rabbitmq_port, api_token.expose(), group_name);
+    args.virtual_host(rabbitmq_vhost_name);
+    if use_ssl {
+        args.tls_adaptor(TlsAdaptor::without_client_auth(None, rabbitmq_host.to_string()).unwrap());
+    }
+    Ok(Connection::open(&args).await?)
+}
+
 impl Handler<RabbitMQListenerActorMessages> for RabbitMQListenerActor {
     type Result = ResponseActFuture<Self, Result<(), TextEgressError>>;
 
@@ -645,69 +1489,82 @@ impl Handler<RabbitMQListenerActorMessages> for RabbitMQListenerActor {
                 use_ssl,
                 exchange_name,
                 binding_key,
+                coordination,
             } => {
                 log::info!("Starting RabbitMQ listener for project: {:#?}", project_id);
-                let conn = self.connection.clone();
+                let consumer_arc = self.consumer.clone();
                 let parent_addr = self.parent_addr.clone();
+                let recent_notifications = self.recent_notifications.clone();
                 let fut = async move {
-                    let args = if use_ssl {
-                        OpenConnectionArguments::new(
-                            &rabbitmq_host,
-                            rabbitmq_port,
-                            &api_token,
-                            &group_name,
-                        )
-                        .virtual_host(&rabbitmq_vhost_name)
-                        .tls_adaptor(
-                            TlsAdaptor::without_client_auth(None, rabbitmq_host.to_string())
-                                .unwrap(),
-                        )
-                        .finish()
-                    } else {
-                        OpenConnectionArguments::new(
-                            &rabbitmq_host,
-                            rabbitmq_port,
-                            &api_token,
-                            &group_name,
-                        )
-                        .virtual_host(&rabbitmq_vhost_name)
-                        .finish()
-                    };
-
-                    let connection = Connection::open(&args).await?;
-
-                    let channel = Arc::new(Mutex::new(connection.open_channel(None).await?));
-                    *conn.lock().await = Some(connection);
-
-                    let queue_declare_args = QueueDeclareArguments::default()
-                        .exclusive(true)
-                        .auto_delete(true)
-                        .finish();
-
-                    let (queue_name, _, _) = channel
-                        .lock()
-                        .await
-                        .queue_declare(queue_declare_args)
-                        .await?
-                        .ok_or_else(|| {
+                    let connection = open_rabbitmq_connection(
+                        &rabbitmq_host,
+                        rabbitmq_port,
+                        &rabbitmq_vhost_name,
+                        &group_name,
+                        &api_token,
+                        use_ssl,
+                    )
+                    .await?;
+
+                    let shared_queue = coordination.mode == CoordinationMode::SharedQueue;
+                    let consumer = async {
+                        let channel = connection.open_channel(None).await?;
+                        let queue_declare_args = if shared_queue {
+                            // Replicas compete for deliveries on one durable queue
+                            QueueDeclareArguments::new(&coordination.queue_name(&project_id))
+                                .durable(true)
+                                .finish()
+                        } else {
+                            QueueDeclareArguments::default()
+                                .exclusive(true)
+                                .auto_delete(true)
+                                .finish()
+                        };
+
+                        let (queue_name, _, _) = channel
+                            .queue_declare(queue_declare_args)
+                            .await?
+                            .ok_or_else(|| {
                             TextEgressError::AMQPError(amqprs::error::Error::ChannelUseError(
                                 "Failed to declare queue".to_string(),
                             ))
                         })?;
 
-                    let queue_bind_args =
-                        QueueBindArguments::new(&queue_name, &exchange_name, &binding_key);
-                    channel.lock().await.queue_bind(queue_bind_args).await?;
-
-                    let cloned_channel = Arc::clone(&channel);
-                    let cloned_queue = queue_name.clone();
+                        let queue_bind_args =
+                            QueueBindArguments::new(&queue_name, &exchange_name, &binding_key);
+                        channel.queue_bind(queue_bind_args).await?;
+
+                        if shared_queue && coordination.max_claimed_sessions > 0 {
+                            channel
+                                .basic_qos(BasicQosArguments::new(
+                                    0,
+                                    coordination.max_claimed_sessions,
+                                    false,
+                                ))
+                                .await?;
+                        }
 
-                    let consume_args = BasicConsumeArguments::new(&cloned_queue, "text-egress");
-                    let result = cloned_channel
-                        .lock()
-                        .await
-                        .basic_consume_rx(consume_args)
-                        .await;
+                        let mut consume_args =
+                            BasicConsumeArguments::new(&queue_name, "text-egress");
+                        if shared_queue {
+                            consume_args.manual_ack(true);
+                        }
+                        let (consumer_tag, rx) = channel.basic_consume_rx(consume_args).await?;
+                        Ok::<_, TextEgressError>((channel, consumer_tag, queue_name, rx))
+                    }
+                    .await;
+                    let (claim_channel, consumer_tag, queue_name, mut rx) = match consumer {
+                        Ok(consumer) => consumer,
+                        Err(e) => {
+                            log::error!(
+                                "Failed to consume session notifications for project {}, keeping the previous connection: {:?}",
+                                project_id,
+                                e
+                            );
+                            let _ = connection.close().await;
+                            return Err(e);
+                        }
+                    };
 
                     log::info!(
                         "Listening for the project with id: {:#?} with queue: {:#?}",
@@ -715,18 +1572,122 @@ impl Handler<RabbitMQListenerActorMessages> for RabbitMQListenerActor {
                         queue_name
                     );
 
-                    let (_, mut rx) = result?;
+                    // Retired only now that the new queue is bound, so no notification falls in between.
+                    // Claims keep their connection open, the broker would requeue them on close.
+                    let connection = ConnectionLease::new(connection);
+                    let claim_connection = Arc::downgrade(&connection);
+                    let previous = consumer_arc.lock().await.replace(NotificationConsumer {
+                        connection,
+                        channel: claim_channel.clone(),
+                        consumer_tag,
+                    });
+                    let generation = recent_notifications
+                        .lock()
+                        .await
+                        .connected(previous.is_some());
+                    if let Some(previous) = previous {
+                        log::info!(
+                            "Retiring the previous RabbitMQ connection of project {}",
+                            project_id
+                        );
+                        previous.retire().await;
+                    }
 
-                    while let Some(msg) = rx.recv().await {
-                        let session_message =
-                            serde_json::from_slice::<NewSessionMessage>(&msg.content.unwrap())?;
+                    // Answered once consuming started, notifications are handled in the background
+                    actix::spawn(async move {
+                        while let Some(msg) = rx.recv().await {
+                            if !parent_addr.connected() {
+                                // Unacknowledged deliveries are requeued when the connection closes
+                                log::warn!(
+                                    "Session listener of project {} stopped, closing its RabbitMQ connection",
+                                    project_id
+                                );
+                                if let Some(consumer) = consumer_arc.lock().await.take() {
+                                    consumer.retire().await;
+                                }
+                                break;
+                            }
+                            // Copies on both connections share the message id, or at least the content
+                            let content = msg.content.unwrap_or_default();
+                            let message_key = msg
+                                .basic_properties
+                                .as_ref()
+                                .and_then(|properties| properties.message_id().cloned())
+                                .unwrap_or_else(|| String::from_utf8_lossy(&content).into_owned());
+                            let session_message = match serde_json::from_slice::<NewSessionMessage>(
+                                &content,
+                            ) {
+                                Ok(session_message) => session_message,
+                                Err(e) => {
+                                    log::error!(
+                                        "Ignoring malformed session notification for project {}: {:?}",
+                                        project_id,
+                                        e
+                                    );
+                                    // Rejected, or it would hold a prefetch slot and move between replicas
+                                    if let (Some(deliver), true) = (&msg.deliver, shared_queue) {
+                                        let reject = BasicNackArguments::new(
+                                            deliver.delivery_tag(),
+                                            false,
+                                            false,
+                                        );
+                                        if let Err(e) = claim_channel.basic_nack(reject).await {
+                                            log::warn!(
+                                                "Failed to reject a malformed session notification: {:?}",
+                                                e
+                                            );
+                                        }
+                                    }
+                                    continue;
+                                }
+                            };
+                            // A shared queue delivers each notification once, redeliveries replace claims
+                            if !shared_queue
+                                && !recent_notifications
+                                    .lock()
+                                    .await
+                                    .first_delivery(generation, &message_key)
+                            {
+                                log::debug!(
+                                    "Ignoring notification for session {} delivered on both connections",
+                                    session_message.session_id
+                                );
+                                continue;
+                            }
+
+                            let span = tracing::info_span!(
+                                "session_notification",
+                                project_id = %project_id,
+                                session_id = %session_message.session_id,
+                                session_name = %session_message.session_name
+                            );
 
-                        parent_addr.do_send(SessionCreatedMessage {
-                            session_id: session_message.session_id,
-                            session_name: session_message.session_name,
-                            project_id: project_id.clone(),
-                        });
-                    }
+                            let claim = match (&msg.deliver, shared_queue) {
+                                (Some(deliver), true) => {
+                                    // Gone once the connection was retired without claims and closed
+                                    let Some(connection) = claim_connection.upgrade() else {
+                                        break;
+                                    };
+                                    Some(Arc::new(AmqpSessionClaim::new(
+                                        claim_channel.clone(),
+                                        deliver.delivery_tag(),
+                                        connection,
+                                    ))
+                                        as Arc<dyn SessionClaim>)
+                                }
+                                _ => None,
+                            };
+
+                            parent_addr.do_send(SessionCreatedMessage {
+                                session_id: session_message.session_id,
+                                session_name: session_message.session_name,
+                                project_id: project_id.clone(),
+                                claim,
+                                span,
+                            });
+                        }
+                        recent_notifications.lock().await.closed(generation);
+                    });
 
                     Ok(())
                 };
@@ -735,14 +1696,12 @@ impl Handler<RabbitMQListenerActorMessages> for RabbitMQListenerActor {
             }
             RabbitMQListenerActorMessages::StopListening { project_id } => {
                 log::debug!("Stopping RabbitMQ listener for project: {:#?}", project_id);
-                let conn = self.connection.clone();
+                let consumer = self.consumer.clone();
                 let fut = async move {
-                    let connection = conn.lock().await;
-                    if connection.is_none() {
-                    } else {
-                        let conn = connection.as_ref().unwrap();
-                        conn.clone().close().await?;
-                        log::info!("RabbitMQ connection closed for {:#?}", project_id);
+                    // Claimed sessions go on recording, their connection closes once they finish
+                    if let Some(consumer) = consumer.lock().await.take() {
+                        consumer.retire().await;
+                        log::info!("RabbitMQ listener stopped for {:#?}", project_id);
                     }
                     Ok(())
                 };
@@ -752,3 +1711,107 @@ impl Handler<RabbitMQListenerActorMessages> for RabbitMQListenerActor {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::room_listener_actor::RoomListenerParent;
+    use crate::room_source::ScriptedRoomSource;
+    use tempdir::TempDir;
+    use tokio::sync::mpsc;
+
+    fn resources() -> ListenerResources {
+        ListenerResources {
+            project_id: "project".to_string(),
+            project_client: Arc::new(Mutex::new(ProjectClient::new(
+                "http://127.0.0.1:9",
+                "project",
+                "key",
+                "secret",
+            ))),
+            registered_egress_group: Arc::new(Mutex::new(None)),
+            rabbitmq_listener: Arc::new(Mutex::new(None)),
+            egress_leases: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn releasing_a_stopped_listener_stops_its_room_listeners() {
+        let work_dir = TempDir::new("session-listener").unwrap();
+        let (sender, mut updates) = mpsc::unbounded_channel();
+        let parent = RoomListenerParent::forwarding_to(sender);
+        // Stays in the room until it is stopped
+        let source = ScriptedRoomSource::new().connection(vec![]);
+        let room_listener = RoomListenerActor::new(
+            "egress",
+            parent,
+            &RecordingConfig::default(),
+            work_dir.path(),
+            Arc::new(source.clone()),
+        )
+        .start();
+        room_listener.do_send(RoomListenerMessages::StartListening {
+            session_id: "session".to_string(),
+            join_token: Secret::new("token"),
+            server_url: "ws://localhost:7880".to_string(),
+            room_name: "room".to_string(),
+            topic: None,
+            span: Span::none(),
+        });
+        while source.joins() == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let resources = resources();
+        resources.egress_leases.lock().await.insert(
+            "egress".to_string(),
+            EgressLease {
+                room_listener: Some(room_listener),
+                ..Default::default()
+            },
+        );
+        resources.release().await;
+
+        assert!(resources.egress_leases.lock().await.is_empty());
+        loop {
+            let update = tokio::time::timeout(Duration::from_secs(10), updates.recv())
+                .await
+                .expect("the room listener was not stopped")
+                .expect("the room listener dropped its parent");
+            if let RoomListenerUpdates::Stopped { egress_id, .. } = update {
+                assert_eq!(egress_id, "egress");
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn drops_copies_delivered_on_both_connections_of_a_refresh() {
+        let mut notifications = RecentNotifications::default();
+        let first = notifications.connected(false);
+        assert!(notifications.first_delivery(first, "message-1"));
+
+        let second = notifications.connected(true);
+        assert!(notifications.first_delivery(first, "message-2"));
+        assert!(!notifications.first_delivery(second, "message-2"));
+        assert!(notifications.first_delivery(second, "message-3"));
+        assert!(!notifications.first_delivery(first, "message-3"));
+        // Redeliveries on the same connection are not copies
+        assert!(notifications.first_delivery(second, "message-3"));
+
+        notifications.closed(first);
+        assert!(notifications.first_delivery(second, "message-2"));
+    }
+
+    #[test]
+    fn keeps_repeated_notifications_outside_a_refresh() {
+        let mut notifications = RecentNotifications::default();
+        let first = notifications.connected(false);
+        assert!(notifications.first_delivery(first, "session"));
+        assert!(notifications.first_delivery(first, "session"));
+
+        // Stopped and started again, nothing overlaps
+        let second = notifications.connected(false);
+        assert!(notifications.first_delivery(second, "session"));
+    }
+}
@@ -0,0 +1,193 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::error_messages::TextEgressError;
+use crate::session_listener_actor::{TextEgressInfo, TextEgressStatus};
+
+/// The egress status attached to a session on the SyncFlow server.
+#[derive(Debug, Clone, Serialize)]
+pub struct TextEgressReport {
+    pub egress_id: String,
+    pub session_id: String,
+    pub room_name: String,
+    pub topic: Option<String>,
+    pub status: TextEgressStatus,
+    pub bucket: Option<String>,
+    pub keys: Vec<String>,
+    pub error: Option<String>,
+    pub started_at: Option<usize>,
+    pub stopped_at: Option<usize>,
+}
+
+impl From<&TextEgressInfo> for TextEgressReport {
+    fn from(info: &TextEgressInfo) -> Self {
+        TextEgressReport {
+            egress_id: info.egress_id.clone(),
+            session_id: info.session_id.clone(),
+            room_name: info.room_name.clone(),
+            topic: info.topic.clone(),
+            status: info.status.clone(),
+            bucket: info.s3_bucket_name.clone(),
+            keys: info.paths.clone(),
+            error: info.error.clone(),
+            started_at: info.started_at,
+            stopped_at: info.stopped_at,
+        }
+    }
+}
+
+/// Where egress statuses are posted. SyncFlow has no text egress API yet, so
+/// reporting is disabled unless an endpoint is configured.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StatusReportingConfig {
+    /// URL template, `{project_id}`, `{session_id}` and `{egress_id}` are substituted
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+impl Default for StatusReportingConfig {
+    fn default() -> Self {
+        StatusReportingConfig {
+            endpoint: None,
+            timeout_secs: default_timeout_secs(),
+        }
+    }
+}
+
+fn default_timeout_secs() -> u64 {
+    10
+}
+
+pub(crate) struct StatusReporter {
+    endpoint: Option<String>,
+    http_client: reqwest::Client,
+}
+
+impl StatusReporter {
+    pub fn new(config: &StatusReportingConfig) -> Self {
+        let http_client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.timeout_secs))
+            .build()
+            .unwrap_or_default();
+        StatusReporter {
+            endpoint: config.endpoint.clone(),
+            http_client,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.endpoint.is_some()
+    }
+
+    pub async fn report(
+        &self,
+        api_token: &str,
+        project_id: &str,
+        info: &TextEgressInfo,
+    ) -> Result<(), TextEgressError> {
+        let Some(endpoint) = &self.endpoint else {
+            return Ok(());
+        };
+        let report = TextEgressReport::from(info);
+        let url = endpoint
+            .replace("{project_id}", project_id)
+            .replace("{session_id}", &report.session_id)
+            .replace("{egress_id}", &report.egress_id);
+        self.http_client
+            .post(&url)
+            .bearer_auth(api_token)
+            .json(&report)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        log::info!(
+            "Reported egress {} as {:?} for session {}",
+            report.egress_id,
+            report.status,
+            report.session_id
+        );
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+    use std::collections::HashMap;
+    use tokio::sync::mpsc;
+
+    fn egress() -> TextEgressInfo {
+        TextEgressInfo {
+            egress_id: "egress".to_string(),
+            session_id: "session".to_string(),
+            room_name: "room".to_string(),
+            topic: None,
+            started_at: Some(1),
+            stopped_at: Some(2),
+            files: vec![],
+            error: None,
+            status: TextEgressStatus::Complete,
+            paths: vec!["prefix/alice.txt".to_string()],
+            s3_bucket_name: Some("bucket".to_string()),
+            uploaded_segments: HashMap::new(),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn posts_the_egress_to_the_configured_endpoint() {
+        let (sender, mut reports) = mpsc::unbounded_channel();
+        let server = HttpServer::new(move || {
+            let sender = sender.clone();
+            App::new().default_service(web::to(
+                move |request: HttpRequest, body: web::Json<serde_json::Value>| {
+                    let authorization = request
+                        .headers()
+                        .get("Authorization")
+                        .and_then(|value| value.to_str().ok())
+                        .map(str::to_string);
+                    let _ = sender.send((request.path().to_string(), authorization, body.0));
+                    async { HttpResponse::Ok().finish() }
+                },
+            ))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let address = server.addrs()[0];
+        actix_rt::spawn(server.run());
+
+        let reporter = StatusReporter::new(&StatusReportingConfig {
+            endpoint: Some(format!(
+                "http://{}/projects/{{project_id}}/sessions/{{session_id}}/egresses/{{egress_id}}",
+                address
+            )),
+            ..Default::default()
+        });
+        assert!(reporter.is_enabled());
+        reporter
+            .report("api-token", "project", &egress())
+            .await
+            .unwrap();
+
+        let (path, authorization, report) = reports.recv().await.unwrap();
+        assert_eq!(path, "/projects/project/sessions/session/egresses/egress");
+        assert_eq!(authorization.as_deref(), Some("Bearer api-token"));
+        assert_eq!(report["status"], "complete");
+        assert_eq!(report["keys"], serde_json::json!(["prefix/alice.txt"]));
+    }
+
+    #[actix_rt::test]
+    async fn reports_nothing_without_an_endpoint() {
+        let reporter = StatusReporter::new(&StatusReportingConfig::default());
+        assert!(!reporter.is_enabled());
+        reporter
+            .report("api-token", "project", &egress())
+            .await
+            .unwrap();
+    }
+}
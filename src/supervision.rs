@@ -0,0 +1,119 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// How often a room listener rejoins its room after losing the connection or panicking,
+/// before the egress is finalized with what was recorded so far.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RestartPolicy {
+    /// Restarts allowed per egress, 0 disables restarting
+    #[serde(default = "default_max_restarts")]
+    pub max_restarts: u32,
+    /// Delay before the first restart, doubled for every further one
+    #[serde(default = "default_initial_delay_ms")]
+    pub initial_delay_ms: u64,
+    #[serde(default = "default_max_delay_ms")]
+    pub max_delay_ms: u64,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        RestartPolicy {
+            max_restarts: default_max_restarts(),
+            initial_delay_ms: default_initial_delay_ms(),
+            max_delay_ms: default_max_delay_ms(),
+        }
+    }
+}
+
+fn default_max_restarts() -> u32 {
+    5
+}
+
+fn default_initial_delay_ms() -> u64 {
+    1000
+}
+
+fn default_max_delay_ms() -> u64 {
+    30_000
+}
+
+impl RestartPolicy {
+    /// The delay before restart number `restart`, counting from 1, or `None` once
+    /// the restarts are used up.
+    pub fn delay(&self, restart: u32) -> Option<Duration> {
+        if restart == 0 || restart > self.max_restarts {
+            return None;
+        }
+        let delay = Duration::from_millis(self.initial_delay_ms)
+            .saturating_mul(2u32.saturating_pow(restart - 1))
+            .min(Duration::from_millis(self.max_delay_ms));
+        Some(delay)
+    }
+}
+
+/// How long a listener that lost its connection keeps its files open while it rejoins the room.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReconnectConfig {
+    /// Time to rejoin the room before the listener is restarted
+    #[serde(default = "default_grace_period_secs")]
+    pub grace_period_secs: u64,
+    #[serde(default = "default_retry_interval_ms")]
+    pub retry_interval_ms: u64,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        ReconnectConfig {
+            grace_period_secs: default_grace_period_secs(),
+            retry_interval_ms: default_retry_interval_ms(),
+        }
+    }
+}
+
+fn default_grace_period_secs() -> u64 {
+    60
+}
+
+fn default_retry_interval_ms() -> u64 {
+    2000
+}
+
+impl ReconnectConfig {
+    pub fn grace_period(&self) -> Duration {
+        Duration::from_secs(self.grace_period_secs)
+    }
+
+    pub fn retry_interval(&self) -> Duration {
+        Duration::from_millis(self.retry_interval_ms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn doubles_the_restart_delay_up_to_the_maximum() {
+        let policy = RestartPolicy {
+            max_restarts: 5,
+            initial_delay_ms: 1000,
+            max_delay_ms: 5000,
+        };
+        let delays: Vec<_> = (1..=5).map(|restart| policy.delay(restart)).collect();
+        assert_eq!(
+            delays,
+            [1000, 2000, 4000, 5000, 5000].map(|ms| Some(Duration::from_millis(ms)))
+        );
+        assert_eq!(policy.delay(6), None);
+        assert_eq!(policy.delay(0), None);
+    }
+
+    #[test]
+    fn never_restarts_without_restarts_allowed() {
+        let policy = RestartPolicy {
+            max_restarts: 0,
+            ..Default::default()
+        };
+        assert_eq!(policy.delay(1), None);
+    }
+}
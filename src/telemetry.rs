@@ -0,0 +1,77 @@
+use opentelemetry::trace::{Status, TracerProvider as _};
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::runtime::TokioCurrentThread;
+use opentelemetry_sdk::trace::{Sampler, TracerProvider};
+use opentelemetry_sdk::Resource;
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+use crate::config::{TracingConfig, TracingExporter};
+use crate::error_messages::TextEgressError;
+
+/// Installs the global tracing subscriber exporting spans over OTLP.
+///
+/// Returns `None` when tracing is disabled, spans are still created but never exported.
+#[allow(clippy::result_large_err)]
+pub fn init_tracing(config: &TracingConfig) -> Result<Option<TracerProvider>, TextEgressError> {
+    let exporter = match config.exporter {
+        TracingExporter::None => return Ok(None),
+        TracingExporter::OtlpGrpc => {
+            let builder = opentelemetry_otlp::SpanExporter::builder().with_tonic();
+            match &config.endpoint {
+                Some(endpoint) => builder.with_endpoint(endpoint).build()?,
+                None => builder.build()?,
+            }
+        }
+        TracingExporter::OtlpHttp => {
+            let builder = opentelemetry_otlp::SpanExporter::builder().with_http();
+            match &config.endpoint {
+                Some(endpoint) => builder.with_endpoint(endpoint).build()?,
+                None => builder.build()?,
+            }
+        }
+    };
+
+    // actix runs on a current thread runtime, so the batch processor gets its own thread
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, TokioCurrentThread)
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+            config.sampling_ratio,
+        ))))
+        .with_resource(Resource::new(vec![KeyValue::new(
+            "service.name",
+            config.service_name.clone(),
+        )]))
+        .build();
+
+    let tracer = provider.tracer("syncflow-text-egress-actor");
+    tracing_subscriber::registry()
+        .with(tracing_opentelemetry::layer().with_tracer(tracer))
+        .try_init()
+        .map_err(|e| TextEgressError::TracingInitError(e.to_string()))?;
+
+    log::info!(
+        "Exporting traces with {:?} exporter as {}",
+        config.exporter,
+        config.service_name
+    );
+
+    Ok(Some(provider))
+}
+
+/// Flushes pending spans before the process exits.
+pub fn shutdown_tracing(provider: Option<TracerProvider>) {
+    if let Some(provider) = provider {
+        if let Err(e) = provider.shutdown() {
+            log::error!("Failed to shutdown tracer provider: {:?}", e);
+        }
+    }
+}
+
+/// Marks the span as failed so that the error shows up in the trace.
+pub(crate) fn record_error(span: &Span, error: &TextEgressError) {
+    span.set_status(Status::error(error.to_string()));
+}
@@ -0,0 +1,147 @@
+use chrono::DateTime;
+use livekit_api::services::room::RoomClient;
+
+use crate::config::TimestampsConfig;
+use crate::error_messages::TextEgressError;
+
+/// Columns of every line written to a participant file.
+pub const RECORD_FORMAT: &str = "received_at_iso|received_at_ns|offset_ns|sender_timestamp_ns|text";
+
+/// Looks up the room's creation time (ns) with the LiveKit room service.
+/// Returns `None` when no LiveKit API credentials are configured.
+pub async fn room_created_at(
+    config: &TimestampsConfig,
+    server_url: &str,
+    room_name: &str,
+) -> Result<Option<i64>, TextEgressError> {
+    let (Some(api_key), Some(api_secret)) = (&config.livekit_api_key, &config.livekit_api_secret)
+    else {
+        return Ok(None);
+    };
+
+    let host = server_url
+        .replacen("wss://", "https://", 1)
+        .replacen("ws://", "http://", 1);
+    let rooms = RoomClient::with_api_key(&host, api_key, api_secret.expose())
+        .list_rooms(vec![room_name.to_string()])
+        .await?;
+
+    Ok(rooms
+        .into_iter()
+        .find(|room| room.name == room_name)
+        .map(|room| {
+            if room.creation_time_ms > 0 {
+                room.creation_time_ms * 1_000_000
+            } else {
+                room.creation_time * 1_000_000_000
+            }
+        }))
+}
+
+/// Extracts a sender provided timestamp (ns) from a JSON payload.
+/// Numbers are interpreted as seconds, milliseconds, microseconds or nanoseconds by magnitude,
+/// strings as RFC 3339 dates or numbers.
+pub fn sender_timestamp_ns(payload: &str, key: &str) -> Option<i64> {
+    let value = match serde_json::from_str::<serde_json::Value>(payload).ok()? {
+        serde_json::Value::Object(mut object) => object.remove(key)?,
+        _ => return None,
+    };
+
+    match value {
+        serde_json::Value::Number(number) => match number.as_i64() {
+            Some(number) => Some(epoch_to_ns(number)),
+            None => number.as_f64().map(fractional_epoch_to_ns),
+        },
+        serde_json::Value::String(value) => {
+            if let Ok(number) = value.parse::<i64>() {
+                Some(epoch_to_ns(number))
+            } else if let Ok(number) = value.parse::<f64>() {
+                Some(fractional_epoch_to_ns(number))
+            } else {
+                DateTime::parse_from_rfc3339(&value)
+                    .ok()
+                    .and_then(|date| date.timestamp_nanos_opt())
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Nanoseconds per unit for an epoch of the given magnitude.
+fn epoch_scale(magnitude: f64) -> i64 {
+    if magnitude < 1e11 {
+        1_000_000_000
+    } else if magnitude < 1e14 {
+        1_000_000
+    } else if magnitude < 1e17 {
+        1_000
+    } else {
+        1
+    }
+}
+
+fn epoch_to_ns(value: i64) -> i64 {
+    value.saturating_mul(epoch_scale(value.unsigned_abs() as f64))
+}
+
+fn fractional_epoch_to_ns(value: f64) -> i64 {
+    (value * epoch_scale(value.abs()) as f64) as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECONDS: i64 = 1_700_000_000;
+    const NANOS: i64 = SECONDS * 1_000_000_000;
+
+    fn parse(payload: &str) -> Option<i64> {
+        sender_timestamp_ns(payload, "ts")
+    }
+
+    #[test]
+    fn scales_epochs_by_magnitude() {
+        assert_eq!(parse(&format!(r#"{{"ts": {}}}"#, SECONDS)), Some(NANOS));
+        assert_eq!(
+            parse(&format!(r#"{{"ts": {}}}"#, SECONDS * 1_000)),
+            Some(NANOS)
+        );
+        assert_eq!(
+            parse(&format!(r#"{{"ts": {}}}"#, SECONDS * 1_000_000)),
+            Some(NANOS)
+        );
+        assert_eq!(parse(&format!(r#"{{"ts": {}}}"#, NANOS)), Some(NANOS));
+    }
+
+    #[test]
+    fn parses_fractional_epochs() {
+        assert_eq!(parse(r#"{"ts": 1700000000.5}"#), Some(NANOS + 500_000_000));
+        assert_eq!(
+            parse(r#"{"ts": "1700000000.5"}"#),
+            Some(NANOS + 500_000_000)
+        );
+    }
+
+    #[test]
+    fn parses_strings() {
+        assert_eq!(
+            parse(&format!(r#"{{"ts": "{}"}}"#, SECONDS * 1_000)),
+            Some(NANOS)
+        );
+        assert_eq!(parse(r#"{"ts": "2023-11-14T22:13:20Z"}"#), Some(NANOS));
+        assert_eq!(parse(r#"{"ts": "2023-11-14T23:13:20+01:00"}"#), Some(NANOS));
+        assert_eq!(parse(r#"{"ts": "yesterday"}"#), None);
+    }
+
+    #[test]
+    fn ignores_payloads_without_a_timestamp() {
+        assert_eq!(parse("plain text"), None);
+        assert_eq!(parse(r#"{"other": 1700000000}"#), None);
+        assert_eq!(parse(r#"[1700000000]"#), None);
+        assert_eq!(parse(r#"{"ts": null}"#), None);
+        assert_eq!(
+            sender_timestamp_ns(r#"{"sent_at": 1700000000}"#, "sent_at"),
+            Some(NANOS)
+        );
+    }
+}